@@ -10,7 +10,7 @@ struct Args {}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    Args::parse();
     FmtSubscriber::builder()
         .with_env_filter("basic_axum=debug,florescence=debug,treeclocks=trace")
         .with_line_number(true)
@@ -18,20 +18,28 @@ async fn main() -> Result<()> {
         .init();
 
     let mut seed_list = vec![];
+    let mut flowers = vec![];
     for port in 8000..8003 {
         let socket_addr = format!("0.0.0.0:{port}");
         let socket_addr = socket_addr.parse()?;
         let url = format!("http://0.0.0.0:{port}");
         let url: Url = url.parse()?;
 
+        // The first flower has no seeds and bootstraps the cluster; the
+        // others are seeded with every flower before them and join it.
         let flower = Flower::builder()
             .engine(AxumEngine::new(socket_addr))
             .own_addr(url.clone())
             .seed_list(seed_list.clone())
-            .start();
+            .start()?;
 
         info!("Flower started at {url}");
         seed_list.push(url);
+        flowers.push(flower);
+    }
+
+    for flower in flowers {
+        flower.runtime().await?;
     }
 
     Ok(())
@@ -0,0 +1,16 @@
+use tokio::time::Instant;
+
+/// Where a `Flower` gets the time from, so simulations can drive it.
+pub trait Clock: Send + 'static {
+    fn now(&self) -> Instant;
+}
+
+/// The runtime's clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -5,14 +5,17 @@ use tokio::sync::mpsc::{Sender, error::SendError};
 use tracing::debug;
 use treeclocks::{EventTree, IdTree};
 
+/// Outbound side of a peer connection that skips redundant heartbeats.
 #[derive(Debug)]
-pub struct Connection {
+#[allow(dead_code)]
+pub(crate) struct Connection {
     pub(crate) peer_id: Option<IdTree>,
     pub(crate) peer_ts: Option<EventTree>,
     pub(crate) prev_msg: Option<(PollinationMessage, Instant)>,
     tx: Sender<PollinationMessage>,
 }
 
+#[allow(dead_code)]
 impl Connection {
     pub fn new(tx: Sender<PollinationMessage>) -> Self {
         Self {
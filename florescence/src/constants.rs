@@ -1,8 +1,14 @@
 use std::time::Duration;
 
-#[allow(unused)]
 pub(crate) const MPSC_CHANNEL_SIZE: usize = 1;
 pub(crate) const HEARTBEAT_TICK_TIME: Duration = Duration::from_secs(1);
 pub(crate) const RECLAIM_IDS_TICK_TIME: Duration = Duration::from_secs(1);
+pub(crate) const DEFRAG_TICK_TIME: Duration = Duration::from_secs(5);
+pub(crate) const ANTI_ENTROPY_TICK_TIME: Duration = Duration::from_secs(30);
+pub(crate) const COMPACTION_TICK_TIME: Duration = Duration::from_secs(10);
 pub(crate) const PROPAGATION_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const SNAPSHOT_TICK_TIME: Duration = Duration::from_secs(30);
+pub(crate) const JOIN_BACKOFF_MIN: Duration = Duration::from_millis(500);
+pub(crate) const JOIN_BACKOFF_MAX: Duration = Duration::from_secs(30);
 pub(crate) const DEBOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+pub(crate) const RESPONSE_CHANNEL_SIZE: usize = 32;
@@ -1,22 +1,49 @@
 use crate::message::PollinationMessage;
 use serde::{Deserialize, Serialize};
-use std::{fmt, future::Future, hash::Hash};
-use tokio::sync::mpsc::{Receiver, Sender};
+use std::{error::Error, fmt, future::Future, hash::Hash, sync::Arc};
+use tokio::sync::{
+    broadcast,
+    mpsc::{Receiver, Sender},
+    oneshot,
+};
 
 const DEFAULT_CHANNEL_SIZE: usize = 10;
+const STATUS_CHANNEL_SIZE: usize = 32;
 
 #[cfg(feature = "axum")]
 pub mod axum;
+pub mod mem;
+pub mod multiplex;
+pub mod queue;
+pub mod stream;
+pub mod tcp;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+pub mod wire;
 
 pub trait Engine: 'static {
-    type Addr: Clone + Serialize + for<'de> Deserialize<'de> + Hash + fmt::Display + Send;
-    type Error: std::error::Error + 'static;
+    type Addr: Clone + Serialize + for<'de> Deserialize<'de> + Hash + fmt::Display + Send + 'static;
+    type Error: Error + Send + Sync + 'static;
+
+    /// The address peers should use to reach this engine, if it was
+    /// configured explicitly. Used as the `Flower`'s own address when none is
+    /// given.
+    fn advertise_addr(&self) -> Option<Self::Addr> {
+        None
+    }
 
     fn run_background(
         self,
-    ) -> impl Future<
-        Output = Result<(Sender<EngineRequest<Self::Addr>>, Receiver<EngineEvent>), Self::Error>,
-    > + Send;
+    ) -> impl Future<Output = Result<EngineChannels<Self::Addr>, Self::Error>> + Send;
+}
+
+/// Everything the `Flower` needs to drive a running engine.
+pub struct EngineChannels<A> {
+    pub requests: Sender<EngineRequest<A>>,
+    pub events: Receiver<EngineEvent>,
+    pub status: broadcast::Receiver<EngineStatus<A>>,
+    pub shutdown: EngineShutdown,
 }
 
 pub struct EngineRequest<A> {
@@ -29,3 +56,100 @@ pub struct EngineEvent {
     pub pollination_msg: PollinationMessage,
     pub tx: Sender<PollinationMessage>,
 }
+
+/// Health of a running engine, as reported on its status stream.
+#[derive(Clone, Debug)]
+pub enum EngineStatus<A> {
+    /// Accepting messages on the given local address.
+    Listening(String),
+    /// Still running, but not everything is working as expected.
+    Degraded(String),
+    /// A request to a peer failed.
+    PeerUnreachable { addr: A, error: String },
+    /// Peers reach us at an address other than the one we advertise.
+    ObservedAddr(A),
+    /// A peer could not be reached at the address it advertises, but could
+    /// at the address its own requests came from. Requests to it are now
+    /// sent to `observed`.
+    AdvertisedUnreachable { advertised: A, observed: A },
+    /// The engine hit an unrecoverable error and will stop.
+    Failed(Arc<dyn Error + Send + Sync>),
+    /// The engine finished a graceful shutdown.
+    Stopped,
+}
+
+impl<A: fmt::Display> fmt::Display for EngineStatus<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        use EngineStatus::*;
+        match self {
+            Listening(addr) => write!(f, "LISTENING {addr}"),
+            Degraded(reason) => write!(f, "DEGRADED {reason}"),
+            PeerUnreachable { addr, error } => write!(f, "PEER_UNREACHABLE {addr}: {error}"),
+            ObservedAddr(addr) => write!(f, "OBSERVED_ADDR {addr}"),
+            AdvertisedUnreachable {
+                advertised,
+                observed,
+            } => write!(f, "ADVERTISED_UNREACHABLE {advertised} -> {observed}"),
+            Failed(err) => write!(f, "FAILED {err}"),
+            Stopped => write!(f, "STOPPED"),
+        }
+    }
+}
+
+/// Sending side of an engine status stream. Reporting never blocks; if no one
+/// is listening the status is simply dropped.
+#[derive(Debug)]
+pub struct StatusReporter<A> {
+    tx: broadcast::Sender<EngineStatus<A>>,
+}
+
+impl<A> Clone for StatusReporter<A> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<A: Clone> StatusReporter<A> {
+    pub fn channel() -> (Self, broadcast::Receiver<EngineStatus<A>>) {
+        let (tx, rx) = broadcast::channel(STATUS_CHANNEL_SIZE);
+        (Self { tx }, rx)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EngineStatus<A>> {
+        self.tx.subscribe()
+    }
+
+    pub fn report(&self, status: EngineStatus<A>) {
+        let _ = self.tx.send(status);
+    }
+
+    pub fn failed<E: Error + Send + Sync + 'static>(&self, err: E) {
+        self.report(EngineStatus::Failed(Arc::new(err)));
+    }
+}
+
+/// Requests a graceful shutdown of a running engine.
+#[derive(Debug)]
+pub struct EngineShutdown {
+    signal: oneshot::Sender<()>,
+    done: oneshot::Receiver<()>,
+}
+
+impl EngineShutdown {
+    /// Returns the shutdown handle along with the engine-side halves: a signal
+    /// to watch for and a sender to fire once in-flight work is drained.
+    pub fn new() -> (Self, oneshot::Receiver<()>, oneshot::Sender<()>) {
+        let (signal, signal_rx) = oneshot::channel();
+        let (done_tx, done) = oneshot::channel();
+        (Self { signal, done }, signal_rx, done_tx)
+    }
+
+    /// Stop accepting new messages and wait for in-flight requests to drain.
+    pub async fn shutdown(self) {
+        if self.signal.send(()).is_ok() {
+            let _ = self.done.await;
+        }
+    }
+}
@@ -1,25 +1,123 @@
-use crate::{
-    ds::WalkieTalkie,
-    message::PollinationMessage,
-    serialization::{deserialize, serialize},
-};
+use crate::message::PollinationMessage;
 use axum::{
-    Router, body::Bytes, extract::State, http::StatusCode, response::IntoResponse, routing::post,
+    Extension, Router,
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use pollination::{ClusterCipher, EnvelopeError, Keyring, Limits, Protocol};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
 };
-use std::{net::SocketAddr, sync::Arc};
 use thiserror::Error;
-use tokio::sync::mpsc::{Receiver, Sender, channel};
-use url::Url;
+use tokio::{
+    net::TcpListener,
+    sync::{
+        mpsc::{Sender, channel},
+        watch,
+    },
+};
+use tokio_rustls::TlsAcceptor;
+use url::{Host, Url};
+use uuid::Uuid;
 
-use super::{DEFAULT_CHANNEL_SIZE, Engine, EngineEvent, EngineRequest};
+use super::{
+    DEFAULT_CHANNEL_SIZE, Engine, EngineChannels, EngineEvent, EngineShutdown, EngineStatus,
+    StatusReporter,
+    queue::{self, QueueConfig, QueueStats},
+    tls::{PeerIdentity, TlsConfig, TlsError},
+    wire::{KeyringHandle, Wire, WireError},
+};
+
+/// Sent with every request: the address the sender advertises itself at.
+const ADVERTISED_ADDR_HEADER: &str = "x-florescence-advertised-addr";
+/// Sent with every response: the IP the request was received from.
+const OBSERVED_ADDR_HEADER: &str = "x-florescence-observed-addr";
 
 pub struct AxumEngine {
     socket_addr: SocketAddr,
+    advertise_addr: Option<Url>,
+    tls: Option<TlsConfig>,
+    wire: Wire,
+    queue_config: QueueConfig,
+    queue_stats: QueueStats<Url>,
 }
 
 impl AxumEngine {
     pub fn new(socket_addr: SocketAddr) -> Self {
-        Self { socket_addr }
+        Self {
+            socket_addr,
+            advertise_addr: None,
+            tls: None,
+            wire: Wire::default(),
+            queue_config: QueueConfig::default(),
+            queue_stats: QueueStats::default(),
+        }
+    }
+
+    /// The address peers should use to reach us, when it differs from the
+    /// bound `socket_addr` (e.g. binding `0.0.0.0` behind NAT or in a
+    /// container).
+    pub fn with_advertise_addr(mut self, addr: Url) -> Self {
+        self.advertise_addr = Some(addr);
+        self
+    }
+
+    /// Serve and send over mutually authenticated TLS. Peers must then be
+    /// addressed with `https://` URLs.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sign all traffic with a shared cluster secret; unsigned or forged
+    /// messages are dropped before they reach the `Flower`. Keys are rotated
+    /// through [`AxumEngine::keyring`].
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.wire = self.wire.with_keyring(keyring);
+        self
+    }
+
+    /// Handle to roll keys over once the engine is running.
+    pub fn keyring(&self) -> Option<KeyringHandle> {
+        self.wire.keyring()
+    }
+
+    /// Advertise a different protocol range than this build's default.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.wire = self.wire.with_protocol(protocol);
+        self
+    }
+
+    /// Encrypt all traffic with a shared cluster key.
+    pub fn with_cipher(mut self, cipher: ClusterCipher) -> Self {
+        self.wire = self.wire.with_cipher(cipher);
+        self
+    }
+
+    /// Bound the size of incoming messages.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.wire = self.wire.with_limits(limits);
+        self
+    }
+
+    pub fn with_queue_config(mut self, queue_config: QueueConfig) -> Self {
+        self.queue_config = queue_config;
+        self
+    }
+
+    /// Handle for observing outbound queue depths once the engine is running.
+    pub fn queue_stats(&self) -> QueueStats<Url> {
+        self.queue_stats.clone()
     }
 }
 
@@ -27,106 +125,468 @@ impl Engine for AxumEngine {
     type Addr = Url;
     type Error = AxumEngineError;
 
-    async fn run_background(
-        self,
-    ) -> Result<(Sender<EngineRequest<Self::Addr>>, Receiver<EngineEvent>), Self::Error> {
-        let (wt0, wt1) = WalkieTalkie::pair();
+    fn advertise_addr(&self) -> Option<Url> {
+        self.advertise_addr.clone()
+    }
+
+    async fn run_background(self) -> Result<EngineChannels<Self::Addr>, Self::Error> {
+        let own_addr = match self.advertise_addr.clone() {
+            Some(addr) if is_unspecified(&addr) => {
+                return Err(AxumEngineError::UnroutableAddr(addr));
+            }
+            Some(addr) => addr,
+            None => {
+                let scheme = if self.tls.is_some() { "https" } else { "http" };
+                Url::parse(&format!("{scheme}://{}/", self.socket_addr))?
+            }
+        };
 
-        let (tx, rx) = wt1.split();
+        let (requests, rx) = channel(DEFAULT_CHANNEL_SIZE);
+        let (tx, events) = channel(DEFAULT_CHANNEL_SIZE);
+        let (status, status_rx) = StatusReporter::channel();
+        let (shutdown, signal, done) = EngineShutdown::new();
+        let (stop_tx, stop_rx) = watch::channel(false);
 
-        tokio::spawn(sender_task(rx));
+        let http = match &self.tls {
+            Some(tls) => reqwest::Client::builder()
+                .use_preconfigured_tls(tls.client_config()?)
+                .tls_info(true)
+                .build()?,
+            None => reqwest::Client::new(),
+        };
+        let book = Arc::new(AddrBook::default());
+        let client = Arc::new(Client {
+            http,
+            wire: self.wire.clone(),
+            own_addr,
+            book: book.clone(),
+            status: status.clone(),
+            own_observed: Mutex::new(None),
+        });
+        let send = move |addr, msg| {
+            let client = client.clone();
+            async move { client.send(addr, msg).await }
+        };
+        let dispatcher = tokio::spawn(queue::dispatcher(
+            rx,
+            stop_rx.clone(),
+            self.queue_config,
+            self.queue_stats,
+            status.clone(),
+            send,
+        ));
 
-        let state = Arc::new(AppState { tx });
+        let body_limit = self.wire.limits().max_message_size;
+        let state = Arc::new(AppState {
+            tx,
+            wire: self.wire,
+            book,
+        });
 
         let app = Router::new()
             .route("/", post(handle_message))
+            .layer(DefaultBodyLimit::max(body_limit))
             .with_state(state);
 
-        let listener = tokio::net::TcpListener::bind(self.socket_addr).await?;
+        let listener = TcpListener::bind(self.socket_addr).await?;
+        let mut server = match self.tls {
+            Some(tls) => {
+                let acceptor = TlsAcceptor::from(tls.server_config()?);
+                tokio::spawn(serve_tls(listener, acceptor, app, stop_rx))
+            }
+            None => {
+                let mut stop = stop_rx;
+                tokio::spawn(async move {
+                    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(async move {
+                            let _ = stop.wait_for(|stop| *stop).await;
+                        })
+                        .await
+                })
+            }
+        };
+        status.report(EngineStatus::Listening(self.socket_addr.to_string()));
+
         tokio::spawn(async move {
-            let res = axum::serve(listener, app).await;
-            if let Err(err) = res {
-                error!("Error running Axum: {err:?}");
+            let res = tokio::select! {
+                _ = signal => {
+                    info!("Shutting down; draining in-flight requests");
+                    let _ = stop_tx.send(true);
+                    (&mut server).await
+                }
+                res = &mut server => {
+                    let _ = stop_tx.send(true);
+                    res
+                }
+            };
+
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    error!("Error running Axum: {err:?}");
+                    status.failed(AxumEngineError::StdIo(err));
+                }
+                Err(err) => {
+                    error!("Axum task failed: {err}");
+                    status.failed(err);
+                }
             }
+
+            if let Err(err) = dispatcher.await {
+                error!("Outbound dispatcher failed: {err}");
+            }
+            status.report(EngineStatus::Stopped);
+            let _ = done.send(());
         });
 
-        Ok(wt0.split())
+        Ok(EngineChannels {
+            requests,
+            events,
+            status: status_rx,
+            shutdown,
+        })
+    }
+}
+
+async fn serve_tls(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    app: Router,
+    mut stop: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let graceful = GracefulShutdown::new();
+    loop {
+        let conn = tokio::select! {
+            conn = listener.accept() => conn,
+            _ = stop.wait_for(|stop| *stop) => break,
+        };
+        let (stream, remote_addr) = match conn {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("Error accepting connection: {err}");
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("TLS handshake with {remote_addr} failed: {err}");
+                    return;
+                }
+            };
+
+            let identity =
+                PeerIdentity::from_peer_certificates(stream.get_ref().1.peer_certificates());
+            let identity = match identity {
+                Ok(identity) => identity,
+                Err(err) => {
+                    warn!("Rejecting connection from {remote_addr}: {err}");
+                    return;
+                }
+            };
+
+            let app = app
+                .layer(Extension(identity))
+                .layer(Extension(ConnectInfo(remote_addr)));
+            let service = TowerToHyperService::new(app);
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            if let Err(err) = watcher.watch(conn).await {
+                error!("Error serving connection from {remote_addr}: {err}");
+            }
+        });
     }
+
+    graceful.shutdown().await;
+    Ok(())
 }
 
-async fn handle_message(State(state): State<Arc<AppState>>, bytes: Bytes) -> impl IntoResponse {
-    match handle_message_inner(&state.tx, bytes).await {
-        Ok(msg) => (StatusCode::OK, msg),
+async fn handle_message(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<PeerIdentity>>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Response {
+    let advertised = headers
+        .get(ADVERTISED_ADDR_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Url::parse(value).ok());
+
+    let identity = identity.map(|Extension(identity)| identity);
+    let observed = [(OBSERVED_ADDR_HEADER, remote_addr.ip().to_string())];
+    match handle_message_inner(&state, identity, bytes).await {
+        Ok((peer, msg)) => {
+            // Only a message that checked out may tell us where its sender is
+            if let Some(advertised) = advertised {
+                state.book.observe(peer, advertised, remote_addr.ip());
+            }
+            (StatusCode::OK, observed, msg).into_response()
+        }
+        Err(AxumEngineError::Tls(err)) => {
+            warn!("Rejecting message: {err}");
+            StatusCode::FORBIDDEN.into_response()
+        }
+        Err(AxumEngineError::Wire(
+            err @ (WireError::Authentication(_) | WireError::Encryption(_)),
+        )) => {
+            warn!("Dropping unauthenticated message: {err}");
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+        Err(AxumEngineError::Wire(WireError::Envelope(
+            err @ EnvelopeError::Incompatible { .. },
+        ))) => {
+            warn!("Ignoring message from {remote_addr}: {err}");
+            StatusCode::BAD_REQUEST.into_response()
+        }
+        Err(AxumEngineError::Wire(err @ WireError::Invalid(_))) => {
+            warn!("Rejecting message from {remote_addr}: {err}");
+            StatusCode::PAYLOAD_TOO_LARGE.into_response()
+        }
         Err(err) => {
             error!("Error handling message inner: {err}");
-            (StatusCode::INTERNAL_SERVER_ERROR, Bytes::new())
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
+/// Returns who sent the message along with our reply.
 async fn handle_message_inner(
-    tx: &Sender<EngineEvent>,
+    state: &AppState,
+    identity: Option<PeerIdentity>,
     bytes: Bytes,
-) -> Result<Bytes, AxumEngineError> {
-    let pollination_msg: PollinationMessage = deserialize(bytes.to_vec())?;
+) -> Result<(Uuid, Bytes), AxumEngineError> {
+    let decoded = state.wire.decode(bytes)?;
+    let peer = decoded.msg.uuid();
+    if let Some(identity) = identity {
+        identity.verify(peer)?;
+    }
+    let pollination_msg = state.wire.accept(decoded);
+
     let (res_tx, mut rx) = channel(DEFAULT_CHANNEL_SIZE);
-    tx.send(EngineEvent {
-        pollination_msg,
-        tx: res_tx,
-    })
-    .await?;
+    state
+        .tx
+        .send(EngineEvent {
+            pollination_msg,
+            tx: res_tx,
+        })
+        .await?;
 
     if let Some(res) = rx.recv().await {
-        Ok(serialize(res)?.into())
+        Ok((peer, state.wire.encode(&res, Some(peer))?.into()))
     } else {
-        Ok(Bytes::new())
+        Ok((peer, Bytes::new()))
     }
 }
 
-async fn sender_task(mut rx: Receiver<EngineRequest<Url>>) {
-    loop {
-        match rx.recv().await {
-            Some(req) => {
-                let EngineRequest {
-                    pollination_msg,
-                    addr,
-                    tx,
-                } = req;
-
-                match send_and_recv(addr, pollination_msg).await {
-                    Ok(res) => {
-                        let res = tx.send(res).await;
-                        if let Err(err) = res {
-                            error!("Error sending request: {err}");
-                        }
-                    }
-                    Err(err) => {
-                        error!("Error sending request: {err}");
-                    }
-                }
-            }
-            None => {
-                info!("Channel closed")
+/// Outbound side of the engine.
+struct Client {
+    http: reqwest::Client,
+    wire: Wire,
+    /// What we tell peers our address is.
+    own_addr: Url,
+    book: Arc<AddrBook>,
+    status: StatusReporter<Url>,
+    /// The last address peers reported seeing us at, if it differs from
+    /// `own_addr`.
+    own_observed: Mutex<Option<Url>>,
+}
+
+impl Client {
+    /// Send to a peer at its advertised address, falling back to the address
+    /// its own requests came from if the advertised one cannot be reached.
+    async fn send(
+        &self,
+        addr: Url,
+        pollination_msg: PollinationMessage,
+    ) -> Result<Option<PollinationMessage>, AxumEngineError> {
+        if let Some(observed) = self.book.rerouted(&addr) {
+            let res = self.send_and_recv(observed, &pollination_msg).await;
+            if res.is_err() {
+                // The observed address stopped working too; start over
+                self.book.forget(&addr);
             }
+            return res;
+        }
+
+        let err = match self.send_and_recv(addr.clone(), &pollination_msg).await {
+            Err(AxumEngineError::Reqwest(err)) if err.is_connect() || err.is_timeout() => err,
+            res => return res,
+        };
+        let Some(observed) = self.book.observed(&addr) else {
+            return Err(err.into());
+        };
+        let Ok(res) = self.send_and_recv(observed.clone(), &pollination_msg).await else {
+            return Err(err.into());
+        };
+
+        warn!("{addr} is unreachable but {observed} works; rerouting");
+        self.book.reroute(addr.clone(), observed.clone());
+        self.status.report(EngineStatus::AdvertisedUnreachable {
+            advertised: addr,
+            observed,
+        });
+        Ok(res)
+    }
+
+    async fn send_and_recv(
+        &self,
+        addr: Url,
+        pollination_msg: &PollinationMessage,
+    ) -> Result<Option<PollinationMessage>, AxumEngineError> {
+        let body = self.wire.encode(pollination_msg, self.book.uuid(&addr))?;
+        let res = self
+            .http
+            .post(addr.clone())
+            .header(ADVERTISED_ADDR_HEADER, self.own_addr.as_str())
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let observed = res
+            .headers()
+            .get(OBSERVED_ADDR_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+
+        // Only present when the client was built with TLS; the server certificate
+        // must then belong to whoever answered.
+        let identity = res
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(|der| PeerIdentity::from_certificate(&der.into()))
+            .transpose()?;
+
+        if let Some(observed) = observed {
+            self.note_observed(observed);
+        }
+
+        // An empty body means the peer had nothing to say back
+        let bytes = res.bytes().await?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        let decoded = self.wire.decode(bytes)?;
+        if let Some(identity) = identity {
+            identity.verify(decoded.msg.uuid())?;
+        }
+        let pollination_msg = self.wire.accept(decoded);
+        self.book.set_uuid(addr, pollination_msg.uuid());
+
+        Ok(Some(pollination_msg))
+    }
+
+    /// Report when a peer sees us at an IP other than the one we advertise.
+    /// Advertised hostnames are trusted as-is.
+    fn note_observed(&self, ip: IpAddr) {
+        let advertised_ip = match self.own_addr.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            _ => return,
+        };
+        if advertised_ip == ip {
+            return;
+        }
+
+        let mut observed = self.own_addr.clone();
+        if observed.set_ip_host(ip).is_err() {
+            return;
+        }
+        let mut own_observed = self.own_observed.lock().expect("poisoned lock");
+        if own_observed.as_ref() != Some(&observed) {
+            *own_observed = Some(observed.clone());
+            self.status.report(EngineStatus::ObservedAddr(observed));
         }
     }
 }
 
-async fn send_and_recv(
-    addr: Url,
-    pollination_msg: PollinationMessage,
-) -> Result<PollinationMessage, AxumEngineError> {
-    let client = reqwest::Client::new();
-    let res = client
-        .post(addr)
-        .body(serialize(pollination_msg)?)
-        .send()
-        .await?;
+/// What peers advertise themselves as versus where their requests actually
+/// come from.
+#[derive(Debug, Default)]
+struct AddrBook {
+    inner: Mutex<AddrBookInner>,
+}
+
+#[derive(Debug, Default)]
+struct AddrBookInner {
+    /// Advertised address to who used it and the same address at the IP we
+    /// saw them use.
+    observed: HashMap<Url, (Uuid, Url)>,
+    /// Advertised addresses we gave up on in favour of the observed one.
+    rerouted: HashMap<Url, Url>,
+    /// Who answered at each address, for picking the wire format version.
+    uuids: HashMap<Url, Uuid>,
+}
+
+impl AddrBook {
+    /// Note that `peer` advertising `advertised` reached us from `ip`. A
+    /// peer cannot claim an address someone else is known to answer at.
+    fn observe(&self, peer: Uuid, advertised: Url, ip: IpAddr) {
+        let mut observed = advertised.clone();
+        if observed.set_ip_host(ip).is_err() || observed == advertised {
+            return;
+        }
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if inner
+            .uuids
+            .get(&advertised)
+            .is_some_and(|uuid| *uuid != peer)
+        {
+            return;
+        }
+        inner.observed.insert(advertised, (peer, observed));
+    }
 
-    let bytes = res.bytes().await?.to_vec();
-    let pollination_msg = crate::serialization::deserialize(bytes)?;
+    fn observed(&self, advertised: &Url) -> Option<Url> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        let (peer, observed) = inner.observed.get(advertised)?;
+        match inner.uuids.get(advertised) {
+            Some(uuid) if uuid != peer => None,
+            _ => Some(observed.clone()),
+        }
+    }
 
-    Ok(pollination_msg)
+    fn reroute(&self, advertised: Url, observed: Url) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.rerouted.insert(advertised, observed);
+    }
+
+    fn rerouted(&self, advertised: &Url) -> Option<Url> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        inner.rerouted.get(advertised).cloned()
+    }
+
+    fn uuid(&self, addr: &Url) -> Option<Uuid> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        inner.uuids.get(addr).copied()
+    }
+
+    fn set_uuid(&self, addr: Url, uuid: Uuid) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.uuids.insert(addr, uuid);
+    }
+
+    fn forget(&self, advertised: &Url) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.rerouted.remove(advertised);
+    }
+}
+
+fn is_unspecified(addr: &Url) -> bool {
+    match addr.host() {
+        Some(Host::Ipv4(ip)) => ip.is_unspecified(),
+        Some(Host::Ipv6(ip)) => ip.is_unspecified(),
+        Some(Host::Domain(_)) => false,
+        None => true,
+    }
 }
 
 #[derive(Debug, Error)]
@@ -137,19 +597,165 @@ pub enum AxumEngineError {
     #[error("StdIO error: {0}")]
     StdIo(#[from] std::io::Error),
 
-    #[error("Deserialize error: {0}")]
-    Deserialize(#[from] crate::serialization::DeserializeError),
-
-    #[error("Serialize error: {0}")]
-    Serialize(#[from] crate::serialization::SerializeError),
+    #[error("Wire error: {0}")]
+    Wire(#[from] WireError),
 
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
 
+    #[error("TLS error: {0}")]
+    Tls(#[from] TlsError),
+
+    #[error("URL error: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("{0} is not routable; set an advertise address peers can reach")]
+    UnroutableAddr(Url),
+
     #[error("Error sending via mpsc: {0}")]
     SendError(#[from] tokio::sync::mpsc::error::SendError<EngineEvent>),
 }
 
 struct AppState {
     tx: Sender<EngineEvent>,
+    wire: Wire,
+    book: Arc<AddrBook>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_compressed_seed() {
+        use crate::engine::EngineRequest;
+        use pollination::{Compression, Features, PollinationNode, StartupMode};
+
+        // Ports nothing is listening on right now
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let addr = |port: u16| url(&format!("http://127.0.0.1:{port}/"));
+        let (a_port, b_port) = (free_port(), free_port());
+
+        // A seed whose map is large enough to be worth compressing
+        let mut seed = PollinationNode::new(Uuid::from_u128(0), addr(b_port));
+        seed.set_compression(Compression::Zstd);
+        for i in 1..=40 {
+            seed.set_propagating();
+            let mut member = PollinationNode::new_with_mode(
+                Uuid::from_u128(i),
+                url(&format!("http://10.0.0.{i}:8000/")),
+                StartupMode::Join,
+            );
+            let new_member = PollinationMessage::NewMember {
+                uuid: member.uuid(),
+            };
+            let seed_msg = seed.handle_message(new_member).unwrap().response.unwrap();
+            let update = member.handle_message(seed_msg).unwrap().response.unwrap();
+            seed.handle_message(update).unwrap();
+        }
+        seed.set_propagating();
+
+        let a = AxumEngine::new(([127, 0, 0, 1], a_port).into())
+            .run_background()
+            .await
+            .unwrap();
+        let mut b = AxumEngine::new(([127, 0, 0, 1], b_port).into())
+            .run_background()
+            .await
+            .unwrap();
+
+        let mut joiner =
+            PollinationNode::new_with_mode(Uuid::from_u128(100), addr(a_port), StartupMode::Join);
+        let (tx, mut rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: joiner.msg_new_member().unwrap(),
+            addr: addr(b_port),
+            tx,
+        };
+        a.requests.send(req).await.unwrap();
+
+        let event = b.events.recv().await.unwrap();
+        let res = seed.handle_message(event.pollination_msg).unwrap();
+        event.tx.send(res.response.unwrap()).await.unwrap();
+
+        let seed_msg = rx.recv().await.unwrap();
+        let PollinationMessage::Seed { patch, .. } = &seed_msg else {
+            panic!("expected a seed, got {seed_msg:?}");
+        };
+        assert_eq!(patch.required(), Features::ZSTD);
+        joiner.handle_message(seed_msg).unwrap();
+        assert!(joiner.id().is_some());
+        assert_eq!(joiner.peers_alive().count(), 41);
+
+        a.shutdown.shutdown().await;
+        b.shutdown.shutdown().await;
+    }
+
+    #[test]
+    fn test_addr_book_observe() {
+        let book = AddrBook::default();
+        let peer = Uuid::from_u128(1);
+        book.observe(
+            peer,
+            url("http://0.0.0.0:8000/"),
+            "10.0.0.7".parse().unwrap(),
+        );
+        book.observe(
+            peer,
+            url("http://10.0.0.8:8000/"),
+            "10.0.0.8".parse().unwrap(),
+        );
+
+        assert_eq!(
+            book.observed(&url("http://0.0.0.0:8000/")),
+            Some(url("http://10.0.0.7:8000/"))
+        );
+        // Advertised correctly, nothing to fall back to
+        assert_eq!(book.observed(&url("http://10.0.0.8:8000/")), None);
+    }
+
+    #[test]
+    fn test_addr_book_observe_other_peer() {
+        let book = AddrBook::default();
+        let addr = url("http://0.0.0.0:8000/");
+        book.set_uuid(addr.clone(), Uuid::from_u128(1));
+
+        // Someone else claiming the address is ignored
+        book.observe(
+            Uuid::from_u128(2),
+            addr.clone(),
+            "10.0.0.9".parse().unwrap(),
+        );
+        assert_eq!(book.observed(&addr), None);
+
+        book.observe(
+            Uuid::from_u128(1),
+            addr.clone(),
+            "10.0.0.7".parse().unwrap(),
+        );
+        assert_eq!(book.observed(&addr), Some(url("http://10.0.0.7:8000/")));
+
+        // Whoever answers there now no longer vouches for the observation
+        book.set_uuid(addr.clone(), Uuid::from_u128(3));
+        assert_eq!(book.observed(&addr), None);
+    }
+
+    #[test]
+    fn test_unspecified() {
+        assert!(is_unspecified(&url("http://0.0.0.0:8000/")));
+        assert!(is_unspecified(&url("http://[::]:8000/")));
+        assert!(!is_unspecified(&url("http://10.0.0.1:8000/")));
+        assert!(!is_unspecified(&url("http://node.example.com/")));
+    }
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use tokio::sync::mpsc::{Sender, channel};
+
+use super::{
+    DEFAULT_CHANNEL_SIZE, Engine, EngineChannels, EngineEvent, EngineRequest, EngineShutdown,
+    EngineStatus, StatusReporter,
+};
+
+/// Connects `MemEngine`s running in the same process, e.g. in tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemNetwork {
+    nodes: Arc<Mutex<HashMap<usize, Sender<EngineEvent>>>>,
+}
+
+impl MemNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, addr: usize) -> Option<Sender<EngineEvent>> {
+        let nodes = self.nodes.lock().expect("poisoned lock");
+        nodes.get(&addr).cloned()
+    }
+}
+
+/// Engine that hands messages straight to other engines on the same
+/// `MemNetwork`, addressed by index.
+#[derive(Debug)]
+pub struct MemEngine {
+    network: MemNetwork,
+    addr: usize,
+}
+
+impl MemEngine {
+    pub fn new(network: MemNetwork, addr: usize) -> Self {
+        Self { network, addr }
+    }
+}
+
+impl Engine for MemEngine {
+    type Addr = usize;
+    type Error = MemEngineError;
+
+    fn advertise_addr(&self) -> Option<usize> {
+        Some(self.addr)
+    }
+
+    async fn run_background(self) -> Result<EngineChannels<Self::Addr>, Self::Error> {
+        let (events_tx, events) = channel(DEFAULT_CHANNEL_SIZE);
+        {
+            let mut nodes = self.network.nodes.lock().expect("poisoned lock");
+            if nodes.contains_key(&self.addr) {
+                return Err(MemEngineError::AddrInUse(self.addr));
+            }
+            nodes.insert(self.addr, events_tx);
+        }
+
+        let (status, status_rx) = StatusReporter::channel();
+        let (requests, mut requests_rx) = channel(DEFAULT_CHANNEL_SIZE);
+        let network = self.network.clone();
+        let request_status = status.clone();
+        tokio::spawn(async move {
+            while let Some(req) = requests_rx.recv().await {
+                let EngineRequest {
+                    pollination_msg,
+                    addr,
+                    tx,
+                } = req;
+                let event = EngineEvent {
+                    pollination_msg,
+                    tx,
+                };
+                let delivered = match network.get(addr) {
+                    Some(peer) => peer.send(event).await.is_ok(),
+                    None => false,
+                };
+                if !delivered {
+                    request_status.report(EngineStatus::PeerUnreachable {
+                        addr,
+                        error: "No engine at this address".to_string(),
+                    });
+                }
+            }
+        });
+
+        let (shutdown, signal, done) = EngineShutdown::new();
+        let network = self.network;
+        let addr = self.addr;
+        status.report(EngineStatus::Listening(format!("mem://{addr}")));
+        tokio::spawn(async move {
+            let _ = signal.await;
+            network.nodes.lock().expect("poisoned lock").remove(&addr);
+            status.report(EngineStatus::Stopped);
+            let _ = done.send(());
+        });
+
+        Ok(EngineChannels {
+            requests,
+            events,
+            status: status_rx,
+            shutdown,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MemEngineError {
+    #[error("Address {0} is already taken")]
+    AddrInUse(usize),
+}
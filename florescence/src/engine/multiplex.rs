@@ -0,0 +1,545 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap, fmt, future::Future, net::SocketAddr, path::PathBuf, pin::Pin,
+    str::FromStr,
+};
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::error::RecvError,
+    mpsc::{Sender, channel},
+};
+use url::Url;
+
+use super::{
+    DEFAULT_CHANNEL_SIZE, Engine, EngineChannels, EngineEvent, EngineRequest, EngineShutdown,
+    EngineStatus, StatusReporter,
+};
+
+/// Address of a peer reachable over any of the transports a
+/// `MultiplexEngine` can speak: `Http` through the `AxumEngine`, `Tcp`
+/// through the `TcpEngine`, `Unix` through the `UnixEngine` and `Mem`
+/// through the `MemEngine`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MultiAddr {
+    Http(Url),
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    Mem(usize),
+}
+
+impl MultiAddr {
+    pub fn scheme(&self) -> Scheme {
+        match self {
+            MultiAddr::Http(_) => Scheme::Http,
+            MultiAddr::Tcp(_) => Scheme::Tcp,
+            MultiAddr::Unix(_) => Scheme::Unix,
+            MultiAddr::Mem(_) => Scheme::Mem,
+        }
+    }
+}
+
+impl fmt::Display for MultiAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            MultiAddr::Http(url) => write!(f, "{url}"),
+            MultiAddr::Tcp(addr) => write!(f, "tcp://{addr}"),
+            MultiAddr::Unix(path) => write!(f, "unix://{}", path.display()),
+            MultiAddr::Mem(idx) => write!(f, "mem://{idx}"),
+        }
+    }
+}
+
+impl FromStr for MultiAddr {
+    type Err = MultiAddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| MultiAddrParseError::MissingScheme(s.to_owned()))?;
+
+        match scheme {
+            "http" | "https" => Ok(MultiAddr::Http(s.parse()?)),
+            "tcp" => Ok(MultiAddr::Tcp(rest.parse()?)),
+            "unix" => Ok(MultiAddr::Unix(rest.into())),
+            "mem" => Ok(MultiAddr::Mem(rest.parse()?)),
+            _ => Err(MultiAddrParseError::UnknownScheme(scheme.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MultiAddrParseError {
+    #[error("Address has no scheme: {0}")]
+    MissingScheme(String),
+
+    #[error("Unknown scheme: {0}")]
+    UnknownScheme(String),
+
+    #[error("Invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("Invalid socket address: {0}")]
+    SocketAddr(#[from] std::net::AddrParseError),
+
+    #[error("Invalid memory address: {0}")]
+    Mem(#[from] std::num::ParseIntError),
+}
+
+/// Transport family an address belongs to; the `MultiplexEngine` runs at most
+/// one engine per scheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    Http,
+    Tcp,
+    Unix,
+    Mem,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        use Scheme::*;
+        write!(
+            f,
+            "{}",
+            match self {
+                Http => "http",
+                Tcp => "tcp",
+                Unix => "unix",
+                Mem => "mem",
+            }
+        )
+    }
+}
+
+/// Engine addresses that can be carried inside a `MultiAddr`.
+pub trait MultiplexAddr: Sized {
+    const SCHEME: Scheme;
+
+    fn into_multi(self) -> MultiAddr;
+
+    fn from_multi(addr: MultiAddr) -> Option<Self>;
+}
+
+impl MultiplexAddr for Url {
+    const SCHEME: Scheme = Scheme::Http;
+
+    fn into_multi(self) -> MultiAddr {
+        MultiAddr::Http(self)
+    }
+
+    fn from_multi(addr: MultiAddr) -> Option<Self> {
+        match addr {
+            MultiAddr::Http(url) => Some(url),
+            _ => None,
+        }
+    }
+}
+
+impl MultiplexAddr for SocketAddr {
+    const SCHEME: Scheme = Scheme::Tcp;
+
+    fn into_multi(self) -> MultiAddr {
+        MultiAddr::Tcp(self)
+    }
+
+    fn from_multi(addr: MultiAddr) -> Option<Self> {
+        match addr {
+            MultiAddr::Tcp(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl MultiplexAddr for super::unix::UnixAddr {
+    const SCHEME: Scheme = Scheme::Unix;
+
+    fn into_multi(self) -> MultiAddr {
+        MultiAddr::Unix(self.into())
+    }
+
+    fn from_multi(addr: MultiAddr) -> Option<Self> {
+        match addr {
+            MultiAddr::Unix(path) => Some(Self::new(path)),
+            _ => None,
+        }
+    }
+}
+
+impl MultiplexAddr for usize {
+    const SCHEME: Scheme = Scheme::Mem;
+
+    fn into_multi(self) -> MultiAddr {
+        MultiAddr::Mem(self)
+    }
+
+    fn from_multi(addr: MultiAddr) -> Option<Self> {
+        match addr {
+            MultiAddr::Mem(idx) => Some(idx),
+            _ => None,
+        }
+    }
+}
+
+/// Runs several engines side by side and routes each outgoing message to the
+/// engine matching the scheme of its address.
+///
+/// This lets a single membership span nodes that are only reachable over
+/// different transports, e.g. some behind an HTTP ingress and some in the
+/// same process.
+#[derive(Default)]
+pub struct MultiplexEngine {
+    engines: Vec<Box<dyn ErasedEngine>>,
+}
+
+impl MultiplexEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<E>(mut self, engine: E) -> Self
+    where
+        E: Engine + Send,
+        E::Addr: MultiplexAddr + Sync,
+    {
+        self.engines.push(Box::new(Adapter(engine)));
+        self
+    }
+}
+
+impl Engine for MultiplexEngine {
+    type Addr = MultiAddr;
+    type Error = MultiplexError;
+
+    async fn run_background(self) -> Result<EngineChannels<Self::Addr>, Self::Error> {
+        let (status, status_rx) = StatusReporter::channel();
+        let (events_tx, events) = channel(DEFAULT_CHANNEL_SIZE);
+
+        let mut routes = HashMap::new();
+        let mut shutdowns = vec![];
+        for engine in self.engines {
+            let scheme = engine.scheme();
+            if routes.contains_key(&scheme) {
+                return Err(MultiplexError::DuplicateScheme(scheme));
+            }
+
+            let (tx, shutdown) = engine.start(events_tx.clone(), status.clone()).await?;
+            routes.insert(scheme, tx);
+            shutdowns.push(shutdown);
+        }
+
+        let (requests, mut requests_rx) = channel(DEFAULT_CHANNEL_SIZE);
+        let router_status = status.clone();
+        tokio::spawn(async move {
+            while let Some(req) = requests_rx.recv().await {
+                let req: EngineRequest<MultiAddr> = req;
+                let scheme = req.addr.scheme();
+                let Some(tx) = routes.get(&scheme) else {
+                    router_status.report(EngineStatus::PeerUnreachable {
+                        addr: req.addr,
+                        error: format!("No engine for scheme {scheme}"),
+                    });
+                    continue;
+                };
+
+                if let Err(err) = tx.send(req).await {
+                    error!("{scheme} engine stopped accepting requests");
+                    router_status.report(EngineStatus::PeerUnreachable {
+                        addr: err.0.addr,
+                        error: format!("{scheme} engine is not running"),
+                    });
+                }
+            }
+        });
+
+        let (shutdown, signal, done) = EngineShutdown::new();
+        tokio::spawn(async move {
+            let _ = signal.await;
+            for shutdown in shutdowns {
+                shutdown.shutdown().await;
+            }
+            status.report(EngineStatus::Stopped);
+            let _ = done.send(());
+        });
+
+        Ok(EngineChannels {
+            requests,
+            events,
+            status: status_rx,
+            shutdown,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MultiplexError {
+    #[error("More than one engine registered for {0}")]
+    DuplicateScheme(Scheme),
+
+    #[error("{scheme} engine failed to start: {err}")]
+    Engine {
+        scheme: Scheme,
+        err: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Object-safe wrapper around `Engine` so engines with different address
+/// types can be stored together.
+trait ErasedEngine: Send {
+    fn scheme(&self) -> Scheme;
+
+    #[allow(clippy::type_complexity)]
+    fn start(
+        self: Box<Self>,
+        events: Sender<EngineEvent>,
+        status: StatusReporter<MultiAddr>,
+    ) -> BoxFuture<Result<(Sender<EngineRequest<MultiAddr>>, EngineShutdown), MultiplexError>>;
+}
+
+struct Adapter<E>(E);
+
+impl<E> ErasedEngine for Adapter<E>
+where
+    E: Engine + Send,
+    E::Addr: MultiplexAddr + Sync,
+{
+    fn scheme(&self) -> Scheme {
+        E::Addr::SCHEME
+    }
+
+    fn start(
+        self: Box<Self>,
+        events_tx: Sender<EngineEvent>,
+        status: StatusReporter<MultiAddr>,
+    ) -> BoxFuture<Result<(Sender<EngineRequest<MultiAddr>>, EngineShutdown), MultiplexError>> {
+        Box::pin(async move {
+            let scheme = E::Addr::SCHEME;
+            let EngineChannels {
+                requests,
+                mut events,
+                status: mut inner_status,
+                shutdown,
+            } = self
+                .0
+                .run_background()
+                .await
+                .map_err(|err| MultiplexError::Engine {
+                    scheme,
+                    err: Box::new(err),
+                })?;
+
+            tokio::spawn(async move {
+                while let Some(event) = events.recv().await {
+                    if events_tx.send(event).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            tokio::spawn(async move {
+                loop {
+                    let inner = match inner_status.recv().await {
+                        Ok(inner) => inner,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    };
+
+                    // A single transport going away only degrades the
+                    // multiplexer; the others keep the node in the cluster.
+                    let status_update = match inner {
+                        EngineStatus::Listening(addr) => {
+                            EngineStatus::Listening(format!("{scheme}:{addr}"))
+                        }
+                        EngineStatus::Degraded(reason) => {
+                            EngineStatus::Degraded(format!("{scheme}: {reason}"))
+                        }
+                        EngineStatus::PeerUnreachable { addr, error } => {
+                            EngineStatus::PeerUnreachable {
+                                addr: addr.into_multi(),
+                                error,
+                            }
+                        }
+                        EngineStatus::ObservedAddr(addr) => {
+                            EngineStatus::ObservedAddr(addr.into_multi())
+                        }
+                        EngineStatus::AdvertisedUnreachable {
+                            advertised,
+                            observed,
+                        } => EngineStatus::AdvertisedUnreachable {
+                            advertised: advertised.into_multi(),
+                            observed: observed.into_multi(),
+                        },
+                        EngineStatus::Failed(err) => {
+                            EngineStatus::Degraded(format!("{scheme} engine failed: {err}"))
+                        }
+                        EngineStatus::Stopped => {
+                            EngineStatus::Degraded(format!("{scheme} engine stopped"))
+                        }
+                    };
+                    status.report(status_update);
+                }
+            });
+
+            let (tx, mut rx) = channel::<EngineRequest<MultiAddr>>(DEFAULT_CHANNEL_SIZE);
+            tokio::spawn(async move {
+                while let Some(req) = rx.recv().await {
+                    let EngineRequest {
+                        pollination_msg,
+                        addr,
+                        tx,
+                    } = req;
+                    let Some(addr) = E::Addr::from_multi(addr) else {
+                        error!("Logic bug: request routed to the wrong engine");
+                        continue;
+                    };
+
+                    let req = EngineRequest {
+                        pollination_msg,
+                        addr,
+                        tx,
+                    };
+                    if requests.send(req).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Ok((tx, shutdown))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::mem::{MemEngine, MemNetwork},
+        message::PollinationMessage,
+    };
+    use uuid::Uuid;
+
+    fn new_member() -> PollinationMessage {
+        PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(1),
+        }
+    }
+
+    #[test]
+    fn test_multi_addr_roundtrip() {
+        for addr in [
+            "http://10.0.0.1:8000/",
+            "https://node.example.com/",
+            "tcp://10.0.0.1:7000",
+            "unix:///run/florescence.sock",
+            "mem://3",
+        ] {
+            let parsed: MultiAddr = addr.parse().unwrap();
+            assert_eq!(parsed.to_string(), addr);
+        }
+    }
+
+    #[test]
+    fn test_multi_addr_scheme() {
+        let addr: MultiAddr = "mem://3".parse().unwrap();
+        assert_eq!(addr.scheme(), Scheme::Mem);
+        let addr: MultiAddr = "tcp://10.0.0.1:7000".parse().unwrap();
+        assert_eq!(addr.scheme(), Scheme::Tcp);
+        let addr: MultiAddr = "unix:///run/florescence.sock".parse().unwrap();
+        assert_eq!(addr.scheme(), Scheme::Unix);
+        assert!(matches!(
+            "tcp://node.example.com".parse::<MultiAddr>(),
+            Err(MultiAddrParseError::SocketAddr(_))
+        ));
+        assert!(matches!(
+            "udp://10.0.0.1:7000".parse::<MultiAddr>(),
+            Err(MultiAddrParseError::UnknownScheme(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_route_by_scheme() {
+        let network = MemNetwork::new();
+        let a = MultiplexEngine::new()
+            .with(MemEngine::new(network.clone(), 0))
+            .run_background()
+            .await
+            .unwrap();
+        let mut b = MultiplexEngine::new()
+            .with(MemEngine::new(network, 1))
+            .run_background()
+            .await
+            .unwrap();
+
+        let (tx, mut rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: new_member(),
+            addr: MultiAddr::Mem(1),
+            tx,
+        };
+        a.requests.send(req).await.unwrap();
+        let event = b.events.recv().await.unwrap();
+        event.tx.send(event.pollination_msg).await.unwrap();
+        assert!(rx.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_route_to_tcp() {
+        use crate::engine::tcp::TcpEngine;
+
+        let network = MemNetwork::new();
+        let a = MultiplexEngine::new()
+            .with(MemEngine::new(network.clone(), 0))
+            .with(TcpEngine::new("127.0.0.1:0".parse().unwrap()))
+            .run_background()
+            .await
+            .unwrap();
+        let mut b = MultiplexEngine::new()
+            .with(TcpEngine::new("127.0.0.1:0".parse().unwrap()))
+            .run_background()
+            .await
+            .unwrap();
+        let EngineStatus::Listening(listening) = b.status.recv().await.unwrap() else {
+            panic!("expected the engine to be listening");
+        };
+        let b_addr = listening.strip_prefix("tcp:").unwrap();
+
+        let (tx, mut rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: new_member(),
+            addr: format!("tcp://{b_addr}").parse().unwrap(),
+            tx,
+        };
+        a.requests.send(req).await.unwrap();
+        let event = b.events.recv().await.unwrap();
+        event.tx.send(event.pollination_msg).await.unwrap();
+        assert!(rx.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unroutable_scheme() {
+        let mut engine = MultiplexEngine::new()
+            .with(MemEngine::new(MemNetwork::new(), 0))
+            .run_background()
+            .await
+            .unwrap();
+
+        let addr: MultiAddr = "http://10.0.0.1:8000/".parse().unwrap();
+        let (tx, _rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: new_member(),
+            addr: addr.clone(),
+            tx,
+        };
+        engine.requests.send(req).await.unwrap();
+        loop {
+            if let EngineStatus::PeerUnreachable { addr: to, .. } =
+                engine.status.recv().await.unwrap()
+            {
+                assert_eq!(to, addr);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use crate::message::PollinationMessage;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    hash::Hash,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Notify, Semaphore, mpsc::Receiver, watch},
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior, interval},
+};
+
+use super::{EngineRequest, EngineStatus, StatusReporter};
+
+const DEFAULT_PEER_CAPACITY: usize = 16;
+const DEFAULT_MAX_IN_FLIGHT: usize = 2;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bounds for the outbound side of an engine.
+///
+/// Every peer gets its own queue of at most `peer_capacity` messages and at
+/// most `max_in_flight` concurrent requests, so a single slow peer only backs
+/// up its own queue instead of stalling gossip to everyone else. Queues left
+/// empty for `idle_timeout` are dropped along with their worker, so peers
+/// that went away do not pile up.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub peer_capacity: usize,
+    pub max_in_flight: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            peer_capacity: DEFAULT_PEER_CAPACITY,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// Live view of the outbound queues of an engine.
+#[derive(Debug)]
+pub struct QueueStats<A> {
+    depths: Arc<Mutex<HashMap<A, usize>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<A> Clone for QueueStats<A> {
+    fn clone(&self) -> Self {
+        Self {
+            depths: self.depths.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<A> Default for QueueStats<A> {
+    fn default() -> Self {
+        Self {
+            depths: Arc::new(Mutex::new(HashMap::new())),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl<A: Clone + Eq + Hash> QueueStats<A> {
+    /// Number of messages waiting to be sent to `addr`.
+    pub fn depth(&self, addr: &A) -> usize {
+        let depths = self.depths.lock().expect("poisoned lock");
+        depths.get(addr).copied().unwrap_or(0)
+    }
+
+    pub fn depths(&self) -> Vec<(A, usize)> {
+        let depths = self.depths.lock().expect("poisoned lock");
+        depths.iter().map(|(a, d)| (a.clone(), *d)).collect()
+    }
+
+    /// Total number of messages dropped because a peer queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn set_depth(&self, addr: &A, depth: usize) {
+        let mut depths = self.depths.lock().expect("poisoned lock");
+        depths.insert(addr.clone(), depth);
+    }
+
+    fn remove(&self, addr: &A) {
+        let mut depths = self.depths.lock().expect("poisoned lock");
+        depths.remove(addr);
+    }
+}
+
+struct PeerQueue<A> {
+    inner: Mutex<VecDeque<EngineRequest<A>>>,
+    capacity: usize,
+    notify: Notify,
+    closed: AtomicBool,
+    last_active: Mutex<Instant>,
+}
+
+impl<A> PeerQueue<A> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.inner.lock().expect("poisoned lock").len()
+    }
+
+    /// Whether the queue has been empty for at least `timeout`.
+    fn idle(&self, timeout: Duration) -> bool {
+        let queue = self.inner.lock().expect("poisoned lock");
+        let last_active = *self.last_active.lock().expect("poisoned lock");
+        queue.is_empty() && last_active.elapsed() >= timeout
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().expect("poisoned lock") = Instant::now();
+    }
+
+    /// Enqueue a request, returning whichever request had to be dropped to
+    /// stay within capacity.
+    ///
+    /// Heartbeats are regenerated every tick, so the oldest queued heartbeat
+    /// is sacrificed first. If only patches are queued the new request is
+    /// dropped instead, since older patches may be the only copy of a change.
+    fn push(&self, req: EngineRequest<A>) -> Option<EngineRequest<A>> {
+        let mut queue = self.inner.lock().expect("poisoned lock");
+        let dropped = if queue.len() >= self.capacity {
+            let heartbeat = queue
+                .iter()
+                .position(|r| matches!(r.pollination_msg, PollinationMessage::Heartbeat { .. }));
+            match heartbeat {
+                Some(idx) => queue.remove(idx),
+                None => return Some(req),
+            }
+        } else {
+            None
+        };
+
+        queue.push_back(req);
+        self.touch();
+        self.notify.notify_one();
+        dropped
+    }
+
+    async fn pop(&self) -> Option<EngineRequest<A>> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut queue = self.inner.lock().expect("poisoned lock");
+                if let Some(req) = queue.pop_front() {
+                    self.touch();
+                    return Some(req);
+                }
+                if self.closed.load(Ordering::Relaxed) {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+}
+
+/// Fan requests from the `Flower` out into per-peer queues, each drained by
+/// its own worker using `send`.
+///
+/// Runs until the request channel closes or `stop` flips to `true`, then
+/// waits for every queued and in-flight request to finish.
+pub(crate) async fn dispatcher<A, F, Fut, E>(
+    mut rx: Receiver<EngineRequest<A>>,
+    mut stop: watch::Receiver<bool>,
+    config: QueueConfig,
+    stats: QueueStats<A>,
+    status: StatusReporter<A>,
+    send: F,
+) where
+    A: Clone + Eq + Hash + fmt::Display + Send + Sync + 'static,
+    F: Fn(A, PollinationMessage) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<PollinationMessage>, E>> + Send + 'static,
+    E: fmt::Display + Send + 'static,
+{
+    let mut peers: HashMap<A, (Arc<PeerQueue<A>>, JoinHandle<()>)> = HashMap::new();
+    let mut prune = interval(config.idle_timeout);
+    prune.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        let req = tokio::select! {
+            req = rx.recv() => req,
+            _ = stop.wait_for(|stop| *stop) => None,
+            _ = prune.tick() => {
+                // Only we push, so nothing can be queued behind our back; the
+                // worker finishes whatever is still in flight and exits
+                peers.retain(|addr, (queue, _)| {
+                    if !queue.idle(config.idle_timeout) {
+                        return true;
+                    }
+                    debug!("Dropping idle queue for {addr}");
+                    queue.close();
+                    stats.remove(addr);
+                    false
+                });
+                continue;
+            }
+        };
+        let Some(req) = req else {
+            break;
+        };
+
+        let addr = req.addr.clone();
+        let (queue, _) = peers.entry(addr.clone()).or_insert_with(|| {
+            let queue = Arc::new(PeerQueue::new(config.peer_capacity));
+            let worker = tokio::spawn(peer_worker(
+                addr.clone(),
+                queue.clone(),
+                stats.clone(),
+                status.clone(),
+                config.max_in_flight,
+                send.clone(),
+            ));
+            (queue, worker)
+        });
+
+        if let Some(dropped) = queue.push(req) {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+            debug!("Queue for {addr} full; dropped {}", dropped.pollination_msg);
+        }
+        stats.set_depth(&addr, queue.len());
+    }
+
+    info!("Outbound dispatcher stopping; draining queues");
+    for (queue, _) in peers.values() {
+        queue.close();
+    }
+    for (_, (_, worker)) in peers.drain() {
+        if let Err(err) = worker.await {
+            error!("Outbound worker failed: {err}");
+        }
+    }
+}
+
+async fn peer_worker<A, F, Fut, E>(
+    addr: A,
+    queue: Arc<PeerQueue<A>>,
+    stats: QueueStats<A>,
+    status: StatusReporter<A>,
+    max_in_flight: usize,
+    send: F,
+) where
+    A: Clone + Eq + Hash + fmt::Display + Send + Sync + 'static,
+    F: Fn(A, PollinationMessage) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<PollinationMessage>, E>> + Send + 'static,
+    E: fmt::Display + Send,
+{
+    let max_in_flight = max_in_flight.max(1);
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
+    loop {
+        // Wait for a free slot before taking from the queue so that a slow
+        // peer accumulates (and sheds) messages in its queue.
+        let permit = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        let Some(req) = queue.pop().await else {
+            break;
+        };
+        stats.set_depth(&addr, queue.len());
+
+        let send = send.clone();
+        let status = status.clone();
+        tokio::spawn(async move {
+            let EngineRequest {
+                pollination_msg,
+                addr,
+                tx,
+            } = req;
+
+            match send(addr.clone(), pollination_msg).await {
+                Ok(None) => {}
+                Ok(Some(res)) => {
+                    if let Err(err) = tx.send(res).await {
+                        error!("Error sending response from {addr}: {err}");
+                    }
+                }
+                Err(err) => {
+                    error!("Error sending request to {addr}: {err}");
+                    status.report(EngineStatus::PeerUnreachable {
+                        addr,
+                        error: err.to_string(),
+                    });
+                }
+            }
+            drop(permit);
+        });
+    }
+
+    // Wait for the requests still in flight
+    let _ = in_flight.acquire_many(max_in_flight as u32).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+    use treeclocks::{EventTree, IdTree};
+    use uuid::Uuid;
+
+    fn request(pollination_msg: PollinationMessage) -> EngineRequest<usize> {
+        let (tx, _) = channel(1);
+        EngineRequest {
+            pollination_msg,
+            addr: 0,
+            tx,
+        }
+    }
+
+    fn heartbeat() -> PollinationMessage {
+        PollinationMessage::Heartbeat {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: Default::default(),
+            legacy_token: None,
+        }
+    }
+
+    fn new_member() -> PollinationMessage {
+        PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(1),
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest_heartbeat() {
+        let queue = PeerQueue::new(2);
+        assert!(queue.push(request(new_member())).is_none());
+        assert!(queue.push(request(heartbeat())).is_none());
+
+        let dropped = queue.push(request(new_member())).unwrap();
+        assert!(matches!(
+            dropped.pollination_msg,
+            PollinationMessage::Heartbeat { .. }
+        ));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_queues_pruned() {
+        let (tx, rx) = channel(1);
+        let (_stop_tx, stop) = watch::channel(false);
+        let config = QueueConfig {
+            idle_timeout: Duration::from_secs(1),
+            ..QueueConfig::default()
+        };
+        let stats = QueueStats::default();
+        let send = |_, msg| async move { Ok::<_, String>(Some(msg)) };
+        tokio::spawn(dispatcher(
+            rx,
+            stop,
+            config,
+            stats.clone(),
+            StatusReporter::channel().0,
+            send,
+        ));
+
+        tx.send(request(new_member())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(stats.depths(), vec![(0, 0)]);
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(stats.depths().is_empty());
+    }
+
+    #[test]
+    fn test_drop_newest_without_heartbeats() {
+        let queue = PeerQueue::new(1);
+        assert!(queue.push(request(new_member())).is_none());
+        assert!(queue.push(request(heartbeat())).is_some());
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::message::PollinationMessage;
+use bytes::{Bytes, BytesMut};
+use pollination::InvalidMessage;
+use std::{collections::HashMap, hash::Hash, sync::Mutex, time::Duration};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc::{Sender, channel},
+    time::timeout,
+};
+use uuid::Uuid;
+
+use super::{
+    DEFAULT_CHANNEL_SIZE, EngineEvent,
+    tls::{PeerIdentity, TlsError},
+    wire::{Wire, WireError},
+};
+
+/// How long a peer gets to answer, so one that hangs only holds up its own
+/// queue.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Write `payload` as a single frame: its length as a big-endian `u32`, then
+/// the bytes. An empty frame is a request that went unanswered.
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    payload: &[u8],
+) -> Result<(), StreamEngineError> {
+    let len = u32::try_from(payload.len()).map_err(|_| InvalidMessage::TooLarge {
+        size: payload.len(),
+        max: u32::MAX as usize,
+    })?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read a single frame, refusing one larger than `max` before allocating it.
+async fn read_frame<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max: usize,
+) -> Result<Bytes, StreamEngineError> {
+    let len = stream.read_u32().await? as usize;
+    if len > max {
+        return Err(InvalidMessage::TooLarge { size: len, max }.into());
+    }
+    let mut buf = BytesMut::zeroed(len);
+    stream.read_exact(&mut buf).await?;
+    Ok(buf.freeze())
+}
+
+/// Answer the one request a connection carries. With mutual TLS, `identity`
+/// is who the peer's certificate says it is.
+pub(crate) async fn serve<S>(
+    mut stream: S,
+    wire: &Wire,
+    events: &Sender<EngineEvent>,
+    identity: Option<PeerIdentity>,
+) -> Result<(), StreamEngineError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let bytes = read_frame(&mut stream, wire.limits().max_message_size).await?;
+    let decoded = wire.decode(bytes)?;
+    let peer = decoded.msg.uuid();
+    if let Some(identity) = identity {
+        identity.verify(peer)?;
+    }
+    let pollination_msg = wire.accept(decoded);
+
+    let (tx, mut rx) = channel(DEFAULT_CHANNEL_SIZE);
+    let event = EngineEvent {
+        pollination_msg,
+        tx,
+    };
+    events
+        .send(event)
+        .await
+        .map_err(|_| StreamEngineError::Stopped)?;
+
+    let reply = match rx.recv().await {
+        Some(res) => wire.encode(&res, Some(peer))?,
+        None => vec![],
+    };
+    write_frame(&mut stream, &reply).await
+}
+
+/// Outbound side of a stream engine. Every request gets a connection of its
+/// own, which the peer closes once it has answered.
+pub(crate) struct Client<A> {
+    wire: Wire,
+    /// Who answered at each address, for picking the wire format version.
+    uuids: Mutex<HashMap<A, Uuid>>,
+}
+
+impl<A: Clone + Eq + Hash> Client<A> {
+    pub(crate) fn new(wire: Wire) -> Self {
+        Self {
+            wire,
+            uuids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send `msg` to the peer at `addr` over `stream`, returning its reply
+    /// if it had one. With mutual TLS, `identity` is who the peer's
+    /// certificate says it is.
+    pub(crate) async fn request<S>(
+        &self,
+        addr: A,
+        stream: S,
+        msg: &PollinationMessage,
+        identity: Option<PeerIdentity>,
+    ) -> Result<Option<PollinationMessage>, StreamEngineError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        timeout(
+            REQUEST_TIMEOUT,
+            self.request_inner(addr, stream, msg, identity),
+        )
+        .await
+        .map_err(|_| StreamEngineError::Timeout)?
+    }
+
+    async fn request_inner<S>(
+        &self,
+        addr: A,
+        mut stream: S,
+        msg: &PollinationMessage,
+        identity: Option<PeerIdentity>,
+    ) -> Result<Option<PollinationMessage>, StreamEngineError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let uuid = self
+            .uuids
+            .lock()
+            .expect("poisoned lock")
+            .get(&addr)
+            .copied();
+        write_frame(&mut stream, &self.wire.encode(msg, uuid)?).await?;
+
+        let reply = read_frame(&mut stream, self.wire.limits().max_message_size).await?;
+        if reply.is_empty() {
+            return Ok(None);
+        }
+        let decoded = self.wire.decode(reply)?;
+        if let Some(identity) = identity {
+            identity.verify(decoded.msg.uuid())?;
+        }
+        let pollination_msg = self.wire.accept(decoded);
+        let mut uuids = self.uuids.lock().expect("poisoned lock");
+        uuids.insert(addr, pollination_msg.uuid());
+        Ok(Some(pollination_msg))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StreamEngineError {
+    #[error("StdIO error: {0}")]
+    StdIo(#[from] std::io::Error),
+
+    #[error("Wire error: {0}")]
+    Wire(#[from] WireError),
+
+    #[error("TLS error: {0}")]
+    Tls(#[from] TlsError),
+
+    #[error("Peer did not answer within {REQUEST_TIMEOUT:?}")]
+    Timeout,
+
+    #[error("Engine stopped")]
+    Stopped,
+}
+
+impl From<InvalidMessage> for StreamEngineError {
+    fn from(err: InvalidMessage) -> Self {
+        Self::Wire(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let (mut a, mut b) = duplex(64);
+        write_frame(&mut a, b"pollen").await.unwrap();
+        write_frame(&mut a, b"").await.unwrap();
+        assert_eq!(read_frame(&mut b, 16).await.unwrap(), &b"pollen"[..]);
+        assert!(read_frame(&mut b, 16).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_serve_checks_identity() {
+        let wire = Wire::default();
+        let msg = PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(1),
+        };
+        let (mut client, server) = duplex(1024);
+        write_frame(&mut client, &wire.encode(&msg, None).unwrap())
+            .await
+            .unwrap();
+
+        let (events, _rx) = channel(1);
+        let identity = PeerIdentity {
+            uuid: Uuid::from_u128(2),
+        };
+        assert!(matches!(
+            serve(server, &wire, &events, Some(identity)).await,
+            Err(StreamEngineError::Tls(TlsError::IdentityMismatch { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let (mut a, mut b) = duplex(64);
+        write_frame(&mut a, b"pollen").await.unwrap();
+        assert!(matches!(
+            read_frame(&mut b, 4).await,
+            Err(StreamEngineError::Wire(WireError::Invalid(
+                InvalidMessage::TooLarge { size: 6, max: 4 }
+            )))
+        ));
+    }
+}
@@ -0,0 +1,264 @@
+use pollination::{ClusterCipher, Keyring, Limits, Protocol};
+use rustls_pki_types::ServerName;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{Sender, channel},
+        watch,
+    },
+    task::JoinSet,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::{
+    DEFAULT_CHANNEL_SIZE, Engine, EngineChannels, EngineEvent, EngineShutdown, EngineStatus,
+    StatusReporter,
+    queue::{self, QueueConfig, QueueStats},
+    stream::{self, Client, StreamEngineError},
+    tls::{PeerIdentity, TlsConfig},
+    wire::{KeyringHandle, Wire},
+};
+
+/// Engine speaking length-prefixed frames over TCP, one connection per
+/// request, optionally wrapped in mutual TLS.
+pub struct TcpEngine {
+    socket_addr: SocketAddr,
+    advertise_addr: Option<SocketAddr>,
+    wire: Wire,
+    tls: Option<TlsConfig>,
+    queue_config: QueueConfig,
+    queue_stats: QueueStats<SocketAddr>,
+}
+
+impl TcpEngine {
+    pub fn new(socket_addr: SocketAddr) -> Self {
+        Self {
+            socket_addr,
+            advertise_addr: None,
+            wire: Wire::default(),
+            tls: None,
+            queue_config: QueueConfig::default(),
+            queue_stats: QueueStats::default(),
+        }
+    }
+
+    /// The address peers should use to reach us, when it differs from the
+    /// bound `socket_addr`.
+    pub fn with_advertise_addr(mut self, addr: SocketAddr) -> Self {
+        self.advertise_addr = Some(addr);
+        self
+    }
+
+    /// Require mutual TLS on every connection, both inbound and outbound.
+    ///
+    /// Peers are dialled by IP, so each node certificate must carry the IP
+    /// address it is reached at as a SAN, next to its `urn:uuid:` identity.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sign all traffic with a shared cluster secret. Keys are rotated
+    /// through [`TcpEngine::keyring`].
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.wire = self.wire.with_keyring(keyring);
+        self
+    }
+
+    /// Handle to roll keys over once the engine is running.
+    pub fn keyring(&self) -> Option<KeyringHandle> {
+        self.wire.keyring()
+    }
+
+    /// Advertise a different protocol range than this build's default.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.wire = self.wire.with_protocol(protocol);
+        self
+    }
+
+    /// Encrypt all traffic with a shared cluster key.
+    pub fn with_cipher(mut self, cipher: ClusterCipher) -> Self {
+        self.wire = self.wire.with_cipher(cipher);
+        self
+    }
+
+    /// Bound the size of incoming messages.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.wire = self.wire.with_limits(limits);
+        self
+    }
+
+    pub fn with_queue_config(mut self, queue_config: QueueConfig) -> Self {
+        self.queue_config = queue_config;
+        self
+    }
+
+    /// Handle for observing outbound queue depths once the engine is running.
+    pub fn queue_stats(&self) -> QueueStats<SocketAddr> {
+        self.queue_stats.clone()
+    }
+}
+
+impl Engine for TcpEngine {
+    type Addr = SocketAddr;
+    type Error = StreamEngineError;
+
+    fn advertise_addr(&self) -> Option<SocketAddr> {
+        self.advertise_addr
+    }
+
+    async fn run_background(self) -> Result<EngineChannels<Self::Addr>, Self::Error> {
+        let (acceptor, connector) = match &self.tls {
+            Some(tls) => (
+                Some(TlsAcceptor::from(tls.server_config()?)),
+                Some(TlsConnector::from(Arc::new(tls.client_config()?))),
+            ),
+            None => (None, None),
+        };
+        let listener = TcpListener::bind(self.socket_addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (requests, rx) = channel(DEFAULT_CHANNEL_SIZE);
+        let (events_tx, events) = channel(DEFAULT_CHANNEL_SIZE);
+        let (status, status_rx) = StatusReporter::channel();
+        let (shutdown, signal, done) = EngineShutdown::new();
+        let (stop_tx, mut stop_rx) = watch::channel(false);
+
+        let client = Arc::new(Client::new(self.wire.clone()));
+        let send = move |addr: SocketAddr, msg| {
+            let client = client.clone();
+            let connector = connector.clone();
+            async move {
+                let stream = TcpStream::connect(addr).await?;
+                let Some(connector) = connector else {
+                    return client.request(addr, stream, &msg, None).await;
+                };
+                let stream = connector
+                    .connect(ServerName::from(addr.ip()), stream)
+                    .await?;
+                let identity =
+                    PeerIdentity::from_peer_certificates(stream.get_ref().1.peer_certificates())?;
+                client.request(addr, stream, &msg, Some(identity)).await
+            }
+        };
+        let dispatcher = tokio::spawn(queue::dispatcher(
+            rx,
+            stop_rx.clone(),
+            self.queue_config,
+            self.queue_stats,
+            status.clone(),
+            send,
+        ));
+
+        let wire = self.wire;
+        let server = tokio::spawn(async move {
+            let mut conns = JoinSet::new();
+            loop {
+                let conn = tokio::select! {
+                    conn = listener.accept() => conn,
+                    _ = stop_rx.wait_for(|stop| *stop) => break,
+                };
+                let (stream, remote_addr) = match conn {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        error!("Error accepting connection: {err}");
+                        continue;
+                    }
+                };
+
+                let wire = wire.clone();
+                let events_tx = events_tx.clone();
+                let acceptor = acceptor.clone();
+                conns.spawn(async move {
+                    let res = match acceptor {
+                        Some(acceptor) => serve_tls(stream, acceptor, &wire, &events_tx).await,
+                        None => stream::serve(stream, &wire, &events_tx, None).await,
+                    };
+                    if let Err(err) = res {
+                        warn!("Dropping request from {remote_addr}: {err}");
+                    }
+                });
+                // Reap finished connections as we go
+                while conns.try_join_next().is_some() {}
+            }
+            // Let requests in flight finish
+            conns.join_all().await;
+        });
+        status.report(EngineStatus::Listening(local_addr.to_string()));
+
+        tokio::spawn(async move {
+            let _ = signal.await;
+            info!("Shutting down; draining in-flight requests");
+            let _ = stop_tx.send(true);
+            if let Err(err) = server.await {
+                error!("TCP server task failed: {err}");
+                status.failed(err);
+            }
+            if let Err(err) = dispatcher.await {
+                error!("Outbound dispatcher failed: {err}");
+            }
+            status.report(EngineStatus::Stopped);
+            let _ = done.send(());
+        });
+
+        Ok(EngineChannels {
+            requests,
+            events,
+            status: status_rx,
+            shutdown,
+        })
+    }
+}
+
+/// Complete the TLS handshake, then answer the request as the peer the
+/// certificate names.
+async fn serve_tls(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    wire: &Wire,
+    events: &Sender<EngineEvent>,
+) -> Result<(), StreamEngineError> {
+    let stream = acceptor.accept(stream).await?;
+    let identity = PeerIdentity::from_peer_certificates(stream.get_ref().1.peer_certificates())?;
+    stream::serve(stream, wire, events, Some(identity)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::EngineRequest, message::PollinationMessage};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_request_reply() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let a = TcpEngine::new(addr).run_background().await.unwrap();
+        let mut b = TcpEngine::new(addr).run_background().await.unwrap();
+        let mut b_status = b.status.resubscribe();
+        let b_addr = b.status.recv().await.unwrap();
+        let EngineStatus::Listening(b_addr) = b_addr else {
+            panic!("expected the engine to be listening");
+        };
+
+        let (tx, mut rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: PollinationMessage::NewMember {
+                uuid: Uuid::from_u128(1),
+            },
+            addr: b_addr.parse().unwrap(),
+            tx,
+        };
+        a.requests.send(req).await.unwrap();
+        let event = b.events.recv().await.unwrap();
+        assert_eq!(event.pollination_msg.uuid(), Uuid::from_u128(1));
+        let reply = PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(2),
+        };
+        event.tx.send(reply).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().uuid(), Uuid::from_u128(2));
+
+        b.shutdown.shutdown().await;
+        while !matches!(b_status.recv().await, Ok(EngineStatus::Stopped)) {}
+    }
+}
@@ -0,0 +1,150 @@
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    server::{WebPkiClientVerifier, danger::ClientCertVerifier},
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use std::{path::Path, sync::Arc};
+use thiserror::Error;
+use uuid::Uuid;
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+const UUID_URN_PREFIX: &str = "urn:uuid:";
+
+/// Certificates used for mutually authenticated TLS between engines.
+///
+/// Every node presents `cert_chain` both as a server and as a client, and
+/// only accepts peers whose certificates chain up to one of `roots`. The leaf
+/// certificate must carry a `urn:uuid:<uuid>` URI SAN which is checked against
+/// the UUID the peer claims in its messages.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert_chain: Vec<CertificateDer<'static>>,
+    private_key: Arc<PrivateKeyDer<'static>>,
+    roots: Arc<RootCertStore>,
+}
+
+impl TlsConfig {
+    pub fn new(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+        roots: RootCertStore,
+    ) -> Self {
+        Self {
+            cert_chain,
+            private_key: Arc::new(private_key),
+            roots: Arc::new(roots),
+        }
+    }
+
+    /// Load the node certificate chain, its private key and the trusted CA
+    /// bundle from PEM files.
+    pub fn from_pem_files(
+        cert_chain: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+        ca_bundle: impl AsRef<Path>,
+    ) -> Result<Self, TlsError> {
+        let cert_chain =
+            CertificateDer::pem_file_iter(cert_chain)?.collect::<Result<Vec<_>, _>>()?;
+        let private_key = PrivateKeyDer::from_pem_file(private_key)?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in CertificateDer::pem_file_iter(ca_bundle)? {
+            roots.add(ca?)?;
+        }
+
+        Ok(Self::new(cert_chain, private_key, roots))
+    }
+
+    pub(crate) fn server_config(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let verifier: Arc<dyn ClientCertVerifier> =
+            WebPkiClientVerifier::builder(self.roots.clone()).build()?;
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(self.cert_chain.clone(), self.private_key.clone_key())?;
+        Ok(Arc::new(config))
+    }
+
+    pub(crate) fn client_config(&self) -> Result<ClientConfig, TlsError> {
+        let config = ClientConfig::builder()
+            .with_root_certificates(self.roots.clone())
+            .with_client_auth_cert(self.cert_chain.clone(), self.private_key.clone_key())?;
+        Ok(config)
+    }
+}
+
+/// Identity of a peer as certified by its TLS certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub uuid: Uuid,
+}
+
+impl PeerIdentity {
+    /// Extract the identity from the leaf certificate of a peer.
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> Result<Self, TlsError> {
+        let (_, cert) = x509_parser::certificate::X509Certificate::from_der(cert.as_ref())
+            .map_err(|err| TlsError::InvalidCertificate(err.to_string()))?;
+
+        let san = cert
+            .subject_alternative_name()
+            .map_err(|err| TlsError::InvalidCertificate(err.to_string()))?
+            .ok_or(TlsError::MissingIdentity)?;
+
+        san.value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::URI(uri) => uri.strip_prefix(UUID_URN_PREFIX),
+                _ => None,
+            })
+            .ok_or(TlsError::MissingIdentity)?
+            .parse()
+            .map(|uuid| Self { uuid })
+            .map_err(|_| TlsError::MissingIdentity)
+    }
+
+    /// Extract the identity from the certificates a peer presented during the
+    /// handshake, if any.
+    pub fn from_peer_certificates(certs: Option<&[CertificateDer<'_>]>) -> Result<Self, TlsError> {
+        let leaf = certs
+            .and_then(|certs| certs.first())
+            .ok_or(TlsError::MissingCertificate)?;
+        Self::from_certificate(leaf)
+    }
+
+    /// Check that a message claiming to be from `uuid` was actually sent by
+    /// this peer.
+    pub fn verify(&self, uuid: Uuid) -> Result<(), TlsError> {
+        if self.uuid == uuid {
+            Ok(())
+        } else {
+            Err(TlsError::IdentityMismatch {
+                claimed: uuid,
+                certified: self.uuid,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Rustls error: {0}")]
+    Rustls(#[from] rustls::Error),
+
+    #[error("Client verifier error: {0}")]
+    Verifier(#[from] rustls::server::VerifierBuilderError),
+
+    #[error("PEM error: {0}")]
+    Pem(#[from] rustls_pki_types::pem::Error),
+
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(String),
+
+    #[error("Peer presented no certificate")]
+    MissingCertificate,
+
+    #[error("Peer certificate has no `urn:uuid:` SAN")]
+    MissingIdentity,
+
+    #[error("Peer claimed UUID {claimed} but certificate is for {certified}")]
+    IdentityMismatch { claimed: Uuid, certified: Uuid },
+}
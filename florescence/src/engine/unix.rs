@@ -0,0 +1,232 @@
+use pollination::{ClusterCipher, Keyring, Limits, Protocol};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::{mpsc::channel, watch},
+    task::JoinSet,
+};
+
+use super::{
+    DEFAULT_CHANNEL_SIZE, Engine, EngineChannels, EngineShutdown, EngineStatus, StatusReporter,
+    queue::{self, QueueConfig, QueueStats},
+    stream::{self, Client, StreamEngineError},
+    wire::{KeyringHandle, Wire},
+};
+
+/// Path of a unix domain socket a peer listens on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UnixAddr(PathBuf);
+
+impl UnixAddr {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self(path.into())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<UnixAddr> for PathBuf {
+    fn from(addr: UnixAddr) -> Self {
+        addr.0
+    }
+}
+
+impl fmt::Display for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+/// Engine speaking length-prefixed frames over unix domain sockets, for
+/// nodes sharing a host. The socket file is removed on shutdown.
+pub struct UnixEngine {
+    addr: UnixAddr,
+    wire: Wire,
+    queue_config: QueueConfig,
+    queue_stats: QueueStats<UnixAddr>,
+}
+
+impl UnixEngine {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            addr: UnixAddr::new(path),
+            wire: Wire::default(),
+            queue_config: QueueConfig::default(),
+            queue_stats: QueueStats::default(),
+        }
+    }
+
+    /// Sign all traffic with a shared cluster secret. Keys are rotated
+    /// through [`UnixEngine::keyring`].
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.wire = self.wire.with_keyring(keyring);
+        self
+    }
+
+    /// Handle to roll keys over once the engine is running.
+    pub fn keyring(&self) -> Option<KeyringHandle> {
+        self.wire.keyring()
+    }
+
+    /// Advertise a different protocol range than this build's default.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.wire = self.wire.with_protocol(protocol);
+        self
+    }
+
+    /// Encrypt all traffic with a shared cluster key.
+    pub fn with_cipher(mut self, cipher: ClusterCipher) -> Self {
+        self.wire = self.wire.with_cipher(cipher);
+        self
+    }
+
+    /// Bound the size of incoming messages.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.wire = self.wire.with_limits(limits);
+        self
+    }
+
+    pub fn with_queue_config(mut self, queue_config: QueueConfig) -> Self {
+        self.queue_config = queue_config;
+        self
+    }
+
+    /// Handle for observing outbound queue depths once the engine is running.
+    pub fn queue_stats(&self) -> QueueStats<UnixAddr> {
+        self.queue_stats.clone()
+    }
+}
+
+impl Engine for UnixEngine {
+    type Addr = UnixAddr;
+    type Error = StreamEngineError;
+
+    fn advertise_addr(&self) -> Option<UnixAddr> {
+        Some(self.addr.clone())
+    }
+
+    async fn run_background(self) -> Result<EngineChannels<Self::Addr>, Self::Error> {
+        let listener = UnixListener::bind(self.addr.path())?;
+
+        let (requests, rx) = channel(DEFAULT_CHANNEL_SIZE);
+        let (events_tx, events) = channel(DEFAULT_CHANNEL_SIZE);
+        let (status, status_rx) = StatusReporter::channel();
+        let (shutdown, signal, done) = EngineShutdown::new();
+        let (stop_tx, mut stop_rx) = watch::channel(false);
+
+        let client = Arc::new(Client::new(self.wire.clone()));
+        let send = move |addr: UnixAddr, msg| {
+            let client = client.clone();
+            async move {
+                let stream = UnixStream::connect(addr.path()).await?;
+                client.request(addr, stream, &msg, None).await
+            }
+        };
+        let dispatcher = tokio::spawn(queue::dispatcher(
+            rx,
+            stop_rx.clone(),
+            self.queue_config,
+            self.queue_stats,
+            status.clone(),
+            send,
+        ));
+
+        let wire = self.wire;
+        let server = tokio::spawn(async move {
+            let mut conns = JoinSet::new();
+            loop {
+                let conn = tokio::select! {
+                    conn = listener.accept() => conn,
+                    _ = stop_rx.wait_for(|stop| *stop) => break,
+                };
+                let stream = match conn {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        error!("Error accepting connection: {err}");
+                        continue;
+                    }
+                };
+
+                let wire = wire.clone();
+                let events_tx = events_tx.clone();
+                conns.spawn(async move {
+                    if let Err(err) = stream::serve(stream, &wire, &events_tx, None).await {
+                        warn!("Dropping request: {err}");
+                    }
+                });
+                // Reap finished connections as we go
+                while conns.try_join_next().is_some() {}
+            }
+            // Let requests in flight finish
+            conns.join_all().await;
+        });
+        let addr = self.addr;
+        status.report(EngineStatus::Listening(addr.to_string()));
+
+        tokio::spawn(async move {
+            let _ = signal.await;
+            info!("Shutting down; draining in-flight requests");
+            let _ = stop_tx.send(true);
+            if let Err(err) = server.await {
+                error!("Unix socket server task failed: {err}");
+                status.failed(err);
+            }
+            if let Err(err) = tokio::fs::remove_file(addr.path()).await {
+                warn!("Could not remove {addr}: {err}");
+            }
+            if let Err(err) = dispatcher.await {
+                error!("Outbound dispatcher failed: {err}");
+            }
+            status.report(EngineStatus::Stopped);
+            let _ = done.send(());
+        });
+
+        Ok(EngineChannels {
+            requests,
+            events,
+            status: status_rx,
+            shutdown,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::EngineRequest, message::PollinationMessage};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_request_reply() {
+        let dir = std::env::temp_dir().join(format!("florescence-unix-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let (a_path, b_path) = (dir.join("a.sock"), dir.join("b.sock"));
+        let a = UnixEngine::new(&a_path).run_background().await.unwrap();
+        let mut b = UnixEngine::new(&b_path).run_background().await.unwrap();
+
+        let (tx, mut rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: PollinationMessage::NewMember {
+                uuid: Uuid::from_u128(1),
+            },
+            addr: UnixAddr::new(&b_path),
+            tx,
+        };
+        a.requests.send(req).await.unwrap();
+        let event = b.events.recv().await.unwrap();
+        event.tx.send(event.pollination_msg).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().uuid(), Uuid::from_u128(1));
+
+        a.shutdown.shutdown().await;
+        b.shutdown.shutdown().await;
+        assert!(!b_path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
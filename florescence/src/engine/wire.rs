@@ -0,0 +1,359 @@
+use crate::{message::PollinationMessage, serialization::SerializeError};
+use bytes::Bytes;
+use pollination::{
+    AuthenticationError, ClusterCipher, ClusterKey, EncryptionError, EnvelopeError, Features,
+    Format, InvalidMessage, Keyring, Limits, Negotiated, PatchDecodeError, Protocol,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// How long to remember what a silent peer negotiated. Forgetting only means
+/// falling back to our oldest version until it is heard from again.
+const NEGOTIATED_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How messages are put on and taken off the wire. Shared by all engines so
+/// that message protection is independent of the transport.
+///
+/// Outgoing messages are wrapped in a versioned envelope, then encrypted,
+/// then signed; incoming messages go through the same steps in reverse.
+#[derive(Clone, Debug, Default)]
+pub struct Wire {
+    keyring: Option<KeyringHandle>,
+    cipher: Option<ClusterCipher>,
+    protocol: Protocol,
+    limits: Limits,
+    negotiated: Arc<Mutex<NegotiatedPeers>>,
+}
+
+/// Shared access to the keyring of a running engine, so keys can be rolled
+/// over without a restart. Every step applies to the next message signed or
+/// verified.
+#[derive(Clone, Debug)]
+pub struct KeyringHandle(Arc<RwLock<Keyring>>);
+
+impl KeyringHandle {
+    pub fn new(keyring: Keyring) -> Self {
+        Self(Arc::new(RwLock::new(keyring)))
+    }
+
+    /// See [`Keyring::accept`].
+    pub fn accept(&self, key: ClusterKey) {
+        self.0.write().expect("poisoned lock").accept(key);
+    }
+
+    /// See [`Keyring::sign_with`].
+    pub fn sign_with(&self, key_id: u32) -> Result<(), AuthenticationError> {
+        self.0.write().expect("poisoned lock").sign_with(key_id)
+    }
+
+    /// See [`Keyring::retire`].
+    pub fn retire(&self, key_id: u32) {
+        self.0.write().expect("poisoned lock").retire(key_id);
+    }
+
+    /// A copy of the keyring as it is now.
+    pub fn get(&self) -> Keyring {
+        self.0.read().expect("poisoned lock").clone()
+    }
+
+    fn sign(&self, payload: Vec<u8>) -> Result<Vec<u8>, SerializeError> {
+        self.0.read().expect("poisoned lock").sign(payload)
+    }
+
+    fn verify(&self, bytes: &Bytes) -> Result<Bytes, AuthenticationError> {
+        self.0.read().expect("poisoned lock").verify(bytes)
+    }
+}
+
+/// What was agreed with each peer and when it was last heard from.
+#[derive(Debug)]
+struct NegotiatedPeers {
+    peers: HashMap<Uuid, (Negotiated, Instant)>,
+    last_sweep: Instant,
+}
+
+impl Default for NegotiatedPeers {
+    fn default() -> Self {
+        Self {
+            peers: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl NegotiatedPeers {
+    /// Record what `peer` agreed to, returning whether it changed.
+    fn insert(&mut self, peer: Uuid, agreed: Negotiated, now: Instant) -> bool {
+        if now.duration_since(self.last_sweep) >= NEGOTIATED_TTL {
+            self.evict(now);
+        }
+        let prev = self.peers.insert(peer, (agreed, now));
+        prev.map(|(prev, _)| prev) != Some(agreed)
+    }
+
+    fn evict(&mut self, now: Instant) {
+        self.peers
+            .retain(|_, (_, seen)| now.duration_since(*seen) < NEGOTIATED_TTL);
+        self.last_sweep = now;
+    }
+}
+
+impl Wire {
+    /// Sign every message with the cluster keyring and drop any incoming
+    /// message that fails verification.
+    pub fn with_keyring(self, keyring: Keyring) -> Self {
+        self.with_keyring_handle(KeyringHandle::new(keyring))
+    }
+
+    /// Like [`Wire::with_keyring`], sharing a keyring that is rotated
+    /// through `handle`.
+    pub fn with_keyring_handle(mut self, handle: KeyringHandle) -> Self {
+        self.keyring = Some(handle);
+        self
+    }
+
+    /// Encrypt every message so peer addresses and pollinator values are not
+    /// readable by anyone observing gossip.
+    pub fn with_cipher(mut self, cipher: ClusterCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Override the advertised protocol, e.g. to keep speaking an older
+    /// version until the whole cluster is upgraded.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Seal messages in `format`, e.g. JSON while debugging a single node.
+    /// Peers read whichever format the envelope names; those without the
+    /// format's feature are sent bincode.
+    ///
+    /// Only the envelope changes: patches inside it stay bincode, which any
+    /// peer can read, even one we have not negotiated with yet.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.protocol = self.protocol.with_format(format);
+        self
+    }
+
+    /// Reject incoming messages larger than `limits.max_message_size` before
+    /// looking at them.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Handle to rotate the keyring while the engine runs.
+    pub fn keyring(&self) -> Option<KeyringHandle> {
+        self.keyring.clone()
+    }
+
+    /// What was agreed with `peer`, once we have heard from it.
+    pub fn negotiated(&self, peer: Uuid) -> Option<Negotiated> {
+        let negotiated = self.negotiated.lock().expect("poisoned lock");
+        negotiated.peers.get(&peer).map(|(agreed, _)| *agreed)
+    }
+
+    /// Encode a message for `peer`, at the version negotiated with it or at
+    /// our oldest supported version if we have not heard from it yet. Messages
+    /// the peer would not understand are refused. Patches
+    /// are decompressed and the payload sealed in bincode for peers that have
+    /// not agreed to the codec or format.
+    pub(crate) fn encode(
+        &self,
+        msg: &PollinationMessage,
+        peer: Option<Uuid>,
+    ) -> Result<Vec<u8>, WireError> {
+        let negotiated = peer.and_then(|peer| self.negotiated(peer));
+        let version = negotiated.map_or(self.protocol.min_version, |agreed| agreed.version);
+        let features = negotiated.map_or(Features::NONE, |agreed| agreed.features);
+        if !features.contains(msg.required()) {
+            return Err(WireError::Unsupported(msg.required()));
+        }
+        let msg = msg.for_features(features, &self.limits)?;
+
+        let protocol = self
+            .protocol
+            .with_format(self.protocol.format.within(features));
+        let mut bytes = protocol.seal(&msg, version)?;
+        if let Some(cipher) = &self.cipher {
+            bytes = cipher.encrypt(&bytes)?;
+        }
+        if let Some(keyring) = &self.keyring {
+            bytes = keyring.sign(bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Decode a message without copying `bytes`; unless it was encrypted,
+    /// patches in the message point straight into it.
+    ///
+    /// What the sender agreed to is only remembered once it is passed to
+    /// [`Wire::accept`], after the sender is known to be who it claims.
+    pub(crate) fn decode(&self, mut bytes: Bytes) -> Result<Decoded, WireError> {
+        if bytes.len() > self.limits.max_message_size {
+            return Err(InvalidMessage::TooLarge {
+                size: bytes.len(),
+                max: self.limits.max_message_size,
+            }
+            .into());
+        }
+        if let Some(keyring) = &self.keyring {
+            bytes = keyring.verify(&bytes)?;
+        }
+        if let Some(cipher) = &self.cipher {
+            bytes = cipher.decrypt(&bytes)?.into();
+        }
+
+        let (msg, peer) = self.protocol.open_within(&bytes, &self.limits)?;
+        // Always succeeds: we could read the message, so the version it was
+        // sealed at is one we have in common
+        let agreed = self.protocol.negotiate(&peer);
+        Ok(Decoded { msg, agreed })
+    }
+
+    /// Remember what the sender of `decoded` agreed to, returning its
+    /// message.
+    pub(crate) fn accept(&self, decoded: Decoded) -> PollinationMessage {
+        let Decoded { msg, agreed } = decoded;
+        if let Some(agreed) = agreed {
+            let mut negotiated = self.negotiated.lock().expect("poisoned lock");
+            if negotiated.insert(msg.uuid(), agreed, Instant::now()) {
+                debug!("Speaking v{} with {}", agreed.version, msg.uuid());
+            }
+        }
+        msg
+    }
+}
+
+/// A message off the wire whose sender has not been vouched for yet.
+#[derive(Debug)]
+pub(crate) struct Decoded {
+    pub(crate) msg: PollinationMessage,
+    agreed: Option<Negotiated>,
+}
+
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Serialize error: {0}")]
+    Serialize(#[from] SerializeError),
+
+    #[error("Authentication error: {0}")]
+    Authentication(#[from] AuthenticationError),
+
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] EncryptionError),
+
+    #[error("Invalid message: {0}")]
+    Invalid(#[from] InvalidMessage),
+
+    #[error("Patch error: {0}")]
+    Patch(#[from] PatchDecodeError),
+
+    #[error("Peer has not agreed to {0:?}")]
+    Unsupported(Features),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pollination::{PROTOCOL_VERSION, RealityToken};
+    use treeclocks::{EventTree, IdTree};
+
+    #[test]
+    fn test_negotiated_evicted() {
+        let start = Instant::now();
+        let agreed = Negotiated {
+            version: 2,
+            features: Features::NONE,
+        };
+        let mut negotiated = NegotiatedPeers {
+            peers: HashMap::new(),
+            last_sweep: start,
+        };
+        assert!(negotiated.insert(Uuid::from_u128(1), agreed, start));
+        assert!(!negotiated.insert(Uuid::from_u128(1), agreed, start));
+
+        let later = start + NEGOTIATED_TTL;
+        negotiated.insert(Uuid::from_u128(2), agreed, later);
+        assert!(!negotiated.peers.contains_key(&Uuid::from_u128(1)));
+        assert!(negotiated.peers.contains_key(&Uuid::from_u128(2)));
+    }
+
+    #[test]
+    fn test_handoff_needs_feature() {
+        let wire = Wire::default();
+        let msg = PollinationMessage::Handoff {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+            fragment: IdTree::One,
+        };
+        assert!(matches!(
+            wire.encode(&msg, None),
+            Err(WireError::Unsupported(Features::HANDOFF))
+        ));
+
+        let peer = Uuid::from_u128(2);
+        let agreed = Negotiated {
+            version: PROTOCOL_VERSION,
+            features: Features::HANDOFF,
+        };
+        wire.negotiated
+            .lock()
+            .unwrap()
+            .insert(peer, agreed, Instant::now());
+        assert!(wire.encode(&msg, Some(peer)).is_ok());
+    }
+
+    #[test]
+    fn test_negotiated_on_accept() {
+        let wire = Wire::default();
+        let msg = PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(1),
+        };
+        let bytes = wire.encode(&msg, None).unwrap();
+
+        // A message failing identity checks never reaches `accept`
+        let decoded = wire.decode(bytes.into()).unwrap();
+        assert!(wire.negotiated(Uuid::from_u128(1)).is_none());
+
+        wire.accept(decoded);
+        assert!(wire.negotiated(Uuid::from_u128(1)).is_some());
+    }
+
+    #[test]
+    fn test_rotate_keyring_live() {
+        let msg = PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(1),
+        };
+        let keyring = Keyring::new(ClusterKey::new(0, "old"));
+        let sender = Wire::default().with_keyring(keyring.clone());
+        let receiver = Wire::default().with_keyring(keyring);
+        let (sending, receiving) = (sender.keyring().unwrap(), receiver.keyring().unwrap());
+
+        sending.accept(ClusterKey::new(1, "new"));
+        sending.sign_with(1).unwrap();
+        let bytes = sender.encode(&msg, None).unwrap();
+        assert!(receiver.decode(bytes.clone().into()).is_err());
+
+        // Clones of the wire, as held by running engines, see the new key
+        receiving.accept(ClusterKey::new(1, "new"));
+        assert!(receiver.clone().decode(bytes.into()).is_ok());
+    }
+}
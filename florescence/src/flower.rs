@@ -1,18 +1,34 @@
 use crate::{
-    clock::Clock,
+    clock::{Clock, SystemClock},
     constants,
-    engine::{Engine, EngineEvent},
-    message::{PollinationMessage, Topic},
-    pollination::PollinationNode,
-    router::Router,
+    engine::{Engine, EngineChannels, EngineEvent, EngineRequest, EngineStatus, StatusReporter},
+    handle::{FlowerHandle, Replayed},
+    message::{PollinationMessage, Topic, Topical},
+    router::{Broadcast, Router},
+    seed::{ErasedSeedProvider, SeedProvider, StaticSeeds},
+    store::{ErasedStateStore, FlowerState, StateStore, Wal, WalEntry, WalError},
+};
+use pollination::{Compression, Defrag, Limits, PollinationNode, StartupMode};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
-use std::collections::HashMap;
 use thiserror::Error;
-use tokio::time::{MissedTickBehavior, interval};
+use tokio::{
+    sync::{
+        broadcast::error::{RecvError, TryRecvError},
+        mpsc::{Receiver, Sender, channel},
+        oneshot, watch,
+    },
+    task::JoinError,
+    time::{MissedTickBehavior, interval, sleep},
+};
 use uuid::Uuid;
 
-pub struct Flower<E: Engine, C, R> {
-    #[allow(unused)]
+pub struct Flower<E: Engine, C = SystemClock, R = Broadcast> {
     uuid: Uuid,
     engine: Option<E>,
     clock: C,
@@ -20,14 +36,66 @@ pub struct Flower<E: Engine, C, R> {
     nuclei: HashMap<Topic, NucleiState<E::Addr>>,
     //engine_request_tx: Sender<EngineRequest<E::Addr>>,
     //engine_event_rx: Receiver<EngineEvent>,
-    #[allow(unused)]
     own_addr: E::Addr,
+    adopt_observed_addr: bool,
+    seeds: Box<dyn ErasedSeedProvider<E::Addr>>,
+    startup_mode: StartupMode,
+    limits: Limits,
+    compression: Compression,
+    store: Option<Box<dyn ErasedStateStore<E::Addr>>>,
+    wal_path: Option<PathBuf>,
+    wal: Option<Wal>,
+    /// Every local operation in the WAL since its topic's last checkpoint.
+    /// Only checkpoints are part of the snapshot, so these are kept across
+    /// compactions.
+    local_ops: Vec<WalEntry>,
+    /// The last state each pollinator checkpointed, persisted along with the
+    /// nuclei.
+    checkpoints: HashMap<Topic, Vec<u8>>,
+    /// Replayed pollinator state no pollinator has claimed yet.
+    unclaimed: HashMap<Topic, Replayed>,
+    status: StatusReporter<E::Addr>,
+    joined: watch::Sender<bool>,
+    shutdown: Option<oneshot::Receiver<()>>,
+    flower_comm: Option<Receiver<FlowerRequest<E::Addr>>>,
+}
+
+/// How a `FlowerHandle` talks to the running `Flower`.
+pub(crate) type FlowerComm<A> = Sender<FlowerRequest<A>>;
+
+pub(crate) enum FlowerRequest<A> {
+    /// A copy of every nucleus.
+    Data(oneshot::Sender<HashMap<Topic, PollinationNode<A>>>),
+    /// Log an operation that originated on this node.
+    Record {
+        topic: Topic,
+        op: Vec<u8>,
+        reply: oneshot::Sender<Result<(), WalError>>,
+    },
+    /// Replace the local operations of a topic with the state they produced.
+    Checkpoint {
+        topic: Topic,
+        state: Vec<u8>,
+        reply: oneshot::Sender<Result<(), WalError>>,
+    },
+    /// Hand out the replayed pollinator state of a topic.
+    Claim {
+        topic: Topic,
+        reply: oneshot::Sender<Replayed>,
+    },
 }
 
 struct NucleiState<A> {
     nucleus: PollinationNode<A>,
-    #[allow(unused)]
     seed_list: Vec<A>,
+    /// Addresses already asked for an ID in the current join attempt.
+    tried: HashSet<String>,
+}
+
+impl<E: Engine> Flower<E> {
+    pub fn builder() -> FlowerBuilder<E> {
+        FlowerBuilder::new()
+    }
 }
 
 impl<E, C, R> Flower<E, C, R>
@@ -36,18 +104,39 @@ where
     C: Clock,
     R: Router,
 {
-    pub fn builder() -> FlowerBuilder<E, C, R> {
-        FlowerBuilder::new()
-    }
-
     pub async fn run(mut self) -> Result<(), FlowerError> {
-        let (_engine_request_tx, mut engine_event_rx) = self
+        self.restore().await?;
+        self.plant();
+        self.replay_wal().await?;
+
+        let EngineChannels {
+            requests: engine_request_tx,
+            events: mut engine_event_rx,
+            status: mut engine_status_rx,
+            shutdown: engine_shutdown,
+        } = self
             .engine
             .take()
             .ok_or(FlowerError::MissingEngine)?
             .run_background()
             .await
             .map_err(|err| FlowerError::EngineError(Box::new(err)))?;
+        let mut engine_shutdown = Some(engine_shutdown);
+        let mut engine_failure = None;
+        let mut engine_status_closed = false;
+        let (response_tx, mut response_rx) = channel(constants::RESPONSE_CHANNEL_SIZE);
+
+        let shutdown = self.shutdown.take();
+        let shutdown = async move {
+            match shutdown {
+                Some(rx) => {
+                    let _ = rx.await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(shutdown);
+        let mut flower_comm = self.flower_comm.take();
 
         let mut heartbeat = interval(constants::HEARTBEAT_TICK_TIME);
         heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -55,8 +144,56 @@ where
         let mut grim_reaper = interval(constants::RECLAIM_IDS_TICK_TIME);
         grim_reaper.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
+        let mut propagation = interval(constants::PROPAGATION_TIMEOUT);
+        propagation.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut defrag = interval(constants::DEFRAG_TICK_TIME);
+        defrag.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut compaction = interval(constants::COMPACTION_TICK_TIME);
+        compaction.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let mut anti_entropy = interval(constants::ANTI_ENTROPY_TICK_TIME);
+        anti_entropy.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut anti_entropy_round = 0;
+
+        let mut snapshot = interval(constants::SNAPSHOT_TICK_TIME);
+        snapshot.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        let join_retry = sleep(Duration::ZERO);
+        tokio::pin!(join_retry);
+        let mut join_backoff = constants::JOIN_BACKOFF_MIN;
+
         loop {
             tokio::select! {
+                _ = &mut shutdown => {
+                    info!("Shutting down.");
+                    if let Some(engine_shutdown) = engine_shutdown.take() {
+                        engine_shutdown.shutdown().await;
+                    }
+                    self.persist().await;
+                    break Ok(())
+                }
+
+                Some(req) = async {
+                    match flower_comm.as_mut() {
+                        Some(rx) => rx.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    self.handle_request(req).await;
+                }
+
+                status = engine_status_rx.recv(), if !engine_status_closed => {
+                    match status {
+                        Ok(status) => self.handle_engine_status(status, &mut engine_failure),
+                        Err(RecvError::Lagged(count)) => {
+                            warn!("Missed {count} engine status updates");
+                        }
+                        Err(RecvError::Closed) => engine_status_closed = true,
+                    }
+                }
+
                 _ = heartbeat.tick() => {
                     let mut msgs = vec![];
                     for (_topic, nuclei_state) in self.nuclei.iter_mut() {
@@ -67,27 +204,113 @@ where
                     }
 
                     for msg in msgs.drain(..) {
-                        self.send(msg).await;
+                        self.send(&engine_request_tx, &response_tx, msg);
                     }
                 }
 
                 _ = grim_reaper.tick() => {
                     let mut msgs = vec![];
                     for (_topic, nuclei_state) in self.nuclei.iter_mut() {
-                        if nuclei_state.nucleus.reap_souls() {
-                            if let Some(msg) = nuclei_state.nucleus.msg_heartbeat() {
-                                msgs.push(msg);
+                        if nuclei_state.nucleus.reap_souls()
+                            && let Some(msg) = nuclei_state.nucleus.msg_heartbeat()
+                        {
+                            msgs.push(msg);
+                        }
+                    }
+                    for msg in msgs.drain(..) {
+                        self.send(&engine_request_tx, &response_tx, msg);
+                    }
+                }
+
+                _ = propagation.tick() => {
+                    // Handing out an ID leaves a nucleus resting; let it
+                    // seed the next joiner once things have settled
+                    for (_topic, nuclei_state) in self.nuclei.iter_mut() {
+                        nuclei_state.nucleus.set_propagating();
+                    }
+                }
+
+                _ = defrag.tick() => {
+                    let mut msgs = vec![];
+                    for (_topic, nuclei_state) in self.nuclei.iter_mut() {
+                        match nuclei_state.nucleus.defragment() {
+                            Defrag::Idle => {}
+                            Defrag::Adopted => msgs.extend(nuclei_state.nucleus.msg_heartbeat()),
+                            Defrag::HandedOff { to, msg } => {
+                                request(&engine_request_tx, &response_tx, to, msg);
                             }
                         }
                     }
                     for msg in msgs.drain(..) {
-                        self.send(msg).await;
+                        self.send(&engine_request_tx, &response_tx, msg);
+                    }
+                }
+
+                _ = compaction.tick() => {
+                    let mut msgs = vec![];
+                    for (_topic, nuclei_state) in self.nuclei.iter_mut() {
+                        if nuclei_state.nucleus.compact() {
+                            let stats = nuclei_state.nucleus.timestamp_stats();
+                            debug!("Compacted timestamp: {stats:?}");
+                            // Let peers follow before they move on
+                            msgs.extend(nuclei_state.nucleus.msg_heartbeat());
+                        }
+                    }
+                    for msg in msgs.drain(..) {
+                        self.send(&engine_request_tx, &response_tx, msg);
+                    }
+                }
+
+                _ = anti_entropy.tick() => {
+                    // Probe a different peer each round
+                    anti_entropy_round += 1;
+                    for (_topic, nuclei_state) in self.nuclei.iter_mut() {
+                        let nucleus = &mut nuclei_state.nucleus;
+                        let count = nucleus.peers_alive().count();
+                        if count == 0 {
+                            continue;
+                        }
+                        let Some(addr) = nucleus
+                            .peers_alive()
+                            .nth(anti_entropy_round % count)
+                            .map(|(_, peer)| peer.addr.clone())
+                        else {
+                            continue;
+                        };
+                        if let Some(msg) = nucleus.msg_digest() {
+                            request(&engine_request_tx, &response_tx, addr, msg);
+                        }
+                        debug!("Anti-entropy: {:?}", nucleus.anti_entropy_stats());
+                    }
+                }
+
+                _ = snapshot.tick(), if self.store.is_some() => {
+                    self.persist().await;
+                }
+
+                () = &mut join_retry => {
+                    if self.join(&engine_request_tx, &response_tx).await {
+                        join_retry.as_mut().reset(self.clock.now() + join_backoff);
+                        join_backoff = (join_backoff * 2).min(constants::JOIN_BACKOFF_MAX);
+                    } else {
+                        // Keep watching in case a nucleus loses its ID again
+                        join_backoff = constants::JOIN_BACKOFF_MIN;
+                        join_retry.as_mut().reset(self.clock.now() + join_backoff);
+                    }
+                }
+
+                Some((addr, msg)) = response_rx.recv() => {
+                    if let Some((addr, reply)) = self.handle_response(addr, msg).await {
+                        request(&engine_request_tx, &response_tx, addr, reply);
                     }
+                    self.update_joined();
                 }
 
                 event  = engine_event_rx.recv() => {
                     if let Some(EngineEvent { tx, pollination_msg: msg }) = event {
-                        if let Some(nuclei_state) = self.nuclei.get_mut(&msg.topic()) {
+                        if self.log_applied(&msg).await
+                            && let Some(nuclei_state) = self.nuclei.get_mut(&msg.topic())
+                        {
                             // TODO: Handle cleanup of nucleus
                             let return_msg = match nuclei_state.nucleus.handle_message(msg) {
                                 Ok(res) => res.response,
@@ -104,59 +327,512 @@ where
                                 }
                             }
                         }
+                        self.update_joined();
                     } else {
-                        error!("Engine shut down.");
-                        break Ok(())
+                        // A failing engine reports why just before it goes away
+                        loop {
+                            match engine_status_rx.try_recv() {
+                                Ok(status) => self.handle_engine_status(status, &mut engine_failure),
+                                Err(TryRecvError::Lagged(_)) => continue,
+                                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                            }
+                        }
+
+                        self.persist().await;
+                        match engine_failure {
+                            Some(err) => {
+                                error!("Engine failed: {err}");
+                                break Err(FlowerError::EngineFailed(err))
+                            }
+                            None => {
+                                error!("Engine shut down.");
+                                break Ok(())
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_engine_status(
+        &mut self,
+        status: EngineStatus<E::Addr>,
+        engine_failure: &mut Option<Arc<dyn Error + Send + Sync>>,
+    ) {
+        match &status {
+            EngineStatus::Failed(err) => {
+                error!("Engine reported failure: {err}");
+                *engine_failure = Some(err.clone());
+            }
+            EngineStatus::ObservedAddr(addr) if self.adopt_observed_addr => {
+                info!(
+                    "Peers observe us at {addr}; gossiping it instead of {}",
+                    self.own_addr
+                );
+                self.own_addr = addr.clone();
+                for nuclei_state in self.nuclei.values_mut() {
+                    nuclei_state.nucleus.set_addr(addr.clone());
+                }
+            }
+            EngineStatus::ObservedAddr(addr) => {
+                warn!(
+                    "Peers observe us at {addr} but we advertise {}",
+                    self.own_addr
+                );
+            }
+            EngineStatus::Degraded(_)
+            | EngineStatus::PeerUnreachable { .. }
+            | EngineStatus::AdvertisedUnreachable { .. } => {
+                warn!("Engine status: {status}");
+            }
+            EngineStatus::Listening(_) | EngineStatus::Stopped => {
+                info!("Engine status: {status}");
+            }
+        }
+        self.status.report(status);
+    }
+
+    /// Pick up the state persisted by a previous run, if any, so we come back
+    /// with the same UUID and IDs.
+    async fn restore(&mut self) -> Result<(), FlowerError> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        let Some(state) = store.load().await.map_err(FlowerError::Store)? else {
+            return Ok(());
+        };
+
+        info!("Restoring {} nuclei for {}", state.nuclei.len(), state.uuid);
+        self.uuid = state.uuid;
+        for (topic, state) in state.pollinators {
+            let replayed = Replayed {
+                state: Some(state.clone()),
+                ops: vec![],
+            };
+            self.unclaimed.insert(topic.clone(), replayed);
+            self.checkpoints.insert(topic, state);
+        }
+        for (topic, snapshot) in state.nuclei {
+            let mut nucleus = PollinationNode::restore(snapshot);
+            // We may be reachable somewhere else after the restart
+            nucleus.set_addr(self.own_addr.clone());
+            nucleus.set_limits(self.limits);
+            nucleus.set_compression(self.compression);
+            self.nuclei.insert(
+                topic,
+                NucleiState {
+                    nucleus,
+                    seed_list: vec![],
+                    tried: HashSet::new(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn handle_request(&mut self, req: FlowerRequest<E::Addr>) {
+        match req {
+            FlowerRequest::Data(reply) => {
+                let nuclei = self
+                    .nuclei
+                    .iter()
+                    .map(|(topic, state)| (topic.clone(), state.nucleus.clone()))
+                    .collect();
+                let _ = reply.send(nuclei);
+            }
+            FlowerRequest::Record { topic, op, reply } => {
+                let _ = reply.send(self.log_local(topic, op).await);
+            }
+            FlowerRequest::Checkpoint {
+                topic,
+                state,
+                reply,
+            } => {
+                let _ = reply.send(self.checkpoint(topic, state).await);
+            }
+            FlowerRequest::Claim { topic, reply } => {
+                let _ = reply.send(self.unclaimed.remove(&topic).unwrap_or_default());
+            }
+        }
+    }
+
+    /// Log an operation that originated on this node, before its pollinator
+    /// applies it.
+    async fn log_local(&mut self, topic: Topic, op: Vec<u8>) -> Result<(), WalError> {
+        let Some(wal) = self.wal.as_mut() else {
+            return Ok(());
+        };
+        let entry = WalEntry::Local { topic, op };
+        wal.append(&entry).await?;
+        self.local_ops.push(entry);
+        Ok(())
+    }
+
+    /// Take `state` as what every local operation of `topic` logged so far
+    /// produced, so those operations no longer need keeping.
+    async fn checkpoint(&mut self, topic: Topic, state: Vec<u8>) -> Result<(), WalError> {
+        if let Some(wal) = self.wal.as_mut() {
+            let entry = WalEntry::Checkpoint {
+                topic: topic.clone(),
+                state: state.clone(),
+            };
+            wal.append(&entry).await?;
+        }
+        self.drop_local_ops(&topic);
+        self.checkpoints.insert(topic, state);
+        Ok(())
+    }
+
+    fn drop_local_ops(&mut self, topic: &Topic) {
+        self.local_ops
+            .retain(|entry| !matches!(entry, WalEntry::Local { topic: t, .. } if t == topic));
+    }
+
+    /// Start the membership nucleus, unless one was restored.
+    fn plant(&mut self) {
+        let (uuid, addr, mode) = (self.uuid, self.own_addr.clone(), self.startup_mode);
+        let (limits, compression) = (self.limits, self.compression);
+        self.nuclei.entry(Topic::membership()).or_insert_with(|| {
+            let mut nucleus = PollinationNode::new_with_mode(uuid, addr, mode);
+            nucleus.set_limits(limits);
+            nucleus.set_compression(compression);
+            NucleiState {
+                nucleus,
+                seed_list: vec![],
+                tried: HashSet::new(),
+            }
+        });
+    }
+
+    async fn persist(&mut self) {
+        let state = FlowerState {
+            uuid: self.uuid,
+            nuclei: self
+                .nuclei
+                .iter()
+                .map(|(topic, state)| (topic.clone(), state.nucleus.snapshot()))
+                .collect(),
+            pollinators: self
+                .checkpoints
+                .iter()
+                .map(|(topic, state)| (topic.clone(), state.clone()))
+                .collect(),
+        };
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        if let Err(err) = store.save(state).await {
+            error!("Failed to persist state: {err}");
+            return;
+        }
+
+        // Everything applied and checkpointed so far is in the snapshot now
+        if let Some(wal) = self.wal.as_mut()
+            && let Err(err) = wal.compact(&self.local_ops).await
+        {
+            error!("Failed to compact WAL: {err}");
+        }
+    }
+
+    /// Re-apply everything logged since the last snapshot, before rejoining
+    /// the cluster.
+    async fn replay_wal(&mut self) -> Result<(), FlowerError> {
+        let Some(path) = self.wal_path.take() else {
+            return Ok(());
+        };
+        let (wal, entries) = Wal::open(path).await?;
+        info!("Replaying {} WAL entries", entries.len());
+
+        for entry in entries {
+            match entry {
+                WalEntry::Applied { topic, msg } => {
+                    let Some(nuclei_state) = self.nuclei.get_mut(&topic) else {
+                        warn!("Dropping WAL entry for unknown topic");
+                        continue;
+                    };
+                    if let Err(err) = nuclei_state.nucleus.handle_message(msg) {
+                        warn!("Error replaying WAL entry: {err:?}");
                     }
                 }
+                WalEntry::Local { topic, op } => {
+                    let unclaimed = self.unclaimed.entry(topic.clone()).or_default();
+                    unclaimed.ops.push(op.clone());
+                    self.local_ops.push(WalEntry::Local { topic, op });
+                }
+                WalEntry::Checkpoint { topic, state } => {
+                    let replayed = Replayed {
+                        state: Some(state.clone()),
+                        ops: vec![],
+                    };
+                    self.unclaimed.insert(topic.clone(), replayed);
+                    self.drop_local_ops(&topic);
+                    self.checkpoints.insert(topic, state);
+                }
             }
         }
+
+        self.wal = Some(wal);
+        Ok(())
     }
 
-    async fn send(&mut self, _msg: PollinationMessage) {
-        todo!()
+    /// Log a message from a peer before applying it. Only messages that carry
+    /// a patch or hand us an ID change state and need logging.
+    ///
+    /// Returns whether the message may be applied; if it could not be logged
+    /// it is dropped and left for gossip to deliver again.
+    async fn log_applied(&mut self, msg: &PollinationMessage) -> bool {
+        use PollinationMessage::*;
+        if !matches!(
+            msg,
+            Update { .. } | RealitySkew { .. } | Seed { .. } | Handoff { .. } | Repair { .. }
+        ) {
+            return true;
+        }
+        let Some(wal) = self.wal.as_mut() else {
+            return true;
+        };
+
+        let entry = WalEntry::Applied {
+            topic: msg.topic(),
+            msg: msg.clone(),
+        };
+        match wal.append(&entry).await {
+            Ok(()) => true,
+            Err(err) => {
+                error!("Not applying message that could not be logged: {err}");
+                false
+            }
+        }
+    }
+
+    /// Ask the seeds for an ID on behalf of every nucleus that has none yet.
+    /// Seeds are refreshed from the provider first; on failure the last known
+    /// seeds are tried again.
+    ///
+    /// Returns whether any nucleus still had to join.
+    async fn join(
+        &mut self,
+        requests: &Sender<EngineRequest<E::Addr>>,
+        responses: &Sender<(E::Addr, PollinationMessage)>,
+    ) -> bool {
+        if self
+            .nuclei
+            .values()
+            .all(|state| state.nucleus.id().is_some())
+        {
+            return false;
+        }
+
+        match self.seeds.seeds().await {
+            Ok(seeds) => {
+                for nuclei_state in self.nuclei.values_mut() {
+                    nuclei_state.seed_list = seeds.clone();
+                }
+            }
+            Err(err) => warn!("Failed to refresh seeds: {err}"),
+        }
+
+        for nuclei_state in self.nuclei.values_mut() {
+            if nuclei_state.nucleus.id().is_some() {
+                continue;
+            }
+            let Some(msg) = nuclei_state.nucleus.msg_new_member() else {
+                continue;
+            };
+            debug!("Asking {} seeds to join", nuclei_state.seed_list.len());
+            nuclei_state.tried.clear();
+            for seed in &nuclei_state.seed_list {
+                nuclei_state.tried.insert(seed.to_string());
+                request(requests, responses, seed.clone(), msg.clone());
+            }
+        }
+        true
+    }
+
+    /// Handle a peer's reply to one of our requests, returning what to send
+    /// next and to whom.
+    ///
+    /// A `Seed` without an ID means the peer is not propagating, but its patch
+    /// tells us who else is in the cluster; in that case we ask the next
+    /// member we have not tried yet.
+    async fn handle_response(
+        &mut self,
+        addr: E::Addr,
+        msg: PollinationMessage,
+    ) -> Option<(E::Addr, PollinationMessage)> {
+        if !self.log_applied(&msg).await {
+            return None;
+        }
+        let declined = matches!(msg, PollinationMessage::Seed { new_id: None, .. });
+        let nuclei_state = self.nuclei.get_mut(&msg.topic())?;
+        let reply = match nuclei_state.nucleus.handle_message(msg) {
+            Ok(res) => res.response,
+            Err(err) => {
+                error!("Error handling response: {err:?}");
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            return Some((addr, reply));
+        }
+
+        if !declined || nuclei_state.nucleus.id().is_some() {
+            return None;
+        }
+        let next = nuclei_state
+            .nucleus
+            .peers_alive()
+            .map(|(_, info)| info.addr.clone())
+            .find(|peer| !nuclei_state.tried.contains(&peer.to_string()))?;
+        debug!("{addr} is not propagating; asking {next} instead");
+        nuclei_state.tried.insert(next.to_string());
+        Some((next, nuclei_state.nucleus.msg_new_member()?))
+    }
+
+    fn update_joined(&self) {
+        let joined = self
+            .nuclei
+            .values()
+            .all(|state| state.nucleus.id().is_some());
+        self.joined.send_if_modified(|prev| {
+            let changed = *prev != joined;
+            *prev = joined;
+            changed
+        });
+    }
+
+    /// Gossip `msg` to the live peers of its topic the router picks.
+    fn send(
+        &mut self,
+        requests: &Sender<EngineRequest<E::Addr>>,
+        responses: &Sender<(E::Addr, PollinationMessage)>,
+        msg: PollinationMessage,
+    ) {
+        let Some(nuclei_state) = self.nuclei.get(&msg.topic()) else {
+            return;
+        };
+        let peers = nuclei_state
+            .nucleus
+            .peers_alive()
+            .map(|(_, info)| &info.addr)
+            .filter(|addr| addr.to_string() != self.own_addr.to_string());
+        for addr in self.router.route(peers) {
+            request(requests, responses, addr.clone(), msg.clone());
+        }
     }
 }
 
-pub struct FlowerBuilder<E: Engine, C, R> {
+/// Send `msg` to `addr` in the background, feeding whatever the peer replies
+/// with back into the `Flower` through `responses`.
+fn request<A: Clone + Send + 'static>(
+    requests: &Sender<EngineRequest<A>>,
+    responses: &Sender<(A, PollinationMessage)>,
+    addr: A,
+    msg: PollinationMessage,
+) {
+    let requests = requests.clone();
+    let responses = responses.clone();
+    tokio::spawn(async move {
+        let (tx, mut rx) = channel(1);
+        let req = EngineRequest {
+            pollination_msg: msg,
+            addr: addr.clone(),
+            tx,
+        };
+        if requests.send(req).await.is_err() {
+            return;
+        }
+        if let Some(res) = rx.recv().await {
+            let _ = responses.send((addr, res)).await;
+        }
+    });
+}
+
+pub struct FlowerBuilder<E: Engine, C = SystemClock, R = Broadcast> {
     engine: Option<E>,
-    clock: Option<C>,
-    router: Option<R>,
+    clock: C,
+    router: R,
     uuid: Option<Uuid>,
     own_addr: Option<E::Addr>,
-    seed_list: Vec<E::Addr>,
+    adopt_observed_addr: bool,
+    seeds: Option<Box<dyn ErasedSeedProvider<E::Addr>>>,
+    startup_mode: Option<StartupMode>,
+    limits: Limits,
+    compression: Compression,
+    store: Option<Box<dyn ErasedStateStore<E::Addr>>>,
+    wal_path: Option<PathBuf>,
 }
 
-impl<E, C, R> FlowerBuilder<E, C, R>
-where
-    E: Engine,
-    C: Clock,
-    R: Router,
-{
+impl<E: Engine> FlowerBuilder<E> {
     pub fn new() -> Self {
         Self {
             engine: None,
-            clock: None,
-            router: None,
+            clock: SystemClock,
+            router: Broadcast,
             uuid: None,
             own_addr: None,
-            seed_list: vec![],
+            adopt_observed_addr: false,
+            seeds: None,
+            startup_mode: None,
+            limits: Limits::default(),
+            compression: Compression::None,
+            store: None,
+            wal_path: None,
         }
     }
+}
+
+impl<E: Engine> Default for FlowerBuilder<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl<E, C, R> FlowerBuilder<E, C, R>
+where
+    E: Engine,
+    C: Clock,
+    R: Router,
+{
     pub fn engine(mut self, engine: E) -> Self {
         self.engine = Some(engine);
         self
     }
 
-    pub fn clock(mut self, clock: C) -> Self {
-        self.clock = Some(clock);
-        self
+    pub fn clock<C2: Clock>(self, clock: C2) -> FlowerBuilder<E, C2, R> {
+        FlowerBuilder {
+            engine: self.engine,
+            clock,
+            router: self.router,
+            uuid: self.uuid,
+            own_addr: self.own_addr,
+            adopt_observed_addr: self.adopt_observed_addr,
+            seeds: self.seeds,
+            startup_mode: self.startup_mode,
+            limits: self.limits,
+            compression: self.compression,
+            store: self.store,
+            wal_path: self.wal_path,
+        }
     }
 
-    pub fn router(mut self, router: R) -> Self {
-        self.router = Some(router);
-        self
+    /// Pick which peers gossip is sent to; defaults to all of them.
+    pub fn router<R2: Router>(self, router: R2) -> FlowerBuilder<E, C, R2> {
+        FlowerBuilder {
+            engine: self.engine,
+            clock: self.clock,
+            router,
+            uuid: self.uuid,
+            own_addr: self.own_addr,
+            adopt_observed_addr: self.adopt_observed_addr,
+            seeds: self.seeds,
+            startup_mode: self.startup_mode,
+            limits: self.limits,
+            compression: self.compression,
+            store: self.store,
+            wal_path: self.wal_path,
+        }
     }
 
     pub fn uuid(mut self, uuid: Uuid) -> Self {
@@ -169,35 +845,391 @@ where
         self
     }
 
-    pub fn seed_list(mut self, seed_list: Vec<E::Addr>) -> Self {
-        self.seed_list = seed_list;
+    /// Replace our gossiped address with the one peers report seeing us
+    /// at. Useful when the engine has no advertise address configured.
+    pub fn adopt_observed_addr(mut self, adopt: bool) -> Self {
+        self.adopt_observed_addr = adopt;
+        self
+    }
+
+    /// Join the cluster through these peers. An empty list configures no
+    /// seeds at all, so the node bootstraps by default.
+    pub fn seed_list(self, seed_list: Vec<E::Addr>) -> Self {
+        if seed_list.is_empty() {
+            return self;
+        }
+        self.seed_provider(StaticSeeds::new(seed_list))
+    }
+
+    /// Whether new nuclei start out owning the whole ID space or wait to be
+    /// seeded. Only the first node of a cluster should bootstrap; everything
+    /// else should join. Defaults to `Join` when seeds are configured and
+    /// `Bootstrap` otherwise.
+    pub fn startup_mode(mut self, startup_mode: StartupMode) -> Self {
+        self.startup_mode = Some(startup_mode);
+        self
+    }
+
+    /// Bound what incoming messages may contain. Pass the same limits to the
+    /// engine, which enforces the message size before anything is decoded.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Compress the patches we send. Peers that have not negotiated the
+    /// codec are sent them uncompressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Persist state so a restarted node comes back with the same UUID and
+    /// IDs instead of joining as a stranger.
+    pub fn state_store<S: StateStore<E::Addr>>(mut self, store: S) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// Log every applied change to a write-ahead log so nothing between two
+    /// snapshots is lost. The log is compacted into the `state_store`
+    /// whenever a snapshot is taken, so one must be set too.
+    pub fn wal(mut self, path: impl Into<PathBuf>) -> Self {
+        self.wal_path = Some(path.into());
+        self
+    }
+
+    /// Discover seeds dynamically, e.g. from DNS or a file. The provider is
+    /// polled again whenever joining the cluster has to be retried.
+    pub fn seed_provider<P: SeedProvider<E::Addr>>(mut self, provider: P) -> Self {
+        self.seeds = Some(Box::new(provider));
         self
     }
 
     pub fn build(self) -> Result<Flower<E, C, R>, FlowerError> {
+        if self.wal_path.is_some() && self.store.is_none() {
+            return Err(FlowerError::WalWithoutStore);
+        }
         let uuid = self.uuid.unwrap_or(Uuid::new_v4());
         let nuclei = HashMap::new();
-        let own_addr = self.own_addr.ok_or(FlowerError::MissingOwnAddr)?;
+        let advertise_addr = self.engine.as_ref().and_then(|e| e.advertise_addr());
+        let own_addr = match (self.own_addr, advertise_addr) {
+            (Some(own_addr), Some(advertised))
+                if own_addr.to_string() != advertised.to_string() =>
+            {
+                return Err(FlowerError::AddrMismatch {
+                    own_addr: own_addr.to_string(),
+                    advertised: advertised.to_string(),
+                });
+            }
+            (Some(own_addr), _) | (None, Some(own_addr)) => own_addr,
+            (None, None) => return Err(FlowerError::MissingOwnAddr),
+        };
+
+        let startup_mode = self.startup_mode.unwrap_or(match self.seeds {
+            Some(_) => StartupMode::Join,
+            None => StartupMode::Bootstrap,
+        });
 
         Ok(Flower {
             uuid,
             nuclei,
             own_addr,
+            adopt_observed_addr: self.adopt_observed_addr,
+            seeds: self
+                .seeds
+                .unwrap_or_else(|| Box::new(StaticSeeds::new(vec![]))),
+            startup_mode,
+            limits: self.limits,
+            compression: self.compression,
+            store: self.store,
+            wal_path: self.wal_path,
+            wal: None,
+            local_ops: vec![],
+            checkpoints: HashMap::new(),
+            unclaimed: HashMap::new(),
             engine: self.engine,
-            clock: self.clock.unwrap_or_else(|| todo!()),
-            router: self.router.unwrap_or_else(|| todo!()),
+            clock: self.clock,
+            router: self.router,
+            status: StatusReporter::channel().0,
+            joined: watch::channel(false).0,
+            shutdown: None,
+            flower_comm: None,
         })
     }
+
+    /// Build the `Flower` and run it in the background.
+    pub fn start(self) -> Result<FlowerHandle<E::Addr>, FlowerError>
+    where
+        E: Send,
+    {
+        let mut flower = self.build()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        flower.shutdown = Some(shutdown_rx);
+        let (comm_tx, comm_rx) = channel(constants::MPSC_CHANNEL_SIZE);
+        flower.flower_comm = Some(comm_rx);
+        let status = flower.status.clone();
+        let joined = flower.joined.subscribe();
+
+        let handle = tokio::spawn(flower.run());
+        Ok(FlowerHandle::new(
+            comm_tx,
+            status,
+            joined,
+            shutdown_tx,
+            handle,
+        ))
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum FlowerError {
-    #[error("No `own_addr` set")]
+    #[error("No `own_addr` set and the engine advertises none")]
     MissingOwnAddr,
 
+    #[error("`own_addr` {own_addr} does not match the engine's advertise address {advertised}")]
+    AddrMismatch {
+        own_addr: String,
+        advertised: String,
+    },
+
     #[error("No `engine` set")]
     MissingEngine,
 
+    #[error("A `wal` needs a `state_store` to compact into")]
+    WalWithoutStore,
+
     #[error("Engine error")]
-    EngineError(#[from] Box<dyn std::error::Error>),
+    EngineError(#[from] Box<dyn Error + Send + Sync>),
+
+    #[error("Engine failed: {0}")]
+    EngineFailed(Arc<dyn Error + Send + Sync>),
+
+    #[error("State store error: {0}")]
+    Store(Box<dyn Error + Send + Sync>),
+
+    #[error("WAL error: {0}")]
+    Wal(#[from] WalError),
+
+    #[error("Flower stopped before joining the cluster")]
+    NotJoined,
+
+    #[error("Flower is not running")]
+    Stopped,
+
+    #[error("Flower task failed: {0}")]
+    Join(#[from] JoinError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::mem::{MemEngine, MemNetwork},
+        store::FileStore,
+    };
+    use treeclocks::IdTree;
+
+    async fn membership_id(mode: StartupMode) -> Option<IdTree> {
+        let handle = Flower::builder()
+            .engine(MemEngine::new(MemNetwork::new(), 0))
+            .startup_mode(mode)
+            .start()
+            .unwrap();
+        let nuclei = handle.data().await.unwrap();
+        handle.shutdown().await.unwrap();
+        nuclei[&Topic::membership()].id().cloned()
+    }
+
+    fn temp_path(ext: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("florescence-{}.{ext}", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_replayed_local_ops_claimed_once() {
+        let (wal, state) = (temp_path("wal"), temp_path("state"));
+        let start = || {
+            Flower::builder()
+                .engine(MemEngine::new(MemNetwork::new(), 0))
+                .state_store(FileStore::new(&state))
+                .wal(&wal)
+                .start()
+                .unwrap()
+        };
+        let topic = Topic::new("counter".to_string());
+
+        let handle = start();
+        handle.record(topic.clone(), vec![1]).await.unwrap();
+        handle.record(topic.clone(), vec![2]).await.unwrap();
+        handle.shutdown().await.unwrap();
+
+        let handle = start();
+        let replayed = handle.claim(topic.clone()).await.unwrap();
+        assert_eq!(replayed.ops, vec![vec![1], vec![2]]);
+        assert_eq!(handle.claim(topic).await.unwrap(), Replayed::default());
+        handle.shutdown().await.unwrap();
+
+        tokio::fs::remove_file(&wal).await.unwrap();
+        tokio::fs::remove_file(&state).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_replaces_local_ops() {
+        let (wal, state) = (temp_path("wal"), temp_path("state"));
+        let start = || {
+            Flower::builder()
+                .engine(MemEngine::new(MemNetwork::new(), 0))
+                .state_store(FileStore::new(&state))
+                .wal(&wal)
+                .start()
+                .unwrap()
+        };
+        let topic = Topic::new("counter".to_string());
+
+        let handle = start();
+        handle.record(topic.clone(), vec![1]).await.unwrap();
+        handle.record(topic.clone(), vec![2]).await.unwrap();
+        handle.checkpoint(topic.clone(), vec![3]).await.unwrap();
+        handle.record(topic.clone(), vec![4]).await.unwrap();
+        handle.shutdown().await.unwrap();
+
+        // The checkpoint went to the store; only what came after it is kept
+        let (_, entries) = Wal::open(&wal).await.unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let handle = start();
+        let replayed = handle.claim(topic).await.unwrap();
+        assert_eq!(replayed.state, Some(vec![3]));
+        assert_eq!(replayed.ops, vec![vec![4]]);
+        handle.shutdown().await.unwrap();
+
+        tokio::fs::remove_file(&wal).await.unwrap();
+        tokio::fs::remove_file(&state).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_persisted() {
+        let state = temp_path("state");
+        let start = || {
+            Flower::builder()
+                .engine(MemEngine::new(MemNetwork::new(), 0))
+                .state_store(FileStore::new(&state))
+                .start()
+                .unwrap()
+        };
+        let topic = Topic::new("counter".to_string());
+
+        let handle = start();
+        handle.checkpoint(topic.clone(), vec![3]).await.unwrap();
+        handle.shutdown().await.unwrap();
+
+        let handle = start();
+        let replayed = handle.claim(topic).await.unwrap();
+        assert_eq!(replayed.state, Some(vec![3]));
+        assert!(replayed.ops.is_empty());
+        handle.shutdown().await.unwrap();
+
+        tokio::fs::remove_file(&state).await.unwrap();
+    }
+
+    #[test]
+    fn test_wal_needs_store() {
+        let res = Flower::builder()
+            .engine(MemEngine::new(MemNetwork::new(), 0))
+            .wal(temp_path("wal"))
+            .build();
+        assert!(matches!(res, Err(FlowerError::WalWithoutStore)));
+    }
+
+    #[tokio::test]
+    async fn test_limits_reach_nucleus() {
+        let limits = Limits {
+            max_tree_depth: 32,
+            ..Limits::default()
+        };
+        let handle = Flower::builder()
+            .engine(MemEngine::new(MemNetwork::new(), 0))
+            .limits(limits)
+            .start()
+            .unwrap();
+        let nuclei = handle.data().await.unwrap();
+        handle.shutdown().await.unwrap();
+        assert_eq!(*nuclei[&Topic::membership()].limits(), limits);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_compression_reaches_nucleus() {
+        let handle = Flower::builder()
+            .engine(MemEngine::new(MemNetwork::new(), 0))
+            .compression(Compression::Zstd)
+            .start()
+            .unwrap();
+        let nuclei = handle.data().await.unwrap();
+        handle.shutdown().await.unwrap();
+        assert_eq!(
+            nuclei[&Topic::membership()].compression(),
+            Compression::Zstd
+        );
+    }
+
+    #[tokio::test]
+    async fn test_startup_mode() {
+        assert_eq!(
+            membership_id(StartupMode::Bootstrap).await,
+            Some(IdTree::One)
+        );
+        assert_eq!(membership_id(StartupMode::Join).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_seeded_flowers_join_one_cluster() {
+        let network = MemNetwork::new();
+        let mut seed_list = vec![];
+        let mut handles = vec![];
+        for addr in 0..3 {
+            let handle = Flower::builder()
+                .engine(MemEngine::new(network.clone(), addr))
+                .seed_list(seed_list.clone())
+                .start()
+                .unwrap();
+            seed_list.push(addr);
+            handles.push(handle);
+        }
+        for handle in &handles {
+            tokio::time::timeout(Duration::from_secs(30), handle.joined())
+                .await
+                .expect("flower did not join")
+                .unwrap();
+        }
+        for handle in handles {
+            handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_seeds_default_to_join() {
+        let builder = || Flower::builder().engine(MemEngine::new(MemNetwork::new(), 0));
+        assert_eq!(
+            builder().build().unwrap().startup_mode,
+            StartupMode::Bootstrap
+        );
+        assert_eq!(
+            builder().seed_list(vec![]).build().unwrap().startup_mode,
+            StartupMode::Bootstrap
+        );
+        assert_eq!(
+            builder().seed_list(vec![1]).build().unwrap().startup_mode,
+            StartupMode::Join
+        );
+        assert_eq!(
+            builder()
+                .seed_list(vec![1])
+                .startup_mode(StartupMode::Bootstrap)
+                .build()
+                .unwrap()
+                .startup_mode,
+            StartupMode::Bootstrap
+        );
+    }
 }
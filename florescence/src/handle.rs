@@ -1,12 +1,33 @@
-use crate::{ds::WalkieTalkie, nucleus::Nucleus};
-use tokio::task::JoinHandle;
+use crate::{
+    engine::{EngineStatus, StatusReporter},
+    flower::{FlowerComm, FlowerError, FlowerRequest},
+    message::Topic,
+};
+use pollination::PollinationNode;
+use std::collections::HashMap;
+use tokio::{
+    sync::{broadcast, oneshot, watch},
+    task::JoinHandle,
+};
+
+/// Pollinator state restored from the state store and the WAL.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Replayed {
+    /// The last checkpointed state, if any.
+    pub state: Option<Vec<u8>>,
+    /// Operations recorded after `state`, oldest first.
+    pub ops: Vec<Vec<u8>>,
+}
 
 pub struct FlowerHandle<A> {
-    flower_comm: WalkieTalkie<(), Nucleus<A>>,
-    handle: JoinHandle<anyhow::Result<()>>,
+    flower_comm: FlowerComm<A>,
+    status: StatusReporter<A>,
+    joined: watch::Receiver<bool>,
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<Result<(), FlowerError>>,
 }
 
-impl<A> FlowerHandle<A> {
+impl<A: Clone> FlowerHandle<A> {
     /*
     pub fn pollinator<P: Pollinator + 'static>(&self, interval: Duration) -> P {
         let (pollinator, inner) = P::from_conn(EngineConnection {});
@@ -15,21 +36,96 @@ impl<A> FlowerHandle<A> {
     */
 
     pub(crate) fn new(
-        flower_comm: WalkieTalkie<(), Nucleus<A>>,
-        handle: JoinHandle<anyhow::Result<()>>,
+        flower_comm: FlowerComm<A>,
+        status: StatusReporter<A>,
+        joined: watch::Receiver<bool>,
+        shutdown: oneshot::Sender<()>,
+        handle: JoinHandle<Result<(), FlowerError>>,
     ) -> Self {
         Self {
             flower_comm,
+            status,
+            joined,
+            shutdown,
             handle,
         }
     }
 
-    pub async fn data(&mut self) -> Option<Nucleus<A>> {
-        self.flower_comm.send_recv(()).await
+    /// A copy of every nucleus of the running `Flower`, or `None` once it has
+    /// stopped.
+    pub async fn data(&self) -> Option<HashMap<Topic, PollinationNode<A>>> {
+        let (tx, rx) = oneshot::channel();
+        self.flower_comm.send(FlowerRequest::Data(tx)).await.ok()?;
+        rx.await.ok()
+    }
+
+    /// Durably log an operation that originated on this node. Pollinators
+    /// call this before applying the operation, so it survives a crash.
+    pub async fn record(&self, topic: Topic, op: Vec<u8>) -> Result<(), FlowerError> {
+        let (reply, rx) = oneshot::channel();
+        let req = FlowerRequest::Record { topic, op, reply };
+        self.flower_comm
+            .send(req)
+            .await
+            .map_err(|_| FlowerError::Stopped)?;
+        Ok(rx.await.map_err(|_| FlowerError::Stopped)??)
+    }
+
+    /// Hand over the encoded state of the pollinator of `topic`, covering
+    /// every operation it recorded so far. The state is persisted through the
+    /// state store and those operations are dropped from the WAL, so
+    /// pollinators should checkpoint regularly.
+    pub async fn checkpoint(&self, topic: Topic, state: Vec<u8>) -> Result<(), FlowerError> {
+        let (reply, rx) = oneshot::channel();
+        let req = FlowerRequest::Checkpoint {
+            topic,
+            state,
+            reply,
+        };
+        self.flower_comm
+            .send(req)
+            .await
+            .map_err(|_| FlowerError::Stopped)?;
+        Ok(rx.await.map_err(|_| FlowerError::Stopped)??)
+    }
+
+    /// The state of `topic` restored on start, for its pollinator to pick up
+    /// again. It is handed out only once.
+    pub async fn claim(&self, topic: Topic) -> Result<Replayed, FlowerError> {
+        let (reply, rx) = oneshot::channel();
+        let req = FlowerRequest::Claim { topic, reply };
+        self.flower_comm
+            .send(req)
+            .await
+            .map_err(|_| FlowerError::Stopped)?;
+        rx.await.map_err(|_| FlowerError::Stopped)
+    }
+
+    /// Subscribe to health updates from the underlying engine.
+    pub fn status(&self) -> broadcast::Receiver<EngineStatus<A>> {
+        self.status.subscribe()
+    }
+
+    /// Wait until the `Flower` has been given an ID by the cluster. Services
+    /// should not start serving before this resolves.
+    pub async fn joined(&self) -> Result<(), FlowerError> {
+        let mut joined = self.joined.clone();
+        joined
+            .wait_for(|joined| *joined)
+            .await
+            .map(|_| ())
+            .map_err(|_| FlowerError::NotJoined)
+    }
+
+    /// Gracefully stop the `Flower`, waiting for the engine to drain in-flight
+    /// requests.
+    pub async fn shutdown(self) -> Result<(), FlowerError> {
+        let _ = self.shutdown.send(());
+        self.handle.await?
     }
 
-    pub async fn runtime(self) -> anyhow::Result<()> {
-        self.handle.await??;
-        Ok(())
+    /// Wait for the `Flower` to stop, returning why it stopped.
+    pub async fn runtime(self) -> Result<(), FlowerError> {
+        self.handle.await?
     }
 }
@@ -0,0 +1,22 @@
+#[macro_use]
+extern crate tracing;
+
+mod clock;
+mod connection;
+mod constants;
+pub mod engine;
+mod flower;
+mod handle;
+mod message;
+#[allow(dead_code)]
+mod pollinator;
+mod router;
+pub mod seed;
+mod serialization;
+pub mod store;
+
+pub use clock::{Clock, SystemClock};
+pub use flower::{Flower, FlowerBuilder, FlowerError};
+pub use handle::{FlowerHandle, Replayed};
+pub use message::Topic;
+pub use router::{Broadcast, Router};
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub use pollination::PollinationMessage;
+
+/// Name of the channel a nucleus gossips on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Topic(String);
+
+impl Topic {
+    pub fn new(name: String) -> Self {
+        Self(name)
+    }
+
+    /// The topic cluster membership is gossiped on.
+    pub fn membership() -> Self {
+        Self::new("membership".to_string())
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Which topic a message belongs to.
+pub(crate) trait Topical {
+    fn topic(&self) -> Topic;
+}
+
+impl Topical for PollinationMessage {
+    // Messages do not name their topic yet; membership is all there is
+    fn topic(&self) -> Topic {
+        Topic::membership()
+    }
+}
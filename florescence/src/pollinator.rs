@@ -1,8 +1,9 @@
-use crate::reality_token::RealityToken;
+use pollination::RealityToken;
 use treeclocks::{EventTree, ItcMap};
 
 mod identity_map;
 
+#[allow(unused_imports)]
 pub use identity_map::IdentityMap;
 
 pub struct PeerInfo {}
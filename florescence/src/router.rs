@@ -0,0 +1,19 @@
+/// Picks which of the known peers a gossiped message goes to.
+pub trait Router: Send + 'static {
+    fn route<'a, A>(&mut self, peers: impl Iterator<Item = &'a A>) -> Vec<&'a A>
+    where
+        A: 'a;
+}
+
+/// Sends every message to every peer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Broadcast;
+
+impl Router for Broadcast {
+    fn route<'a, A>(&mut self, peers: impl Iterator<Item = &'a A>) -> Vec<&'a A>
+    where
+        A: 'a,
+    {
+        peers.collect()
+    }
+}
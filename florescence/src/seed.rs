@@ -0,0 +1,53 @@
+use std::{convert::Infallible, error::Error, future::Future, pin::Pin};
+
+mod dns;
+mod file;
+
+pub use dns::{DnsRecord, DnsSeeds};
+pub use file::{FileSeeds, FileSeedsError};
+
+/// Where a `Flower` finds peers to join the cluster through.
+///
+/// `seeds` is called again every time a node without an ID retries joining,
+/// so providers can pick up changes to the cluster in between.
+pub trait SeedProvider<A>: Send + 'static {
+    type Error: Error + Send + Sync + 'static;
+
+    fn seeds(&mut self) -> impl Future<Output = Result<Vec<A>, Self::Error>> + Send;
+}
+
+/// A fixed list of seeds.
+#[derive(Clone, Debug)]
+pub struct StaticSeeds<A>(Vec<A>);
+
+impl<A> StaticSeeds<A> {
+    pub fn new(seeds: Vec<A>) -> Self {
+        Self(seeds)
+    }
+}
+
+impl<A: Clone + Send + 'static> SeedProvider<A> for StaticSeeds<A> {
+    type Error = Infallible;
+
+    async fn seeds(&mut self) -> Result<Vec<A>, Self::Error> {
+        Ok(self.0.clone())
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Object-safe wrapper around `SeedProvider` so the `Flower` does not need
+/// another type parameter.
+pub(crate) trait ErasedSeedProvider<A>: Send {
+    fn seeds(&mut self) -> BoxFuture<'_, Result<Vec<A>, Box<dyn Error + Send + Sync>>>;
+}
+
+impl<A, P: SeedProvider<A>> ErasedSeedProvider<A> for P {
+    fn seeds(&mut self) -> BoxFuture<'_, Result<Vec<A>, Box<dyn Error + Send + Sync>>> {
+        Box::pin(async move {
+            SeedProvider::seeds(self)
+                .await
+                .map_err(|err| Box::new(err) as Box<dyn Error + Send + Sync>)
+        })
+    }
+}
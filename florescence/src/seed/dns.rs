@@ -0,0 +1,194 @@
+use hickory_resolver::{
+    TokioAsyncResolver,
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::ResolveError,
+};
+use std::net::SocketAddr;
+
+use super::SeedProvider;
+
+/// Which records to look seeds up from.
+#[derive(Clone, Copy, Debug)]
+pub enum DnsRecord {
+    /// SRV records, which carry the port of every seed.
+    Srv,
+    /// A/AAAA records; every seed listens on the same `port`.
+    A { port: u16 },
+}
+
+/// Seeds resolved from DNS, e.g. a headless Kubernetes service.
+pub struct DnsSeeds<A> {
+    name: String,
+    record: DnsRecord,
+    resolver: TokioAsyncResolver,
+    to_addr: Box<dyn Fn(SocketAddr) -> A + Send + Sync>,
+}
+
+impl<A> DnsSeeds<A> {
+    /// Resolve `name` using the system resolver configuration, turning every
+    /// resolved socket address into an engine address with `to_addr`.
+    pub fn new(
+        name: impl Into<String>,
+        record: DnsRecord,
+        to_addr: impl Fn(SocketAddr) -> A + Send + Sync + 'static,
+    ) -> Result<Self, ResolveError> {
+        Ok(Self {
+            name: name.into(),
+            record,
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+            to_addr: Box::new(to_addr),
+        })
+    }
+
+    pub fn with_resolver(mut self, config: ResolverConfig, opts: ResolverOpts) -> Self {
+        self.resolver = TokioAsyncResolver::tokio(config, opts);
+        self
+    }
+
+    /// Query a single nameserver over plain UDP/TCP, e.g. a local test server.
+    pub fn with_nameserver(self, nameserver: SocketAddr) -> Self {
+        let group =
+            NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], group);
+        self.with_resolver(config, ResolverOpts::default())
+    }
+}
+
+impl<A: Send + 'static> SeedProvider<A> for DnsSeeds<A> {
+    type Error = ResolveError;
+
+    async fn seeds(&mut self) -> Result<Vec<A>, Self::Error> {
+        let mut seeds = vec![];
+        match self.record {
+            DnsRecord::Srv => {
+                let lookup = self.resolver.srv_lookup(self.name.as_str()).await?;
+                for srv in lookup.iter() {
+                    // One stale target should not hide the others
+                    let ips = match self.resolver.lookup_ip(srv.target().clone()).await {
+                        Ok(ips) => ips,
+                        Err(err) => {
+                            warn!("Failed to resolve seed {}: {err}", srv.target());
+                            continue;
+                        }
+                    };
+                    seeds.extend(
+                        ips.iter()
+                            .map(|ip| (self.to_addr)(SocketAddr::new(ip, srv.port()))),
+                    );
+                }
+            }
+            DnsRecord::A { port } => {
+                let ips = self.resolver.lookup_ip(self.name.as_str()).await?;
+                seeds.extend(
+                    ips.iter()
+                        .map(|ip| (self.to_addr)(SocketAddr::new(ip, port))),
+                );
+            }
+        }
+
+        debug!("Resolved {} seeds from {}", seeds.len(), self.name);
+        Ok(seeds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{
+            Name, RData, Record,
+            rdata::{A, SRV},
+        },
+        serialize::binary::{BinDecodable, BinEncodable},
+    };
+    use std::net::Ipv4Addr;
+    use tokio::net::UdpSocket;
+
+    fn name(s: &str) -> Name {
+        Name::from_ascii(s).unwrap()
+    }
+
+    /// Answers queries from a fixed set of records, and with NXDOMAIN for
+    /// everything else.
+    async fn stub_resolver(records: Vec<Record>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let query = Message::from_bytes(&buf[..len]).unwrap();
+                let mut res = Message::new();
+                res.set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(query.recursion_desired())
+                    .set_recursion_available(true)
+                    .add_queries(query.queries().to_vec());
+
+                let q = &query.queries()[0];
+                let answers: Vec<_> = records
+                    .iter()
+                    .filter(|r| r.name() == q.name() && r.record_type() == q.query_type())
+                    .cloned()
+                    .collect();
+                if answers.is_empty() {
+                    res.set_response_code(ResponseCode::NXDomain);
+                }
+                res.add_answers(answers);
+                let _ = socket.send_to(&res.to_bytes().unwrap(), from).await;
+            }
+        });
+        addr
+    }
+
+    fn a(host: &str, ip: [u8; 4]) -> Record {
+        Record::from_rdata(name(host), 60, RData::A(A(Ipv4Addr::from(ip))))
+    }
+
+    fn srv(service: &str, port: u16, target: &str) -> Record {
+        Record::from_rdata(
+            name(service),
+            60,
+            RData::SRV(SRV::new(0, 0, port, name(target))),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_a_records() {
+        let nameserver = stub_resolver(vec![
+            a("seeds.test.", [10, 0, 0, 1]),
+            a("seeds.test.", [10, 0, 0, 2]),
+        ])
+        .await;
+        let mut seeds = DnsSeeds::new("seeds.test.", DnsRecord::A { port: 8000 }, |addr| addr)
+            .unwrap()
+            .with_nameserver(nameserver);
+
+        let mut found = seeds.seeds().await.unwrap();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                "10.0.0.1:8000".parse().unwrap(),
+                "10.0.0.2:8000".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_srv_records_skip_stale_targets() {
+        let nameserver = stub_resolver(vec![
+            srv("_gossip._tcp.seeds.test.", 7000, "a.seeds.test."),
+            srv("_gossip._tcp.seeds.test.", 7001, "gone.seeds.test."),
+            a("a.seeds.test.", [10, 0, 0, 3]),
+        ])
+        .await;
+        let mut seeds = DnsSeeds::new("_gossip._tcp.seeds.test.", DnsRecord::Srv, |addr| {
+            addr.to_string()
+        })
+        .unwrap()
+        .with_nameserver(nameserver);
+
+        assert_eq!(seeds.seeds().await.unwrap(), vec!["10.0.0.3:7000"]);
+    }
+}
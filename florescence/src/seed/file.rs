@@ -0,0 +1,111 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+use thiserror::Error;
+
+use super::SeedProvider;
+
+/// Seeds read from a file with one address per line. Blank lines and lines
+/// starting with `#` are ignored.
+///
+/// The file is only re-read when its modification time changes, so it can be
+/// updated in place (e.g. a mounted ConfigMap) while the node is running.
+#[derive(Debug)]
+pub struct FileSeeds<A> {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    seeds: Vec<A>,
+}
+
+impl<A> FileSeeds<A> {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            modified: None,
+            seeds: vec![],
+        }
+    }
+}
+
+impl<A> FileSeeds<A>
+where
+    A: FromStr,
+    A::Err: fmt::Display,
+{
+    fn parse(contents: &str) -> Result<Vec<A>, FileSeedsError> {
+        contents
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line, addr)| {
+                addr.parse().map_err(|err: A::Err| FileSeedsError::Parse {
+                    line,
+                    err: err.to_string(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl<A> SeedProvider<A> for FileSeeds<A>
+where
+    A: FromStr + Clone + Send + 'static,
+    A::Err: fmt::Display,
+{
+    type Error = FileSeedsError;
+
+    async fn seeds(&mut self) -> Result<Vec<A>, Self::Error> {
+        let modified = tokio::fs::metadata(&self.path).await?.modified()?;
+        if self.modified != Some(modified) {
+            let contents = tokio::fs::read_to_string(&self.path).await?;
+            self.seeds = Self::parse(&contents)?;
+            self.modified = Some(modified);
+            debug!(
+                "Read {} seeds from {}",
+                self.seeds.len(),
+                self.path.display()
+            );
+        }
+        Ok(self.seeds.clone())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FileSeedsError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid seed on line {line}: {err}")]
+    Parse { line: usize, err: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_parse() {
+        let seeds = FileSeeds::<SocketAddr>::parse(
+            "# seeds\n10.0.0.1:8000\n\n  10.0.0.2:8000  \n# 10.0.0.3:8000\n",
+        )
+        .unwrap();
+        assert_eq!(
+            seeds,
+            vec![
+                "10.0.0.1:8000".parse().unwrap(),
+                "10.0.0.2:8000".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_line() {
+        let res = FileSeeds::<SocketAddr>::parse("10.0.0.1:8000\nnot an addr\n");
+        assert!(matches!(res, Err(FileSeedsError::Parse { line: 2, .. })));
+    }
+}
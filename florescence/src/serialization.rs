@@ -0,0 +1,13 @@
+use pollination::{Bincode, Codec};
+use serde::{Serialize, de::DeserializeOwned};
+
+pub(crate) use pollination::{DeserializeError, SerializeError};
+
+/// Serialize with the same codec the core uses for snapshots.
+pub(crate) fn serialize<T: Serialize>(val: T) -> Result<Vec<u8>, SerializeError> {
+    Bincode.encode(&val)
+}
+
+pub(crate) fn deserialize<T: DeserializeOwned>(val: Vec<u8>) -> Result<T, DeserializeError> {
+    Bincode.decode(&val)
+}
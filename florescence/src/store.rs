@@ -0,0 +1,171 @@
+use crate::{
+    message::Topic,
+    serialization::{DeserializeError, SerializeError, deserialize, serialize},
+};
+use pollination::NodeSnapshot;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+use thiserror::Error;
+use tokio::{fs, io::AsyncWriteExt};
+use uuid::Uuid;
+
+mod wal;
+
+pub use wal::{Wal, WalEntry, WalError};
+
+/// What a `Flower` persists so it can pick up where it left off after a
+/// restart.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FlowerState<A> {
+    pub uuid: Uuid,
+    pub nuclei: Vec<(Topic, NodeSnapshot<A>)>,
+    /// Checkpointed pollinator state, keyed by the topic it is gossiped on.
+    pub pollinators: Vec<(Topic, Vec<u8>)>,
+}
+
+/// Durable storage for `FlowerState`.
+///
+/// `save` is called periodically and on shutdown; `load` once on start.
+pub trait StateStore<A>: Send + 'static {
+    type Error: Error + Send + Sync + 'static;
+
+    fn load(&mut self) -> impl Future<Output = Result<Option<FlowerState<A>>, Self::Error>> + Send;
+
+    fn save(
+        &mut self,
+        state: FlowerState<A>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Stores state in a single file. Writes go to a temporary file which is
+/// synced and then renamed over the old one, so a crash mid-write never
+/// leaves a torn snapshot behind.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tmp.into()
+    }
+}
+
+impl<A> StateStore<A> for FileStore
+where
+    A: Serialize + for<'de> Deserialize<'de> + Send + 'static,
+{
+    type Error = StoreError;
+
+    async fn load(&mut self) -> Result<Option<FlowerState<A>>, Self::Error> {
+        match fs::read(&self.path).await {
+            Ok(bytes) => Ok(Some(deserialize(bytes)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save(&mut self, state: FlowerState<A>) -> Result<(), Self::Error> {
+        let bytes = serialize(&state)?;
+        let tmp = self.tmp_path();
+
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        fs::rename(&tmp, &self.path).await?;
+        sync_parent(&self.path).await?;
+        Ok(())
+    }
+}
+
+/// Sync the directory holding `path`, making a rename into it durable.
+async fn sync_parent(path: &Path) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialize error: {0}")]
+    Serialize(#[from] SerializeError),
+
+    #[error("Deserialize error: {0}")]
+    Deserialize(#[from] DeserializeError),
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Object-safe wrapper around `StateStore`.
+pub(crate) trait ErasedStateStore<A>: Send {
+    fn load(&mut self) -> BoxFuture<'_, Result<Option<FlowerState<A>>, BoxError>>;
+
+    fn save(&mut self, state: FlowerState<A>) -> BoxFuture<'_, Result<(), BoxError>>;
+}
+
+impl<A: Send + 'static, S: StateStore<A>> ErasedStateStore<A> for S {
+    fn load(&mut self) -> BoxFuture<'_, Result<Option<FlowerState<A>>, BoxError>> {
+        Box::pin(async move {
+            StateStore::load(self)
+                .await
+                .map_err(|err| Box::new(err) as BoxError)
+        })
+    }
+
+    fn save(&mut self, state: FlowerState<A>) -> BoxFuture<'_, Result<(), BoxError>> {
+        Box::pin(async move {
+            StateStore::save(self, state)
+                .await
+                .map_err(|err| Box::new(err) as BoxError)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("florescence-{}.state", Uuid::new_v4()));
+        let mut store = FileStore::new(&path);
+
+        let loaded: Option<FlowerState<usize>> = StateStore::load(&mut store).await.unwrap();
+        assert!(loaded.is_none());
+
+        let state = FlowerState::<usize> {
+            uuid: Uuid::from_u128(3),
+            nuclei: vec![],
+            pollinators: vec![(Topic::new("counter".to_string()), vec![7])],
+        };
+        StateStore::save(&mut store, state).await.unwrap();
+
+        let loaded: Option<FlowerState<usize>> = StateStore::load(&mut store).await.unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.uuid, Uuid::from_u128(3));
+        assert_eq!(loaded.pollinators[0].1, vec![7]);
+        assert!(!store.tmp_path().exists());
+
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use super::sync_parent;
+use crate::{
+    message::{PollinationMessage, Topic},
+    serialization::{SerializeError, deserialize, serialize},
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+/// Length and CRC32 of the payload, both little endian.
+const HEADER_LEN: usize = 8;
+
+/// A single durable change, logged before it is applied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WalEntry {
+    /// An operation that originated on this node, encoded by its pollinator.
+    Local { topic: Topic, op: Vec<u8> },
+    /// A message from a peer that was applied to a nucleus.
+    Applied {
+        topic: Topic,
+        msg: PollinationMessage,
+    },
+    /// Encoded pollinator state covering every earlier `Local` entry of its
+    /// topic.
+    Checkpoint { topic: Topic, state: Vec<u8> },
+}
+
+/// Append-only log of changes made since the last snapshot.
+///
+/// Every entry is synced to disk before `append` returns. On open the log is
+/// replayed up to the first torn or corrupt record, which is cut off so new
+/// entries are not appended after garbage.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    entries: usize,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if needed, and return the entries
+    /// it already holds.
+    pub async fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<WalEntry>), WalError> {
+        let path = path.as_ref().to_owned();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .await?;
+
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes).await?;
+        let (entries, valid_len) = Self::decode(&bytes);
+        if valid_len < bytes.len() {
+            warn!(
+                "Discarding {} bytes of torn WAL tail in {}",
+                bytes.len() - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len as u64).await?;
+            file.sync_all().await?;
+        }
+
+        let wal = Self {
+            path,
+            file,
+            entries: entries.len(),
+        };
+        Ok((wal, entries))
+    }
+
+    pub async fn append(&mut self, entry: &WalEntry) -> Result<(), WalError> {
+        self.file.write_all(&Self::record(entry)?).await?;
+        self.file.sync_data().await?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Drop everything already captured by a snapshot, keeping only `keep`.
+    ///
+    /// The kept entries are written to a temporary file which is synced and
+    /// then renamed over the log, so a crash mid-compaction leaves either the
+    /// old log or the new one.
+    pub async fn compact(&mut self, keep: &[WalEntry]) -> Result<(), WalError> {
+        let tmp = self.tmp_path();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .truncate(false)
+            .open(&tmp)
+            .await?;
+        // Left over if an earlier compaction crashed before the rename
+        file.set_len(0).await?;
+        for entry in keep {
+            file.write_all(&Self::record(entry)?).await?;
+        }
+        file.sync_all().await?;
+
+        tokio::fs::rename(&tmp, &self.path).await?;
+        sync_parent(&self.path).await?;
+        // The handle follows the file to its new name
+        self.file = file;
+        self.entries = keep.len();
+        debug!("Compacted WAL {}", self.path.display());
+        Ok(())
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        tmp.into()
+    }
+
+    fn record(entry: &WalEntry) -> Result<Vec<u8>, WalError> {
+        let payload = serialize(entry)?;
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    /// Number of entries since the last compaction.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Decode records until the end or the first bad one, returning the
+    /// entries and the number of bytes they span.
+    fn decode(bytes: &[u8]) -> (Vec<WalEntry>, usize) {
+        let mut entries = vec![];
+        let mut offset = 0;
+        while let Some(header) = bytes.get(offset..offset + HEADER_LEN) {
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+            let start = offset + HEADER_LEN;
+            let Some(payload) = bytes.get(start..start + len) else {
+                break;
+            };
+            if crc32fast::hash(payload) != crc {
+                break;
+            }
+
+            // A matching CRC over an unreadable payload is a write torn just
+            // so; nothing after it can be trusted either
+            match deserialize(payload.to_vec()) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => {
+                    warn!("Stopping WAL replay at unreadable record: {err}");
+                    break;
+                }
+            }
+            offset = start + len;
+        }
+        (entries, offset)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WalError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialize error: {0}")]
+    Serialize(#[from] SerializeError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn entry(op: u8) -> WalEntry {
+        WalEntry::Local {
+            topic: Topic::new("test".to_string()),
+            op: vec![op],
+        }
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("florescence-{}.wal", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let path = temp_path();
+        let (mut wal, entries) = Wal::open(&path).await.unwrap();
+        assert!(entries.is_empty());
+        wal.append(&entry(1)).await.unwrap();
+        wal.append(&entry(2)).await.unwrap();
+        drop(wal);
+
+        let (wal, entries) = Wal::open(&path).await.unwrap();
+        assert_eq!(wal.len(), 2);
+        assert!(matches!(&entries[1], WalEntry::Local { op, .. } if op == &[2]));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let path = temp_path();
+        let (mut wal, _) = Wal::open(&path).await.unwrap();
+        for op in 1..=3 {
+            wal.append(&entry(op)).await.unwrap();
+        }
+        wal.compact(&[entry(2)]).await.unwrap();
+        assert_eq!(wal.len(), 1);
+        assert!(!wal.tmp_path().exists());
+        // Appends go to the compacted log
+        wal.append(&entry(4)).await.unwrap();
+        drop(wal);
+
+        let (_, entries) = Wal::open(&path).await.unwrap();
+        let ops: Vec<_> = entries
+            .iter()
+            .map(|entry| match entry {
+                WalEntry::Local { op, .. } => op[0],
+                _ => panic!("expected a local entry"),
+            })
+            .collect();
+        assert_eq!(ops, [2, 4]);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_torn_tail_discarded() {
+        let path = temp_path();
+        let (mut wal, _) = Wal::open(&path).await.unwrap();
+        wal.append(&entry(1)).await.unwrap();
+        wal.file.write_all(&[42, 0, 0, 0, 1]).await.unwrap();
+        drop(wal);
+
+        let (mut wal, entries) = Wal::open(&path).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        wal.append(&entry(2)).await.unwrap();
+        drop(wal);
+
+        let (_, entries) = Wal::open(&path).await.unwrap();
+        assert_eq!(entries.len(), 2);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_record_discarded() {
+        let path = temp_path();
+        let (mut wal, _) = Wal::open(&path).await.unwrap();
+        wal.append(&entry(1)).await.unwrap();
+        let garbage = [0xff; 4];
+        let mut record = vec![];
+        record.extend_from_slice(&(garbage.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&garbage).to_le_bytes());
+        record.extend_from_slice(&garbage);
+        wal.file.write_all(&record).await.unwrap();
+        drop(wal);
+
+        let (mut wal, entries) = Wal::open(&path).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        wal.append(&entry(2)).await.unwrap();
+        drop(wal);
+
+        let (_, entries) = Wal::open(&path).await.unwrap();
+        assert_eq!(entries.len(), 2);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use pollination::{PollinationMessage, PollinationNode, StartupMode};
+use std::{collections::VecDeque, hint::black_box};
+use uuid::Uuid;
+
+/// A cluster of `members` nodes besides the first, each joining through
+/// whichever node has split its ID the fewest times, so IDs stay as shallow
+/// as in a real cluster. Returns the first node and the last to join, both
+/// in sync with everyone.
+fn cluster(members: u128) -> (PollinationNode<u128>, PollinationNode<u128>) {
+    let mut nodes = vec![PollinationNode::new(Uuid::from_u128(0), 0)];
+    let mut seeds = VecDeque::from([0]);
+    for i in 1..=members {
+        let seed = seeds.pop_front().unwrap();
+        let joiner = PollinationNode::new_with_mode(Uuid::from_u128(i), i, StartupMode::Join);
+        let new_member = joiner.msg_new_member();
+        nodes.push(joiner);
+        nodes[seed].set_propagating();
+        exchange(&mut nodes, i as usize, seed, new_member);
+        seeds.extend([seed, i as usize]);
+    }
+    // Everyone catches up with the first node, then it with everyone
+    for _ in 0..2 {
+        for i in 1..nodes.len() {
+            let heartbeat = nodes[i].msg_heartbeat();
+            exchange(&mut nodes, i, 0, heartbeat);
+        }
+    }
+    let last = nodes.pop().unwrap();
+    assert_eq!(last.timestamp(), nodes[0].timestamp());
+    (nodes.swap_remove(0), last)
+}
+
+/// Pass `msg` from `from` to `to` and keep replying until both are done.
+fn exchange(
+    nodes: &mut [PollinationNode<u128>],
+    from: usize,
+    to: usize,
+    mut msg: Option<PollinationMessage>,
+) {
+    let mut idx = to;
+    while let Some(next) = msg.take() {
+        msg = nodes[idx].handle_message(next).unwrap().response;
+        idx = if idx == to { from } else { to };
+    }
+}
+
+fn bump(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bump");
+    for members in [10, 100, 1000] {
+        let (mut node, _) = cluster(members);
+        group.bench_function(BenchmarkId::from_parameter(members), |b| {
+            b.iter(|| node.bump())
+        });
+    }
+    group.finish();
+}
+
+/// Diffing and encoding the update for a peer one bump behind.
+fn diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("diff");
+    for members in [10, 100, 1000] {
+        let (mut node, peer) = cluster(members);
+        node.bump();
+        group.bench_with_input(BenchmarkId::from_parameter(members), &node, |b, node| {
+            b.iter_batched(
+                // Fresh clones so the patch cache is cold
+                || node.clone(),
+                |node| black_box(node.msg_update(peer.timestamp()).unwrap()),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// A peer one bump behind applying the update.
+fn apply(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply");
+    for members in [10, 100, 1000] {
+        let (mut node, peer) = cluster(members);
+        node.bump();
+        let update = node.msg_update(peer.timestamp()).unwrap().unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(members), &peer, |b, peer| {
+            b.iter_batched(
+                || (peer.clone(), update.clone()),
+                |(mut peer, update)| black_box(peer.handle_message(update).unwrap()),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bump, diff, apply);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use pollination::{Limits, PollinationMessage, PollinationNode, StartupMode};
+use std::hint::black_box;
+use treeclocks::EventTree;
+use uuid::Uuid;
+
+/// A seed node that has let `members` other nodes join through it.
+///
+/// Every join halves the seed's ID, so the depth limit is raised to fit.
+fn cluster(members: u128) -> PollinationNode<u128> {
+    let limits = Limits {
+        max_tree_depth: members as usize + 2,
+        ..Default::default()
+    };
+    let mut seed = PollinationNode::new(Uuid::from_u128(0), 0);
+    seed.set_limits(limits);
+    for i in 1..=members {
+        seed.set_propagating();
+        let mut joiner = PollinationNode::new_with_mode(Uuid::from_u128(i), i, StartupMode::Join);
+        joiner.set_limits(limits);
+        let new_member = PollinationMessage::NewMember {
+            uuid: joiner.uuid(),
+        };
+        let seed_msg = seed.handle_message(new_member).unwrap().response.unwrap();
+        let update = joiner.handle_message(seed_msg).unwrap().response.unwrap();
+        seed.handle_message(update).unwrap();
+    }
+    seed
+}
+
+/// Full-map patches, as sent to every joiner, with and without a warm cache.
+fn seed_patch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("seed_patch");
+    for members in [50, 200, 500] {
+        let node = cluster(members);
+        let empty = EventTree::Leaf(0);
+
+        group.bench_with_input(BenchmarkId::new("cold", members), &node, |b, node| {
+            b.iter_batched(
+                || node.clone(),
+                |node| black_box(node.msg_update(&empty)),
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("cached", members), &node, |b, node| {
+            node.msg_update(&empty).unwrap();
+            b.iter(|| black_box(node.msg_update(&empty)))
+        });
+    }
+    group.finish();
+}
+
+/// Peers that are one step behind, which is the common case for updates.
+fn delta_patch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delta_patch");
+    for members in [50, 200, 500] {
+        let mut node = cluster(members);
+        let behind = node.timestamp().clone();
+        node.bump();
+
+        group.bench_with_input(BenchmarkId::new("cold", members), &node, |b, node| {
+            b.iter_batched(
+                || node.clone(),
+                |node| black_box(node.msg_update(&behind)),
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("cached", members), &node, |b, node| {
+            node.msg_update(&behind).unwrap();
+            b.iter(|| black_box(node.msg_update(&behind)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, seed_patch, delta_patch);
+criterion_main!(benches);
@@ -0,0 +1,257 @@
+use crate::{message::PollinationMessage, serialization::*};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A shared cluster secret used to sign messages. The `id` travels with every
+/// signed message so receivers know which key to verify against during a
+/// rollover.
+#[derive(Clone)]
+pub struct ClusterKey {
+    id: u32,
+    secret: Arc<[u8]>,
+}
+
+impl ClusterKey {
+    pub fn new(id: u32, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id,
+            secret: secret.into().into(),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(&self.id.to_be_bytes());
+        mac.update(payload);
+        mac
+    }
+}
+
+impl fmt::Debug for ClusterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "ClusterKey {{ id: {}, secret: <redacted> }}", self.id)
+    }
+}
+
+/// Signs outgoing and verifies incoming `PollinationMessage`s.
+///
+/// A new secret is rolled out in three steps, each completed across the whole
+/// cluster before the next: `accept` it, `sign_with` it, then `retire` the old
+/// one. No node ever signs with a key a peer does not accept yet.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    signing: ClusterKey,
+    accepted: Vec<ClusterKey>,
+    metrics: Arc<AuthMetrics>,
+}
+
+impl Keyring {
+    pub fn new(key: ClusterKey) -> Self {
+        Self {
+            signing: key,
+            accepted: vec![],
+            metrics: Arc::new(AuthMetrics::default()),
+        }
+    }
+
+    /// Accept messages signed with `key` without signing with it yet.
+    pub fn accept(&mut self, key: ClusterKey) {
+        if key.id == self.signing.id {
+            return;
+        }
+        self.accepted.retain(|k| k.id != key.id);
+        self.accepted.push(key);
+    }
+
+    /// Start signing with an accepted key, still accepting the previous one.
+    pub fn sign_with(&mut self, key_id: u32) -> Result<(), AuthenticationError> {
+        if key_id == self.signing.id {
+            return Ok(());
+        }
+        let idx = self
+            .accepted
+            .iter()
+            .position(|k| k.id == key_id)
+            .ok_or(AuthenticationError::UnknownKey(key_id))?;
+        let key = self.accepted.swap_remove(idx);
+        let old = std::mem::replace(&mut self.signing, key);
+        self.accepted.push(old);
+        Ok(())
+    }
+
+    /// Stop accepting a key once the rollover has completed. The signing key
+    /// cannot be retired.
+    pub fn retire(&mut self, key_id: u32) {
+        self.accepted.retain(|k| k.id != key_id);
+    }
+
+    pub fn metrics(&self) -> &AuthMetrics {
+        &self.metrics
+    }
+
+    pub fn seal(&self, msg: &PollinationMessage) -> Result<Vec<u8>, SerializeError> {
+        self.sign(serialize(msg)?)
+    }
+
+    pub fn open(&self, bytes: &[u8]) -> Result<PollinationMessage, AuthenticationError> {
+        Ok(deserialize_slice(self.check(bytes)?)?)
+    }
+
+    /// Wrap an already serialized payload in a signed envelope.
+    pub fn sign(&self, payload: Vec<u8>) -> Result<Vec<u8>, SerializeError> {
+        let tag = self.signing.mac(&payload).finalize().into_bytes().to_vec();
+        serialize(SignedEnvelope {
+            key_id: self.signing.id,
+            payload: &payload,
+            tag: &tag,
+        })
+    }
+
+    /// Check a signed envelope and return the payload, sharing the buffer of
+    /// `bytes`. Every failure is counted as a rejection so operators can spot
+    /// misconfigured or hostile peers.
+    pub fn verify(&self, bytes: &Bytes) -> Result<Bytes, AuthenticationError> {
+        Ok(bytes.slice_ref(self.check(bytes)?))
+    }
+
+    fn check<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], AuthenticationError> {
+        let res = self.verify_inner(bytes);
+        match &res {
+            Ok(_) => self.metrics.accepted.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.metrics.rejected.fetch_add(1, Ordering::Relaxed),
+        };
+        res
+    }
+
+    fn verify_inner<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], AuthenticationError> {
+        let envelope: SignedEnvelope = deserialize_slice(bytes)?;
+        let key = self
+            .keys()
+            .find(|k| k.id == envelope.key_id)
+            .ok_or(AuthenticationError::UnknownKey(envelope.key_id))?;
+
+        key.mac(envelope.payload)
+            .verify_slice(envelope.tag)
+            .map_err(|_| AuthenticationError::InvalidTag)?;
+
+        Ok(envelope.payload)
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &ClusterKey> {
+        std::iter::once(&self.signing).chain(self.accepted.iter())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedEnvelope<'a> {
+    key_id: u32,
+    payload: &'a [u8],
+    tag: &'a [u8],
+}
+
+#[derive(Debug, Default)]
+pub struct AuthMetrics {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl AuthMetrics {
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AuthenticationError {
+    #[error("Deserialization error: {0}")]
+    DeserializationError(#[from] DeserializeError),
+
+    #[error("Message signed with unknown key {0}")]
+    UnknownKey(u32),
+
+    #[error("Message signature does not match")]
+    InvalidTag,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn msg() -> PollinationMessage {
+        PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(7),
+        }
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let keyring = Keyring::new(ClusterKey::new(0, "secret"));
+        let bytes = keyring.seal(&msg()).unwrap();
+        let out = keyring.open(&bytes).unwrap();
+        assert_eq!(out.uuid(), Uuid::from_u128(7));
+        assert_eq!(keyring.metrics().accepted(), 1);
+    }
+
+    #[test]
+    fn test_wrong_key_rejected() {
+        let sender = Keyring::new(ClusterKey::new(0, "secret"));
+        let receiver = Keyring::new(ClusterKey::new(0, "other secret"));
+        let bytes = sender.seal(&msg()).unwrap();
+        assert!(matches!(
+            receiver.open(&bytes),
+            Err(AuthenticationError::InvalidTag)
+        ));
+        assert_eq!(receiver.metrics().rejected(), 1);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut old = Keyring::new(ClusterKey::new(0, "old"));
+        let mut new = old.clone();
+        assert!(matches!(
+            new.sign_with(1),
+            Err(AuthenticationError::UnknownKey(1))
+        ));
+
+        old.accept(ClusterKey::new(1, "new"));
+        new.accept(ClusterKey::new(1, "new"));
+        new.sign_with(1).unwrap();
+
+        // Both sides of the rollover understand each other
+        assert!(old.open(&new.seal(&msg()).unwrap()).is_ok());
+        assert!(new.open(&old.seal(&msg()).unwrap()).is_ok());
+        assert!(new.open(&new.seal(&msg()).unwrap()).is_ok());
+
+        old.sign_with(1).unwrap();
+        new.retire(0);
+        assert!(new.open(&old.seal(&msg()).unwrap()).is_ok());
+
+        let stale = Keyring::new(ClusterKey::new(0, "old"));
+        assert!(matches!(
+            new.open(&stale.seal(&msg()).unwrap()),
+            Err(AuthenticationError::UnknownKey(0))
+        ));
+    }
+}
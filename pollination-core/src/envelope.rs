@@ -0,0 +1,441 @@
+use crate::{
+    limits::Limits,
+    message::{BinaryPatch, PatchSlice, PollinationMessage},
+    serialization::*,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{fmt, ops::RangeInclusive};
+use thiserror::Error;
+
+/// Wire format version spoken by this build.
+///
+/// - v1: bincode payload
+/// - v2: payload in the [`Format`] named by the envelope
+/// - v3: reality tokens hash the whole membership
+pub const PROTOCOL_VERSION: u16 = 3;
+/// Oldest wire format version this build can still read and write.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional capabilities a node advertises alongside its version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Can decode zstd compressed patches.
+    pub const ZSTD: Features = Features(1 << 0);
+    /// Can decode lz4 compressed patches.
+    pub const LZ4: Features = Features(1 << 1);
+    /// Can read [`Format::Json`] payloads and patches.
+    pub const JSON: Features = Features(1 << 2);
+    /// Can read [`Format::Cbor`] payloads and patches.
+    pub const CBOR: Features = Features(1 << 3);
+    /// Can read [`Format::MessagePack`] payloads and patches.
+    pub const MSGPACK: Features = Features(1 << 4);
+    /// Understands `Handoff` messages.
+    pub const HANDOFF: Features = Features(1 << 5);
+    /// Understands `Digest` and `Repair` messages.
+    pub const ANTI_ENTROPY: Features = Features(1 << 6);
+
+    /// Everything this build supports.
+    pub fn supported() -> Self {
+        #[allow(unused_mut)]
+        let mut features = Self::HANDOFF.union(Self::ANTI_ENTROPY);
+        #[cfg(feature = "zstd")]
+        {
+            features = features.union(Self::ZSTD);
+        }
+        #[cfg(feature = "lz4")]
+        {
+            features = features.union(Self::LZ4);
+        }
+        #[cfg(feature = "json")]
+        {
+            features = features.union(Self::JSON);
+        }
+        #[cfg(feature = "cbor")]
+        {
+            features = features.union(Self::CBOR);
+        }
+        #[cfg(feature = "msgpack")]
+        {
+            features = features.union(Self::MSGPACK);
+        }
+        features
+    }
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Features) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Features) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// What a node can speak, as advertised in every envelope it sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u16,
+    pub min_version: u16,
+    pub features: Features,
+    /// Format payloads are sealed in from v2 onwards.
+    pub format: Format,
+}
+
+/// The highest version and the features both sides of a conversation share.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: Features::supported(),
+            format: Format::default(),
+        }
+    }
+}
+
+impl Protocol {
+    /// Seal payloads in `format` for peers that have negotiated v2 or later.
+    /// Senders should fall back to bincode for peers without the format's
+    /// feature, see [`Format::within`].
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn supports(&self, version: u16) -> bool {
+        self.versions().contains(&version)
+    }
+
+    pub fn versions(&self) -> RangeInclusive<u16> {
+        self.min_version..=self.version
+    }
+
+    /// Pick the highest version both sides speak, if there is one.
+    pub fn negotiate(&self, peer: &Protocol) -> Option<Negotiated> {
+        let version = self.version.min(peer.version);
+        if version < self.min_version || version < peer.min_version {
+            return None;
+        }
+        Some(Negotiated {
+            version,
+            features: self.features.intersection(peer.features),
+        })
+    }
+
+    /// Wrap `msg` in an envelope encoded at `version`.
+    ///
+    /// Until a peer has been heard from, messages to it should be sealed at
+    /// `min_version` so that older nodes can still read them.
+    pub fn seal(&self, msg: &PollinationMessage, version: u16) -> Result<Vec<u8>, EnvelopeError> {
+        if !self.supports(version) {
+            return Err(EnvelopeError::Incompatible {
+                version,
+                supported: self.versions(),
+            });
+        }
+        // Peers before v3 compare tokens by their XOR
+        if version < 3 {
+            return self.seal_inner(&msg.clone().into_legacy(), version);
+        }
+        self.seal_inner(msg, version)
+    }
+
+    fn seal_inner(&self, msg: &PollinationMessage, version: u16) -> Result<Vec<u8>, EnvelopeError> {
+        // v1 peers only read bincode and an envelope without a format
+        if version < 2 {
+            return Ok(serialize(EnvelopeV1 {
+                version,
+                min_version: self.min_version,
+                max_version: self.version,
+                features: self.features,
+                payload: &serialize(msg)?,
+            })?);
+        }
+
+        Ok(serialize(Envelope {
+            version,
+            min_version: self.min_version,
+            max_version: self.version,
+            features: self.features,
+            payload: &self.format.encode(msg)?,
+            format: self.format,
+        })?)
+    }
+
+    /// Unwrap an envelope, returning the message and what the sender speaks.
+    ///
+    /// Patches in a bincode message share the buffer of `bytes`.
+    pub fn open(&self, bytes: &Bytes) -> Result<(PollinationMessage, Protocol), EnvelopeError> {
+        self.open_within(bytes, &Limits::default())
+    }
+
+    /// Like [`Protocol::open`], but decoding within `limits`.
+    pub fn open_within(
+        &self,
+        bytes: &Bytes,
+        limits: &Limits,
+    ) -> Result<(PollinationMessage, Protocol), EnvelopeError> {
+        // Every envelope starts with its version
+        let version: u16 = deserialize_slice_within(bytes, limits)?;
+        if !self.supports(version) {
+            return Err(EnvelopeError::Incompatible {
+                version,
+                supported: self.versions(),
+            });
+        }
+
+        let envelope: Envelope = if version < 2 {
+            deserialize_slice_within::<EnvelopeV1>(bytes, limits)?.into()
+        } else {
+            deserialize_slice_within(bytes, limits)?
+        };
+
+        let mut msg: PollinationMessage = match envelope.format {
+            // Bincode borrows patches from the payload, so they can point
+            // into `bytes` rather than own a copy
+            Format::Bincode => deserialize_slice_within::<PollinationMessage<PatchSlice>>(
+                envelope.payload,
+                limits,
+            )?
+            .map_patch(|patch| BinaryPatch::shared(bytes, patch)),
+            format => format.decode_within(envelope.payload, limits)?,
+        };
+        if version < 3 {
+            msg = msg.into_legacy();
+        }
+        let peer = Protocol {
+            version: envelope.max_version,
+            min_version: envelope.min_version,
+            features: envelope.features,
+            format: envelope.format,
+        };
+        Ok((msg, peer))
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "v{}..=v{} ({})",
+            self.min_version, self.version, self.format
+        )
+    }
+}
+
+/// Field order is part of the wire format; only ever append fields.
+#[derive(Serialize, Deserialize)]
+struct Envelope<'a> {
+    /// Version `payload` is encoded at.
+    version: u16,
+    min_version: u16,
+    max_version: u16,
+    features: Features,
+    payload: &'a [u8],
+    format: Format,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EnvelopeV1<'a> {
+    version: u16,
+    min_version: u16,
+    max_version: u16,
+    features: Features,
+    payload: &'a [u8],
+}
+
+impl<'a> From<EnvelopeV1<'a>> for Envelope<'a> {
+    fn from(v1: EnvelopeV1<'a>) -> Self {
+        Self {
+            version: v1.version,
+            min_version: v1.min_version,
+            max_version: v1.max_version,
+            features: v1.features,
+            payload: v1.payload,
+            format: Format::Bincode,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+    #[error("Serialize error: {0}")]
+    Serialize(#[from] SerializeError),
+
+    #[error("Deserialize error: {0}")]
+    Deserialize(#[from] DeserializeError),
+
+    #[error("Protocol version {version} is not in supported range {supported:?}")]
+    Incompatible {
+        version: u16,
+        supported: RangeInclusive<u16>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer_info::PeerInfo, reality_token::Tokens};
+    use treeclocks::{EventTree, IdTree};
+    use uuid::Uuid;
+
+    /// A `NewMember` from `Uuid::from_u128(1)` as sent by a v1 node without
+    /// any optional features. Never regenerate this; add a new fixture per
+    /// version instead.
+    const V1_NEW_MEMBER: &[u8] = &[
+        1,  // version
+        1,  // min_version
+        1,  // max_version
+        0,  // features
+        18, // payload length
+        4,  // NewMember
+        16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, // uuid
+    ];
+
+    fn new_member() -> PollinationMessage {
+        PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(1),
+        }
+    }
+
+    #[test]
+    fn test_v1_fixture() {
+        let (msg, peer) = Protocol::default()
+            .open(&Bytes::from_static(V1_NEW_MEMBER))
+            .unwrap();
+        assert_eq!(msg.uuid(), Uuid::from_u128(1));
+        assert_eq!(peer.version, 1);
+        assert_eq!(peer.features, Features::NONE);
+        assert_eq!(peer.format, Format::Bincode);
+    }
+
+    #[test]
+    fn test_v2_format() {
+        for format in [Format::Json, Format::Cbor, Format::MessagePack] {
+            if !Features::supported().contains(format.feature()) {
+                continue;
+            }
+            let sender = Protocol::default().with_format(format);
+            let bytes = sender.seal(&new_member(), 2).unwrap();
+            let (msg, peer) = Protocol::default().open(&bytes.into()).unwrap();
+            assert_eq!(msg.uuid(), Uuid::from_u128(1));
+            assert_eq!(peer.format, format);
+        }
+    }
+
+    #[test]
+    fn test_v2_unsupported_format() {
+        let sender = Protocol::default().with_format(Format::MessagePack);
+        let res = sender.seal(&new_member(), 2);
+        if Features::supported().contains(Features::MSGPACK) {
+            assert!(res.is_ok());
+        } else {
+            assert!(matches!(
+                res,
+                Err(EnvelopeError::Serialize(SerializeError::Unsupported(
+                    Format::MessagePack
+                )))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_v1_ignores_format() {
+        let sender = Protocol {
+            features: Features::NONE,
+            ..Protocol::default()
+        }
+        .with_format(Format::Json);
+        let bytes = sender.seal(&new_member(), 1).unwrap();
+        // Identical to the fixture apart from `max_version`
+        assert_eq!(&bytes[..2], &V1_NEW_MEMBER[..2]);
+        assert_eq!(&bytes[3..], &V1_NEW_MEMBER[3..]);
+    }
+
+    #[test]
+    fn test_token_per_version() {
+        let info = PeerInfo::new(Uuid::from_u128(1), ());
+        let tokens = Tokens::of([(&IdTree::One, &info)]);
+        let heartbeat = PollinationMessage::Heartbeat {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: tokens.current(),
+            legacy_token: Some(tokens.legacy()),
+        };
+        let protocol = Protocol::default();
+        for (version, expected) in [(2, tokens.legacy()), (PROTOCOL_VERSION, tokens.current())] {
+            let bytes = protocol.seal(&heartbeat, version).unwrap();
+            let (msg, _) = protocol.open(&bytes.into()).unwrap();
+            let PollinationMessage::Heartbeat { reality_token, .. } = msg else {
+                panic!("expected a heartbeat");
+            };
+            assert_eq!(reality_token, expected);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let protocol = Protocol::default();
+        let bytes = protocol.seal(&new_member(), MIN_PROTOCOL_VERSION).unwrap();
+        let (msg, peer) = protocol.open(&bytes.into()).unwrap();
+        assert_eq!(msg.uuid(), Uuid::from_u128(1));
+        assert_eq!(peer, protocol);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let old = Protocol {
+            version: 2,
+            min_version: 1,
+            features: Features::ZSTD,
+            format: Format::Bincode,
+        };
+        let new = Protocol {
+            version: 4,
+            min_version: 2,
+            features: Features::ZSTD.union(Features::LZ4),
+            format: Format::Bincode,
+        };
+        let ancient = Protocol {
+            version: 1,
+            min_version: 1,
+            features: Features::NONE,
+            format: Format::Bincode,
+        };
+
+        let negotiated = new.negotiate(&old).unwrap();
+        assert_eq!(negotiated.version, 2);
+        assert_eq!(negotiated.features, Features::ZSTD);
+        assert_eq!(old.negotiate(&new), new.negotiate(&old));
+        assert!(new.negotiate(&ancient).is_none());
+    }
+
+    #[test]
+    fn test_reject_unsupported_version() {
+        let newer = Protocol {
+            version: PROTOCOL_VERSION + 1,
+            min_version: PROTOCOL_VERSION + 1,
+            features: Features::NONE,
+            format: Format::Bincode,
+        };
+        let bytes = newer.seal(&new_member(), PROTOCOL_VERSION + 1).unwrap();
+        assert!(matches!(
+            Protocol::default().open(&bytes.into()),
+            Err(EnvelopeError::Incompatible { .. })
+        ));
+    }
+}
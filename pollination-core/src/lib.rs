@@ -1,6 +1,9 @@
 #[macro_use]
 extern crate tracing;
 
+mod authentication;
+mod envelope;
+mod limits;
 mod message;
 mod peer_info;
 mod pollination;
@@ -8,6 +11,26 @@ mod propagativity;
 mod reality_token;
 mod serialization;
 
-pub use message::{BinaryPatch, PollinationMessage};
+pub use authentication::{AuthMetrics, AuthenticationError, ClusterKey, Keyring};
+pub use envelope::{
+    EnvelopeError, Features, MIN_PROTOCOL_VERSION, Negotiated, PROTOCOL_VERSION, Protocol,
+};
+pub use limits::{InvalidMessage, Limits};
+pub use message::{
+    BinaryPatch, Compression, CompressionError, PatchDecodeError, PollinationMessage,
+};
 pub use peer_info::{PeerInfo, PeerStatus};
-pub use pollination::{PollinationError, PollinationNode, PollinationResponse};
+pub use pollination::{
+    AntiEntropyStats, Defrag, MapDigest, NodeSnapshot, PatchCacheStats, PollinationError,
+    PollinationNode, PollinationResponse, RecycleError, StartupMode, TimestampStats,
+};
+pub use reality_token::RealityToken;
+#[cfg(feature = "cbor")]
+pub use serialization::Cbor;
+#[cfg(feature = "json")]
+pub use serialization::Json;
+#[cfg(feature = "msgpack")]
+pub use serialization::MessagePack;
+pub use serialization::{
+    Bincode, ClusterCipher, Codec, DeserializeError, EncryptionError, Format, SerializeError,
+};
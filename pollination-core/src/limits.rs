@@ -0,0 +1,163 @@
+use thiserror::Error;
+use treeclocks::{EventTree, IdTree};
+
+/// Bounds on what a peer can make us decode. Anything larger is rejected
+/// before it is applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest encoded message accepted off the wire.
+    pub max_message_size: usize,
+    /// Largest patch once decompressed.
+    pub max_patch_size: usize,
+    /// Deepest `IdTree` or `EventTree`. Bincode decoding gives up on input
+    /// nested much deeper than this before building it, so a peer can not
+    /// exhaust our stack.
+    pub max_tree_depth: usize,
+    /// Most a single bincode decode may claim, counting scalars by size and
+    /// byte fields and sequences by their announced length.
+    pub max_decode_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4 * 1024 * 1024,
+            max_patch_size: 16 * 1024 * 1024,
+            max_tree_depth: 256,
+            max_decode_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Check that `id` is within bounds and normalized, i.e. no subtree
+    /// could be collapsed into a single `Zero` or `One`.
+    pub fn check_id(&self, id: &IdTree) -> Result<(), InvalidMessage> {
+        let mut stack = vec![(id, 1)];
+        while let Some((id, depth)) = stack.pop() {
+            self.check_depth(depth)?;
+            if let IdTree::SubTree(l, r) = id {
+                match (l.as_ref(), r.as_ref()) {
+                    (IdTree::Zero, IdTree::Zero) | (IdTree::One, IdTree::One) => {
+                        return Err(InvalidMessage::UnnormalizedId);
+                    }
+                    (l, r) => {
+                        stack.push((l, depth + 1));
+                        stack.push((r, depth + 1));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `timestamp` is within bounds and that no event count,
+    /// summed from the root down, overflows a `u64`.
+    pub fn check_timestamp(&self, timestamp: &EventTree) -> Result<(), InvalidMessage> {
+        let mut stack = vec![(timestamp, 1, 0u64)];
+        while let Some((timestamp, depth, base)) = stack.pop() {
+            self.check_depth(depth)?;
+            let (EventTree::Leaf(n) | EventTree::SubTree(n, _, _)) = timestamp;
+            let value = base
+                .checked_add(*n)
+                .ok_or(InvalidMessage::TimestampOverflow)?;
+            if let EventTree::SubTree(_, l, r) = timestamp {
+                stack.push((l.as_ref(), depth + 1, value));
+                stack.push((r.as_ref(), depth + 1, value));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_patch_size(&self, size: usize) -> Result<(), InvalidMessage> {
+        if size > self.max_patch_size {
+            return Err(InvalidMessage::PatchTooLarge {
+                size,
+                max: self.max_patch_size,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_depth(&self, depth: usize) -> Result<(), InvalidMessage> {
+        if depth > self.max_tree_depth {
+            return Err(InvalidMessage::TreeTooDeep {
+                max: self.max_tree_depth,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidMessage {
+    #[error("Message of {size} bytes exceeds the limit of {max}")]
+    TooLarge { size: usize, max: usize },
+
+    #[error("Patch of {size} bytes exceeds the limit of {max}")]
+    PatchTooLarge { size: usize, max: usize },
+
+    #[error("Tree deeper than the limit of {max}")]
+    TreeTooDeep { max: usize },
+
+    #[error("ID tree is not normalized")]
+    UnnormalizedId,
+
+    #[error("Timestamp overflows an event count")]
+    TimestampOverflow,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deep_id(depth: usize) -> IdTree {
+        (0..depth).fold(IdTree::One, |id, _| {
+            IdTree::SubTree(Box::new(id), Box::new(IdTree::Zero))
+        })
+    }
+
+    #[test]
+    fn test_id_depth() {
+        let limits = Limits {
+            max_tree_depth: 8,
+            ..Default::default()
+        };
+        assert!(limits.check_id(&deep_id(7)).is_ok());
+        assert!(matches!(
+            limits.check_id(&deep_id(8)),
+            Err(InvalidMessage::TreeTooDeep { max: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_unnormalized_id() {
+        let id = IdTree::SubTree(
+            Box::new(IdTree::Zero),
+            Box::new(IdTree::SubTree(
+                Box::new(IdTree::One),
+                Box::new(IdTree::One),
+            )),
+        );
+        assert!(matches!(
+            Limits::default().check_id(&id),
+            Err(InvalidMessage::UnnormalizedId)
+        ));
+    }
+
+    #[test]
+    fn test_timestamp_overflow() {
+        let timestamp = |n| {
+            EventTree::SubTree(
+                u64::MAX - 1,
+                Box::new(EventTree::Leaf(n)),
+                Box::new(EventTree::Leaf(0)),
+            )
+        };
+        assert!(Limits::default().check_timestamp(&timestamp(1)).is_ok());
+        assert!(matches!(
+            Limits::default().check_timestamp(&timestamp(2)),
+            Err(InvalidMessage::TimestampOverflow)
+        ));
+    }
+}
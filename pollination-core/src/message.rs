@@ -1,29 +1,52 @@
-use crate::{reality_token::RealityToken, serialization::*};
+use crate::{
+    envelope::Features,
+    limits::{InvalidMessage, Limits},
+    pollination::MapDigest,
+    reality_token::RealityToken,
+    serialization::*,
+};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use thiserror::Error;
 use treeclocks::{EventTree, IdTree};
 use uuid::Uuid;
 
+mod compression;
+
+pub use compression::{Compression, CompressionError};
+
+/// Messages we build carry our token for peers before v3 in `legacy_token`,
+/// which never goes on the wire itself; see [`PollinationMessage::into_legacy`].
+///
+/// Patches are [`BinaryPatch`]es, other than while a message is decoded.
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub enum PollinationMessage {
+pub enum PollinationMessage<P = BinaryPatch> {
     Heartbeat {
         uuid: Uuid,
         id: IdTree,
         timestamp: EventTree,
         reality_token: RealityToken,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
     },
     Update {
         uuid: Uuid,
         id: IdTree,
         timestamp: EventTree,
         reality_token: RealityToken,
-        patch: BinaryPatch,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
+        patch: P,
     },
     RealitySkew {
         uuid: Uuid,
         id: IdTree,
         timestamp: EventTree,
         reality_token: RealityToken,
-        patch: BinaryPatch,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
+        patch: P,
         peer_count: usize,
     },
     Seed {
@@ -31,16 +54,63 @@ pub enum PollinationMessage {
         id: IdTree,
         timestamp: EventTree,
         reality_token: RealityToken,
-        patch: BinaryPatch,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
+        patch: P,
         peer_count: usize,
         new_id: Option<IdTree>,
     },
     NewMember {
         uuid: Uuid,
     },
+    /// `fragment` of the sender's ID has been retired as dead, for the
+    /// receiver to merge into its own.
+    Handoff {
+        uuid: Uuid,
+        id: IdTree,
+        timestamp: EventTree,
+        reality_token: RealityToken,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
+        fragment: IdTree,
+    },
+    /// Anti-entropy probe, only acted on by peers that look converged.
+    Digest {
+        uuid: Uuid,
+        id: IdTree,
+        timestamp: EventTree,
+        reality_token: RealityToken,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
+        digest: MapDigest,
+    },
+    /// The sender's full state, to replace the receiver's diverged map.
+    Repair {
+        uuid: Uuid,
+        id: IdTree,
+        timestamp: EventTree,
+        reality_token: RealityToken,
+        #[serde(skip)]
+        legacy_token: Option<RealityToken>,
+        patch: P,
+    },
 }
 
 impl PollinationMessage {
+    pub fn uuid(&self) -> Uuid {
+        use PollinationMessage::*;
+        match self {
+            Heartbeat { uuid, .. }
+            | Update { uuid, .. }
+            | RealitySkew { uuid, .. }
+            | Seed { uuid, .. }
+            | NewMember { uuid }
+            | Handoff { uuid, .. }
+            | Digest { uuid, .. }
+            | Repair { uuid, .. } => *uuid,
+        }
+    }
+
     pub fn timestamp(&self) -> Option<&EventTree> {
         use PollinationMessage::*;
         match self {
@@ -48,7 +118,10 @@ impl PollinationMessage {
             Heartbeat { timestamp, .. }
             | Update { timestamp, .. }
             | RealitySkew { timestamp, .. }
-            | Seed { timestamp, .. } => Some(timestamp),
+            | Seed { timestamp, .. }
+            | Handoff { timestamp, .. }
+            | Digest { timestamp, .. }
+            | Repair { timestamp, .. } => Some(timestamp),
         }
     }
 
@@ -56,10 +129,136 @@ impl PollinationMessage {
         use PollinationMessage::*;
         match self {
             NewMember { .. } => None,
-            Heartbeat { id, .. } | Update { id, .. } | RealitySkew { id, .. } | Seed { id, .. } => {
-                Some(id)
+            Heartbeat { id, .. }
+            | Update { id, .. }
+            | RealitySkew { id, .. }
+            | Seed { id, .. }
+            | Handoff { id, .. }
+            | Digest { id, .. }
+            | Repair { id, .. } => Some(id),
+        }
+    }
+
+    /// Check everything the sender controls against `limits`, before any of
+    /// it is acted on.
+    pub fn validate(&self, limits: &Limits) -> Result<(), InvalidMessage> {
+        use PollinationMessage::*;
+        if let Some(id) = self.id() {
+            limits.check_id(id)?;
+        }
+        if let Some(timestamp) = self.timestamp() {
+            limits.check_timestamp(timestamp)?;
+        }
+        if let Seed {
+            new_id: Some(id), ..
+        }
+        | Handoff { fragment: id, .. } = self
+        {
+            limits.check_id(id)?;
+        }
+        if let Update { patch, .. }
+        | RealitySkew { patch, .. }
+        | Seed { patch, .. }
+        | Repair { patch, .. } = self
+        {
+            limits.check_patch_size(patch.len())?;
+        }
+        Ok(())
+    }
+
+    /// What a peer needs to understand this kind of message, patches aside.
+    pub fn required(&self) -> Features {
+        match self {
+            PollinationMessage::Handoff { .. } => Features::HANDOFF,
+            PollinationMessage::Digest { .. } | PollinationMessage::Repair { .. } => {
+                Features::ANTI_ENTROPY
             }
+            _ => Features::NONE,
+        }
+    }
+
+    /// This message as a peer with `features` can decode it, with its patch
+    /// decompressed if the peer lacks the codec. Patches can not be moved to
+    /// another format, so one the peer can not read is an error.
+    pub fn for_features(
+        &self,
+        features: Features,
+        limits: &Limits,
+    ) -> Result<Cow<'_, Self>, PatchDecodeError> {
+        use PollinationMessage::*;
+        let (Update { patch, .. }
+        | RealitySkew { patch, .. }
+        | Seed { patch, .. }
+        | Repair { patch, .. }) = self
+        else {
+            return Ok(Cow::Borrowed(self));
+        };
+        if features.contains(patch.required()) {
+            return Ok(Cow::Borrowed(self));
+        }
+        let format = patch.format()?;
+        if !features.contains(format.feature()) {
+            return Err(PatchDecodeError::Unreadable(format));
+        }
+
+        let uncompressed = patch.uncompressed(limits)?;
+        let mut msg = self.light_clone();
+        if let Update { patch, .. }
+        | RealitySkew { patch, .. }
+        | Seed { patch, .. }
+        | Repair { patch, .. } = &mut msg
+        {
+            *patch = uncompressed;
         }
+        Ok(Cow::Owned(msg))
+    }
+
+    /// This message as peers before v3 read it: with the XOR of uuids as
+    /// its reality token. Messages decoded off a pre-v3 envelope only know
+    /// that value, which they carry as their token.
+    pub(crate) fn into_legacy(mut self) -> Self {
+        use PollinationMessage::*;
+        if let Heartbeat {
+            reality_token,
+            legacy_token,
+            ..
+        }
+        | Update {
+            reality_token,
+            legacy_token,
+            ..
+        }
+        | RealitySkew {
+            reality_token,
+            legacy_token,
+            ..
+        }
+        | Seed {
+            reality_token,
+            legacy_token,
+            ..
+        }
+        | Handoff {
+            reality_token,
+            legacy_token,
+            ..
+        }
+        | Digest {
+            reality_token,
+            legacy_token,
+            ..
+        }
+        | Repair {
+            reality_token,
+            legacy_token,
+            ..
+        } = &mut self
+        {
+            *reality_token = legacy_token
+                .take()
+                .unwrap_or_else(|| reality_token.into_legacy());
+        }
+        self
     }
 
     pub fn light_clone(&self) -> Self {
@@ -72,14 +271,136 @@ impl PollinationMessage {
     fn delete_patch(&mut self) {
         use PollinationMessage::*;
         match self {
-            Heartbeat { .. } | NewMember { .. } => {}
-            Update { patch, .. } | RealitySkew { patch, .. } | Seed { patch, .. } => {
+            Heartbeat { .. } | NewMember { .. } | Handoff { .. } | Digest { .. } => {}
+            Update { patch, .. }
+            | RealitySkew { patch, .. }
+            | Seed { patch, .. }
+            | Repair { patch, .. } => {
                 let _ = std::mem::take(patch);
             }
         }
     }
 }
 
+impl<P> PollinationMessage<P> {
+    /// The same message with its patch, if any, passed through `f`.
+    pub(crate) fn map_patch<Q>(self, f: impl FnOnce(P) -> Q) -> PollinationMessage<Q> {
+        use PollinationMessage::*;
+        match self {
+            Heartbeat {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+            } => Heartbeat {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+            },
+            Update {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch,
+            } => Update {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch: f(patch),
+            },
+            RealitySkew {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch,
+                peer_count,
+            } => RealitySkew {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch: f(patch),
+                peer_count,
+            },
+            Seed {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch,
+                peer_count,
+                new_id,
+            } => Seed {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch: f(patch),
+                peer_count,
+                new_id,
+            },
+            NewMember { uuid } => NewMember { uuid },
+            Handoff {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                fragment,
+            } => Handoff {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                fragment,
+            },
+            Digest {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                digest,
+            } => Digest {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                digest,
+            },
+            Repair {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch,
+            } => Repair {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token,
+                patch: f(patch),
+            },
+        }
+    }
+}
+
 impl std::fmt::Display for PollinationMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         use PollinationMessage::*;
@@ -89,6 +410,7 @@ impl std::fmt::Display for PollinationMessage {
                 id,
                 timestamp,
                 reality_token,
+                legacy_token: _,
             } => {
                 write!(
                     f,
@@ -100,6 +422,7 @@ impl std::fmt::Display for PollinationMessage {
                 id,
                 timestamp,
                 reality_token,
+                legacy_token: _,
                 patch,
             } => {
                 write!(
@@ -112,6 +435,7 @@ impl std::fmt::Display for PollinationMessage {
                 id,
                 timestamp,
                 reality_token,
+                legacy_token: _,
                 peer_count,
                 patch,
             } => {
@@ -125,6 +449,7 @@ impl std::fmt::Display for PollinationMessage {
                 id,
                 timestamp,
                 reality_token,
+                legacy_token: _,
                 new_id,
                 peer_count,
                 patch,
@@ -138,17 +463,64 @@ impl std::fmt::Display for PollinationMessage {
             NewMember { uuid, .. } => {
                 write!(f, "NEW_MEMBER UUID:{uuid}")
             }
+            Handoff {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token: _,
+                fragment,
+            } => {
+                write!(
+                    f,
+                    "HANDOFF UUID:{uuid} ID:{id} TS:{timestamp} RT:{reality_token} FRAGMENT:{fragment}"
+                )
+            }
+            Digest {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token: _,
+                digest,
+            } => {
+                write!(
+                    f,
+                    "DIGEST UUID:{uuid} ID:{id} TS:{timestamp} RT:{reality_token} DIGEST:{digest}"
+                )
+            }
+            Repair {
+                uuid,
+                id,
+                timestamp,
+                reality_token,
+                legacy_token: _,
+                patch,
+            } => {
+                write!(
+                    f,
+                    "REPAIR UUID:{uuid} ID:{id} TS:{timestamp} RT:{reality_token} PATCH:{patch}"
+                )
+            }
         }
     }
 }
 
+/// An encoded patch, kept as received until it is applied. When opened
+/// from a bincode envelope it shares the buffer of the incoming message.
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct BinaryPatch {
-    inner: Vec<u8>,
+    inner: Bytes,
+}
+
+/// A [`BinaryPatch`] borrowed from the buffer it is decoded from. Both
+/// encode the same way.
+#[derive(Deserialize)]
+pub(crate) struct PatchSlice<'a> {
+    inner: &'a [u8],
 }
 
 impl std::fmt::Display for BinaryPatch {
-    #[cfg(not(feature = "json"))]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(f, "[")?;
         for b in self.inner.iter() {
@@ -158,26 +530,116 @@ impl std::fmt::Display for BinaryPatch {
 
         Ok(())
     }
-
-    #[cfg(feature = "json")]
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        let s = String::from_utf8(self.inner.clone()).map_err(|_| std::fmt::Error {})?;
-        write!(f, "{s}")
-    }
 }
 
 impl BinaryPatch {
+    /// `slice` as a patch sharing `source`, which it was decoded from.
+    pub(crate) fn shared(source: &Bytes, slice: PatchSlice<'_>) -> Self {
+        Self {
+            inner: source.slice_ref(slice.inner),
+        }
+    }
+
     pub fn new<T: Serialize>(val: T) -> Result<Self, SerializeError> {
-        let inner = serialize(val)?;
-        Ok(Self { inner })
+        Self::with_compression(val, Compression::None)
+    }
+
+    /// Encode `val` with bincode, compressing it if it is large enough to be
+    /// worth it.
+    pub fn with_compression<T: Serialize>(
+        val: T,
+        compression: Compression,
+    ) -> Result<Self, SerializeError> {
+        Self::encode(val, Format::Bincode, compression)
+    }
+
+    /// Encode `val` in `format`, which is named in the patch header so any
+    /// peer with the format's feature can decode it.
+    pub fn encode<T: Serialize>(
+        val: T,
+        format: Format,
+        compression: Compression,
+    ) -> Result<Self, SerializeError> {
+        let bytes = compression::compress(format.encode(&val)?, compression);
+        Ok(Self {
+            inner: with_format(bytes, format).into(),
+        })
+    }
+
+    pub fn decode<T: for<'de> Deserialize<'de>>(&self) -> Result<T, PatchDecodeError> {
+        self.decode_within(&Limits::default())
     }
 
-    pub fn decode<T: for<'de> Deserialize<'de>>(self) -> Result<T, DeserializeError> {
-        let res = deserialize(self.inner)?;
-        Ok(res)
+    /// Decode, refusing patches that decompress to more than `limits` allow.
+    pub fn decode_within<T: for<'de> Deserialize<'de>>(
+        &self,
+        limits: &Limits,
+    ) -> Result<T, PatchDecodeError> {
+        let format = self.format()?;
+        let payload = compression::decompress(&self.inner, limits.max_patch_size)?;
+        Ok(format.decode_within(&payload, limits)?)
+    }
+
+    /// The format the patch was encoded in.
+    pub fn format(&self) -> Result<Format, PatchDecodeError> {
+        let tag = self.inner.first().map_or(0, |header| header >> 4);
+        Format::from_tag(tag).ok_or(PatchDecodeError::UnknownFormat(tag))
+    }
+
+    /// What a peer needs to decode this patch.
+    pub fn required(&self) -> Features {
+        let format = self
+            .format()
+            .map_or(Features::NONE, |format| format.feature());
+        compression::required(&self.inner).union(format)
+    }
+
+    /// The same patch without compression, for peers lacking the codec.
+    pub fn uncompressed(&self, limits: &Limits) -> Result<Self, PatchDecodeError> {
+        let format = self.format()?;
+        let payload = compression::decompress(&self.inner, limits.max_patch_size)?;
+        let bytes = compression::compress(payload.into_owned(), Compression::None);
+        Ok(Self {
+            inner: with_format(bytes, format).into(),
+        })
+    }
+
+    /// Size of the patch on the wire.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
     }
 }
 
+/// Name `format` in the high bits of the header byte written by `compress`.
+fn with_format(mut bytes: Vec<u8>, format: Format) -> Vec<u8> {
+    if let Some(header) = bytes.first_mut() {
+        *header = (*header & compression::CODEC_MASK) | (format.tag() << 4);
+    }
+    bytes
+}
+
+#[derive(Debug, Error)]
+pub enum PatchDecodeError {
+    #[error("Decompression error: {0}")]
+    Decompress(#[from] CompressionError),
+
+    #[error("Deserialization error: {0}")]
+    Deserialize(#[from] DeserializeError),
+
+    #[error("Re-encoding error: {0}")]
+    Reencode(#[from] SerializeError),
+
+    #[error("Unknown patch format {0}")]
+    UnknownFormat(u8),
+
+    #[error("Peer can not read {0} patches")]
+    Unreadable(Format),
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -200,6 +662,144 @@ mod tests {
         let p_bin = BinaryPatch::new(p_in).unwrap();
         let p_out = p_bin.decode::<Patch<i32>>().unwrap();
 
-        //assert_eq!(string, bbh);
+        let mut out = ItcMap::new();
+        out.apply(p_out);
+        assert_eq!(out.get(&IdTree::One), Some(&1));
+        assert_eq!(out.timestamp(), m.timestamp());
+    }
+
+    #[test]
+    fn test_opened_patch_shares_buffer() {
+        let msg: PollinationMessage = PollinationMessage::Repair {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+            patch: BinaryPatch::new("pollen").unwrap(),
+        };
+        let protocol = crate::envelope::Protocol::default();
+        let bytes = Bytes::from(protocol.seal(&msg, protocol.version).unwrap());
+        let (PollinationMessage::Repair { patch, .. }, _) = protocol.open(&bytes).unwrap() else {
+            panic!("expected a repair");
+        };
+        assert_eq!(patch.decode::<String>().unwrap(), "pollen");
+        let range = bytes.as_ptr_range();
+        assert!(range.contains(&patch.inner.as_ptr()));
+    }
+
+    #[test]
+    fn test_binary_patch_deepest_id() {
+        let limits = Limits::default();
+        let id = (1..limits.max_tree_depth).fold(IdTree::One, |id, _| {
+            IdTree::SubTree(Box::new(id), Box::new(IdTree::Zero))
+        });
+        let mut m = ItcMap::new();
+        m.insert(id.clone(), 1);
+
+        let p_bin = BinaryPatch::new(m.diff(&EventTree::new())).unwrap();
+        let p_out = p_bin.decode_within::<Patch<i32>>(&limits).unwrap();
+
+        let mut out = ItcMap::new();
+        out.apply(p_out);
+        assert_eq!(out.get(&id), Some(&1));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    #[test]
+    fn test_binary_patch_compressed() {
+        #[cfg(feature = "zstd")]
+        let compression = Compression::Zstd;
+        #[cfg(all(feature = "lz4", not(feature = "zstd")))]
+        let compression = Compression::Lz4;
+
+        let val = "pollen ".repeat(1000);
+        let raw = BinaryPatch::new(&val).unwrap();
+        let compressed = BinaryPatch::with_compression(&val, compression).unwrap();
+        assert!(compressed.len() < raw.len());
+        assert_eq!(compressed.decode::<String>().unwrap(), val);
+    }
+
+    #[test]
+    fn test_for_features_uncompressed() {
+        let msg = PollinationMessage::Repair {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+            patch: BinaryPatch::new("pollen ".repeat(1000)).unwrap(),
+        };
+        let limits = Limits::default();
+        assert!(matches!(
+            msg.for_features(Features::NONE, &limits).unwrap(),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    #[test]
+    fn test_for_features_decompresses() {
+        #[cfg(feature = "zstd")]
+        let compression = Compression::Zstd;
+        #[cfg(all(feature = "lz4", not(feature = "zstd")))]
+        let compression = Compression::Lz4;
+
+        let val = "pollen ".repeat(1000);
+        let msg = PollinationMessage::Repair {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+            patch: BinaryPatch::with_compression(&val, compression).unwrap(),
+        };
+        let limits = Limits::default();
+        assert!(matches!(
+            msg.for_features(compression.feature(), &limits).unwrap(),
+            Cow::Borrowed(_)
+        ));
+
+        let Cow::Owned(PollinationMessage::Repair { patch, .. }) =
+            msg.for_features(Features::NONE, &limits).unwrap()
+        else {
+            panic!("patch was not decompressed");
+        };
+        assert_eq!(patch.required(), Features::NONE);
+        assert_eq!(patch.decode::<String>().unwrap(), val);
+    }
+    #[test]
+    fn test_patch_unknown_format() {
+        let patch = BinaryPatch {
+            inner: Bytes::from_static(&[0xF0, 0]),
+        };
+        assert!(matches!(
+            patch.decode::<String>(),
+            Err(PatchDecodeError::UnknownFormat(0xF))
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_patch_format() {
+        let val = "pollen".to_string();
+        let patch = BinaryPatch::encode(&val, Format::Json, Compression::None).unwrap();
+        assert_eq!(patch.format().unwrap(), Format::Json);
+        assert_eq!(patch.required(), Features::JSON);
+        assert_eq!(&patch.inner[1..], br#""pollen""#);
+        assert_eq!(patch.decode::<String>().unwrap(), val);
+
+        let msg = PollinationMessage::Repair {
+            uuid: Uuid::from_u128(1),
+            id: IdTree::One,
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+            patch,
+        };
+        assert!(matches!(
+            msg.for_features(Features::NONE, &Limits::default()),
+            Err(PatchDecodeError::Unreadable(Format::Json))
+        ));
     }
 }
@@ -0,0 +1,211 @@
+use crate::envelope::Features;
+use std::borrow::Cow;
+use thiserror::Error;
+
+/// Payloads smaller than this are never worth compressing.
+pub(crate) const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Bits of the header byte naming the codec; `BinaryPatch` keeps its format
+/// in the rest.
+pub(crate) const CODEC_MASK: u8 = 0x0F;
+
+const HEADER_NONE: u8 = 0;
+#[cfg(feature = "zstd")]
+const HEADER_ZSTD: u8 = 1;
+#[cfg(feature = "lz4")]
+const HEADER_LZ4: u8 = 2;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// How `BinaryPatch` payloads are compressed.
+///
+/// Every payload starts with a header byte naming its codec, so nodes can
+/// decode each other's patches whatever they compress with themselves, as
+/// long as the codec's feature is enabled. Roll a codec out by enabling the
+/// feature everywhere first and only then switching nodes over to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// What a peer needs to decode payloads compressed this way.
+    pub fn feature(&self) -> Features {
+        match self {
+            Compression::None => Features::NONE,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Features::ZSTD,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Features::LZ4,
+        }
+    }
+}
+
+/// What a peer needs to decode `bytes`, judging by its header byte.
+pub(crate) fn required(bytes: &[u8]) -> Features {
+    match bytes.first().map(|header| header & CODEC_MASK) {
+        #[cfg(feature = "zstd")]
+        Some(HEADER_ZSTD) => Features::ZSTD,
+        #[cfg(feature = "lz4")]
+        Some(HEADER_LZ4) => Features::LZ4,
+        _ => Features::NONE,
+    }
+}
+
+/// Prefix `payload` with a header byte, compressing it if it is large enough.
+pub(crate) fn compress(payload: Vec<u8>, compression: Compression) -> Vec<u8> {
+    if payload.len() < COMPRESSION_THRESHOLD {
+        return with_header(HEADER_NONE, &payload);
+    }
+
+    match compression {
+        Compression::None => with_header(HEADER_NONE, &payload),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => match zstd::bulk::compress(&payload, ZSTD_LEVEL) {
+            Ok(compressed) => with_header(HEADER_ZSTD, &compressed),
+            Err(err) => {
+                warn!("zstd compression failed, sending uncompressed: {err}");
+                with_header(HEADER_NONE, &payload)
+            }
+        },
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => with_header(HEADER_LZ4, &lz4_flex::compress_prepend_size(&payload)),
+    }
+}
+
+/// Strip the header byte and decompress, refusing to produce more than
+/// `max_size` bytes. Uncompressed payloads are borrowed.
+pub(crate) fn decompress(bytes: &[u8], max_size: usize) -> Result<Cow<'_, [u8]>, CompressionError> {
+    let (&header, payload) = bytes.split_first().ok_or(CompressionError::Empty)?;
+    let too_large = CompressionError::TooLarge { max: max_size };
+    match header & CODEC_MASK {
+        HEADER_NONE if payload.len() > max_size => Err(too_large),
+        HEADER_NONE => Ok(Cow::Borrowed(payload)),
+        #[cfg(feature = "zstd")]
+        HEADER_ZSTD => {
+            use std::io::Read;
+
+            // Read one byte past the limit to tell "exactly max" from "more"
+            let mut out = Vec::new();
+            zstd::stream::read::Decoder::new(payload)
+                .and_then(|decoder| {
+                    decoder
+                        .take((max_size as u64).saturating_add(1))
+                        .read_to_end(&mut out)
+                })
+                .map_err(|err| CompressionError::Corrupt(err.to_string()))?;
+            if out.len() > max_size {
+                return Err(too_large);
+            }
+            Ok(Cow::Owned(out))
+        }
+        #[cfg(feature = "lz4")]
+        HEADER_LZ4 => {
+            // The size prefix is attacker controlled; check it before the
+            // decoder allocates for it
+            let size = payload
+                .first_chunk::<4>()
+                .map(|size| u32::from_le_bytes(*size) as usize)
+                .ok_or_else(|| CompressionError::Corrupt("missing size prefix".to_string()))?;
+            if size > max_size {
+                return Err(too_large);
+            }
+            lz4_flex::decompress_size_prepended(payload)
+                .map(Cow::Owned)
+                .map_err(|err| CompressionError::Corrupt(err.to_string()))
+        }
+        other => Err(CompressionError::Unsupported(other)),
+    }
+}
+
+fn with_header(header: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 1);
+    bytes.push(header);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("Patch is empty")]
+    Empty,
+
+    #[error("Patch compressed with unsupported codec {0}")]
+    Unsupported(u8),
+
+    #[error("Corrupt compressed patch: {0}")]
+    Corrupt(String),
+
+    #[error("Patch decompresses to more than {max} bytes")]
+    TooLarge { max: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_payload_uncompressed() {
+        let bytes = compress(vec![7; 16], Compression::default());
+        assert_eq!(bytes[0], HEADER_NONE);
+        assert_eq!(decompress(&bytes, usize::MAX).unwrap(), vec![7; 16]);
+    }
+
+    #[test]
+    fn test_uncompressed_too_large() {
+        let bytes = compress(vec![7; 16], Compression::default());
+        assert!(matches!(
+            decompress(&bytes, 15),
+            Err(CompressionError::TooLarge { max: 15 })
+        ));
+    }
+
+    #[test]
+    fn test_required() {
+        let bytes = compress(vec![7; 4 * COMPRESSION_THRESHOLD], Compression::None);
+        assert_eq!(required(&bytes), Features::NONE);
+        #[cfg(feature = "zstd")]
+        {
+            let bytes = compress(vec![7; 4 * COMPRESSION_THRESHOLD], Compression::Zstd);
+            assert_eq!(required(&bytes), Features::ZSTD);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_codec() {
+        assert!(matches!(
+            decompress(&[0x0A, 1, 2, 3], usize::MAX),
+            Err(CompressionError::Unsupported(0x0A))
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_roundtrip() {
+        let payload = vec![7; 4 * COMPRESSION_THRESHOLD];
+        let bytes = compress(payload.clone(), Compression::Zstd);
+        assert_eq!(bytes[0], HEADER_ZSTD);
+        assert!(bytes.len() < payload.len());
+        assert_eq!(decompress(&bytes, usize::MAX).unwrap(), payload);
+        assert!(matches!(
+            decompress(&bytes, payload.len() - 1),
+            Err(CompressionError::TooLarge { .. })
+        ));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4_roundtrip() {
+        let payload = vec![7; 4 * COMPRESSION_THRESHOLD];
+        let bytes = compress(payload.clone(), Compression::Lz4);
+        assert_eq!(bytes[0], HEADER_LZ4);
+        assert!(bytes.len() < payload.len());
+        assert_eq!(decompress(&bytes, usize::MAX).unwrap(), payload);
+    }
+}
@@ -17,6 +17,15 @@ impl<A> PeerInfo<A> {
             uuid,
         }
     }
+
+    /// A retired ID, waiting for a live peer to take it over.
+    pub(crate) fn dead(addr: A) -> Self {
+        Self {
+            addr,
+            status: PeerStatus::Dead,
+            uuid: Uuid::nil(),
+        }
+    }
 }
 
 impl<A: fmt::Display> fmt::Display for PeerInfo<A> {
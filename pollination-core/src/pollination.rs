@@ -1,8 +1,10 @@
 use crate::{
-    message::{BinaryPatch, PollinationMessage},
+    limits::{InvalidMessage, Limits},
+    message::{BinaryPatch, Compression, PatchDecodeError, PollinationMessage},
     peer_info::{PeerInfo, PeerStatus},
     propagativity::Propagativity,
-    reality_token::RealityToken,
+    reality_token::{RealityToken, Tokens},
+    serialization::Format,
 };
 use serde::{Deserialize, Serialize};
 use std::{cmp::Ordering, fmt};
@@ -10,15 +12,49 @@ use thiserror::Error;
 use treeclocks::{EventTree, IdTree, ItcMap, Patch};
 use uuid::Uuid;
 
+mod anti_entropy;
+mod compaction;
+mod defrag;
+mod merge;
+mod patch_cache;
 mod recycling;
+mod snapshot;
+
+pub use anti_entropy::{AntiEntropyStats, MapDigest};
+use compaction::Acks;
+pub use compaction::TimestampStats;
+use patch_cache::PatchCache;
+pub use patch_cache::PatchCacheStats;
+pub use recycling::RecycleError;
+pub use snapshot::NodeSnapshot;
+
+/// How a node enters the cluster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StartupMode {
+    /// Own the entire ID space, forming a new cluster that others join.
+    #[default]
+    Bootstrap,
+    /// Own nothing until a seed hands out an ID, so no throwaway reality is
+    /// created that later has to be merged away.
+    Join,
+}
 
 #[derive(Clone, Debug)]
 pub struct PollinationNode<A> {
     uuid: Uuid,
     propagativity: Propagativity,
-    reality_token: RealityToken,
+    reality_token: Tokens,
     core_map: ItcMap<PeerInfo<A>>,
     own_info: PeerInfo<A>,
+    patch_cache: PatchCache,
+    compression: Compression,
+    format: Format,
+    limits: Limits,
+    acks: Acks,
+    timestamp_stats: TimestampStats,
+    anti_entropy_stats: AntiEntropyStats,
+    /// Our timestamp when we last sent a digest, until a repair answers it.
+    awaiting_repair: Option<EventTree>,
 }
 
 impl<A> PollinationNode<A>
@@ -27,16 +63,47 @@ where
 {
     #[allow(unused)]
     pub fn new(uuid: Uuid, addr: A) -> Self {
+        Self::new_with_mode(uuid, addr, StartupMode::Bootstrap)
+    }
+
+    pub fn new_with_mode(uuid: Uuid, addr: A, mode: StartupMode) -> Self {
         let own_info = PeerInfo::new(uuid, addr);
-        let reality_token = RealityToken::new(uuid);
-        let mut core_map = ItcMap::new();
-        core_map.insert(IdTree::One, own_info.clone());
-        Self {
-            propagativity: Propagativity::Propagating(IdTree::One),
-            reality_token,
-            core_map,
-            uuid,
-            own_info,
+        match mode {
+            StartupMode::Bootstrap => {
+                let mut core_map = ItcMap::new();
+                core_map.insert(IdTree::One, own_info.clone());
+                let reality_token = Tokens::of([(&IdTree::One, &own_info)]);
+                Self {
+                    propagativity: Propagativity::Propagating(IdTree::One),
+                    reality_token,
+                    core_map,
+                    uuid,
+                    own_info,
+                    patch_cache: PatchCache::default(),
+                    compression: Compression::default(),
+                    format: Format::default(),
+                    limits: Limits::default(),
+                    acks: Acks::default(),
+                    timestamp_stats: TimestampStats::default(),
+                    anti_entropy_stats: AntiEntropyStats::default(),
+                    awaiting_repair: None,
+                }
+            }
+            StartupMode::Join => Self {
+                propagativity: Propagativity::Unknown,
+                reality_token: Tokens::default(),
+                core_map: ItcMap::new(),
+                uuid,
+                own_info,
+                patch_cache: PatchCache::default(),
+                compression: Compression::default(),
+                format: Format::default(),
+                limits: Limits::default(),
+                acks: Acks::default(),
+                timestamp_stats: TimestampStats::default(),
+                anti_entropy_stats: AntiEntropyStats::default(),
+                awaiting_repair: None,
+            },
         }
     }
 
@@ -52,8 +119,32 @@ where
         self.core_map.len()
     }
 
+    /// Advance our own component of the timestamp so peers see we are
+    /// alive.
+    ///
+    /// The membership is unchanged, so unlike `set_raw` the reality token is
+    /// only touched if the map did not hold our entry.
     pub fn bump(&mut self) {
-        // TODO: Optimized version
+        let Some(id) = self.id().cloned() else {
+            return;
+        };
+        let ours = self
+            .core_map
+            .get(&id)
+            .is_some_and(|info| info.uuid == self.uuid && info.status == self.own_info.status);
+        if !(ours && self.core_map.event(&id)) {
+            self.set_raw(self.own_info.clone());
+        }
+    }
+
+    pub fn addr(&self) -> &A {
+        &self.own_info.addr
+    }
+
+    /// Change the address gossiped for this node, e.g. once we learn how
+    /// peers actually reach us.
+    pub fn set_addr(&mut self, addr: A) {
+        self.own_info.addr = addr;
         self.set_raw(self.own_info.clone());
     }
 
@@ -68,7 +159,7 @@ where
     }
 
     pub fn reality_token(&self) -> RealityToken {
-        self.reality_token
+        self.reality_token.current()
     }
 
     pub fn uuid(&self) -> Uuid {
@@ -84,34 +175,159 @@ where
     }
 
     fn set_raw(&mut self, own_info: PeerInfo<A>) -> Option<()> {
-        let dead = matches!(own_info.status, PeerStatus::Dead);
-        let mut removals = self.core_map.insert(self.id()?.clone(), own_info);
-        for (_removed_id, info) in removals.drain(..) {
-            self.reality_token.push(info.uuid);
+        let id = self.id()?.clone();
+        self.insert(id, own_info);
+        Some(())
+    }
+
+    /// Insert an entry we own, keeping the reality token in step. Dead IDs
+    /// it only partly covers keep the rest, so it can still be reaped.
+    fn insert(&mut self, id: IdTree, info: PeerInfo<A>) {
+        self.reality_token.add(&id, &info);
+        let mut leftovers = vec![];
+        for (removed_id, removed) in self.core_map.insert(id.clone(), info) {
+            self.reality_token.remove(&removed_id, &removed);
+            let rest = defrag::subtract(&removed_id, &id);
+            if removed.status == PeerStatus::Dead && rest != IdTree::Zero {
+                leftovers.push((rest, removed));
+            }
         }
-        if !dead {
-            self.reality_token.push(self.uuid);
+        for (rest, removed) in leftovers {
+            self.insert(rest, removed);
         }
-        Some(())
     }
 
-    fn create_patch(&self, peer_ts: &EventTree) -> BinaryPatch {
-        let itc_patch: Patch<PeerInfo<A>> = self.core_map.diff(peer_ts);
-        BinaryPatch::new(itc_patch).expect("Error serializing patch")
+    /// Compress outgoing patches. Peers that did not negotiate the codec's
+    /// feature need them decompressed with
+    /// [`PollinationMessage::for_features`] before sending.
+    pub fn set_compression(&mut self, compression: Compression) {
+        if compression != self.compression {
+            // Cached patches were encoded with the old codec
+            self.patch_cache.clear();
+        }
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Encode outgoing patches in `format`. Like compressed ones, they can
+    /// only be sent to peers that negotiated the format's feature; unlike
+    /// them, they can not be converted back for peers that did not, so even
+    /// a join fails until a peer has negotiated. Florescence leaves patches
+    /// in bincode and only changes the envelope format.
+    pub fn set_format(&mut self, format: Format) {
+        if format != self.format {
+            self.patch_cache.clear();
+        }
+        self.format = format;
+    }
+
+    /// Bound what incoming messages may contain.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
 
-    /// NOTE: Not a clean swap; the new core has most information
-    /// wiped. This is a helper function to efficiently reset.
-    fn swap_cores(&mut self, mut other: PollinationNode<A>) -> PollinationNode<A> {
-        todo!()
-        /*
-        self.set_raw(PeerInfo::dead());
-        std::mem::swap(self, &mut other);
-        self.propagativity = Propagativity::Unknown;
-        self.core_map = ItcMap::new();
-        self.reality_token = RealityToken::zero();
-        other
-            */
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn patch_cache_stats(&self) -> PatchCacheStats {
+        self.patch_cache.stats()
+    }
+
+    pub fn anti_entropy_stats(&self) -> AntiEntropyStats {
+        self.anti_entropy_stats
+    }
+
+    pub fn timestamp_stats(&self) -> TimestampStats {
+        self.timestamp_stats.measure(self.timestamp())
+    }
+
+    /// Rebuild the core map with a minimal timestamp once every live peer
+    /// has acknowledged ours, so heartbeats stop growing with churn.
+    ///
+    /// Each compaction starts a new epoch whose timestamps are ahead of all
+    /// earlier ones, so it never looks like we went back in time. Peers
+    /// holding the same membership rebuild to the same timestamp and follow
+    /// as soon as they see it; the others catch up through regular patches.
+    pub fn compact(&mut self) -> bool {
+        if self.id().is_none() {
+            return false;
+        }
+        let live: Vec<Uuid> = self.peers_alive().map(|(_, info)| info.uuid).collect();
+        if !self.acks.all_acked(&live, self.core_map.timestamp()) {
+            return false;
+        }
+        let epoch = compaction::epoch(self.timestamp()) + 1;
+        let Ok(rebuilt) = compaction::rebuild(&self.core_map, epoch) else {
+            return false;
+        };
+        if compaction::nodes(rebuilt.timestamp()) >= compaction::nodes(self.timestamp()) {
+            return false;
+        }
+        debug!("Compacted timestamp to {}", rebuilt.timestamp());
+        self.core_map = rebuilt;
+        self.timestamp_stats.compactions += 1;
+        true
+    }
+
+    /// Take over a peer's compaction if `peer_ts` is what our own map
+    /// rebuilds to.
+    fn follow_compaction(&mut self, peer_ts: &EventTree) {
+        // Only a later, smaller epoch can be a compaction of our map
+        let epoch = compaction::epoch(peer_ts);
+        if epoch <= compaction::epoch(self.timestamp())
+            || compaction::nodes(peer_ts) >= compaction::nodes(self.timestamp())
+        {
+            return;
+        }
+        let Ok(rebuilt) = compaction::rebuild(&self.core_map, epoch) else {
+            return;
+        };
+        if rebuilt.timestamp() == peer_ts {
+            debug!("Following compaction to {peer_ts}");
+            self.core_map = rebuilt;
+            self.timestamp_stats.followed += 1;
+        }
+    }
+
+    fn create_patch(&self, peer_ts: &EventTree) -> Result<BinaryPatch, PollinationError> {
+        self.patch_cache
+            .get_or_try_insert_with(self.timestamp(), peer_ts, || {
+                let itc_patch: Patch<PeerInfo<A>> = self.core_map.diff(peer_ts);
+                BinaryPatch::encode(itc_patch, self.format, self.compression)
+            })
+            .map_err(PollinationError::PatchEncodeError)
+    }
+
+    /// Replace our view with a peer's full one after losing a reality skew.
+    /// Both saw the same events but disagree on who owns what, as when two
+    /// nodes reaped the same dead ID at once. Returns the view we gave up.
+    fn swap_cores(&mut self, patch: BinaryPatch) -> Result<PollinationNode<A>, PollinationError> {
+        let mut adopted = self.clone();
+        adopted.core_map = ItcMap::new();
+        adopted.reality_token = Tokens::default();
+        let patch = merge::PatchParts::new(patch.decode_within(&self.limits)?);
+        adopted.apply_patch_unchecked(patch.retain(|id, info| !self.buried(id, info)));
+        adopted.check_peer_ids()?;
+        adopted.retire_stale_ids();
+        // The peer may not have heard of every death we have
+        adopted.keep_dead(
+            self.peers()
+                .filter(|(_, info)| info.status == PeerStatus::Dead)
+                .map(|(id, info)| (id.clone(), info.clone())),
+        );
+
+        let kept = adopted
+            .id()
+            .and_then(|id| adopted.core_map.get(id))
+            .is_some_and(|info| info.uuid == self.uuid && info.status == PeerStatus::Healthy);
+        if !kept {
+            adopted.settle_overlap();
+        }
+        Ok(std::mem::replace(self, adopted))
     }
 
     fn propagate(&mut self) -> Option<IdTree> {
@@ -120,84 +336,148 @@ where
         Some(new_id)
     }
 
+    /// Merge a peer's patch. If that leaves us exactly where the peer is,
+    /// our reality tokens have to agree; after a concurrent merge we are
+    /// ahead of the peer and it catches up from us instead.
     fn apply_patch(
         &mut self,
+        peer_ts: &EventTree,
         peer_rt: RealityToken,
         patch: BinaryPatch,
     ) -> Result<(), PatchApplyError<A>> {
-        let patch: Patch<PeerInfo<A>> = patch.decode()?;
+        let patch = merge::PatchParts::new(patch.decode_within(&self.limits)?)
+            .retain(|id, info| !self.buried(id, info));
         // Apply patch on a clone to detect RealitySkew
         let mut self_clone = self.clone();
-        if self_clone.apply_patch_unchecked(patch) {
-            // Self was removed; assuming this is a reality skew (could also be a bug)
-            Err(PatchApplyError::RealitySkew(Box::new(self_clone)))
+        let self_removed = self_clone.apply_patch_unchecked(patch);
+        self_clone.check_peer_ids()?;
+        if self_removed {
+            self_clone.settle_overlap();
+        }
+        self_clone.retire_stale_ids();
+        if self_clone.timestamp() != peer_ts || self_clone.reality_token.like(&peer_rt) == peer_rt {
+            *self = self_clone;
+            Ok(())
         } else {
-            if self_clone.reality_token == peer_rt {
-                *self = self_clone;
-                Ok(())
-            } else {
-                Err(PatchApplyError::RealitySkew(Box::new(self_clone)))
-            }
+            Err(PatchApplyError::RealitySkew(Box::new(self_clone)))
         }
     }
 
     // Returns a bool of whether self was removed from the core_map
-    fn apply_patch_unchecked(&mut self, patch: Patch<PeerInfo<A>>) -> bool {
-        let (mut additions, mut removals) = self.core_map.apply(patch);
+    fn apply_patch_unchecked(&mut self, patch: merge::PatchParts<PeerInfo<A>>) -> bool {
+        let (mut additions, mut removals) = patch.apply_to(&mut self.core_map);
 
-        for (_, info) in additions.drain(..) {
-            self.reality_token.push(info.uuid);
+        for (id, info) in additions.drain(..) {
+            self.reality_token.add(&id, info);
         }
 
         let mut self_removed = false;
+        let mut dead = vec![];
         for (removed_id, info) in removals.drain(..) {
-            self.reality_token.push(info.uuid);
+            self.reality_token.remove(&removed_id, &info);
             if let Some(own_id) = self.id()
                 && *own_id == removed_id
             {
                 debug!("Applied patch removes own ID");
                 self_removed = true;
             }
+            if info.status == PeerStatus::Dead {
+                dead.push((removed_id, info));
+            }
         }
 
+        // The patch may only cover part of a dead ID
+        self.keep_dead(dead);
+
         self_removed
     }
 
-    fn apply_seed_patch(&mut self, patch: BinaryPatch) -> Result<(), PatchApplyError<A>> {
-        let mut new_core = self.clone();
-        new_core.core_map = ItcMap::new();
-        let patch: Patch<PeerInfo<A>> = patch.decode()?;
-        new_core.apply_patch_unchecked(patch);
-
-        let mut id_to_delete = None;
-        for (id, peer_info) in new_core.peers() {
-            if peer_info.uuid == self.uuid {
-                assert!(id_to_delete.is_none());
-                id_to_delete = Some(id);
+    /// Re-insert whatever part of `dead` IDs no entry covers any more, so
+    /// it can still be reaped, as `insert` does.
+    fn keep_dead(&mut self, dead: impl IntoIterator<Item = (IdTree, PeerInfo<A>)>) {
+        for (dead_id, info) in dead {
+            let rest = self
+                .core_map
+                .iter()
+                .fold(dead_id, |rest, (id, _)| defrag::subtract(&rest, id));
+            if rest != IdTree::Zero {
+                self.insert(rest, info);
             }
         }
+    }
 
-        if let Some(id) = id_to_delete {
-            // TODO: Rethink this logic
-            warn!("Seed patch for group already a member of; killing old ID");
-            todo!()
-            /*
-            new_core.propagativity = Propagativity::Propagating(id.clone());
-            new_core.set_raw(PeerInfo::dead());
-                */
+    fn apply_seed_patch(&mut self, patch: BinaryPatch) -> Result<(), PollinationError> {
+        let parts = merge::PatchParts::new(patch.decode_within(&self.limits)?);
+        // Keep what we learned before losing our ID, e.g. deaths we
+        // witnessed, unless it contradicts the seed's view
+        let mut merged = self.clone();
+        merged.apply_patch_unchecked(parts.clone().retain(|id, info| !self.buried(id, info)));
+        if merged.check_peer_ids().is_ok() {
+            merged.retire_stale_ids();
+            *self = merged;
+            return Ok(());
         }
 
+        let mut new_core = self.clone();
+        new_core.core_map = ItcMap::new();
+        new_core.reality_token = Tokens::default();
+        new_core.apply_patch_unchecked(parts);
+        new_core.check_peer_ids()?;
+        new_core.retire_stale_ids();
         *self = new_core;
 
         Ok(())
     }
 
+    /// Whether `info` is a live entry of a peer we know died within `id`.
+    /// Such an entry predates the death, however the clocks compare; only
+    /// an entry for whoever took the dead ID over can replace ours.
+    fn buried(&self, id: &IdTree, info: &PeerInfo<A>) -> bool {
+        info.status == PeerStatus::Healthy
+            && self.peers().any(|(dead_id, dead)| {
+                dead.status == PeerStatus::Dead
+                    && dead.uuid == info.uuid
+                    && recycling::overlaps(dead_id, id)
+            })
+    }
+
+    /// Kill entries of ours that a peer's view still lists besides our
+    /// current ID, e.g. ones we held before losing them or restarting.
+    /// Nobody else will reclaim them while they look alive.
+    fn retire_stale_ids(&mut self) {
+        let own_id = self.id().cloned().unwrap_or(IdTree::Zero);
+        let stale: Vec<IdTree> = self
+            .peers()
+            .filter(|(id, info)| {
+                info.uuid == self.uuid
+                    && info.status == PeerStatus::Healthy
+                    && !recycling::overlaps(id, &own_id)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stale {
+            warn!("Peers still list our old Id {id}; marking it dead");
+            self.insert(id, PeerInfo::dead(self.own_info.addr.clone()));
+        }
+    }
+
+    /// Take over the IDs of dead peers next to our own. A corrupted view of
+    /// the ID space is logged and leaves our ID untouched.
     pub fn reap_souls(&mut self) -> bool {
-        self.reap_souls_inner().is_some()
+        match self.reap_souls_inner() {
+            Ok(reaped) => reaped,
+            Err(err) => {
+                warn!("Not reclaiming IDs: {err}");
+                false
+            }
+        }
     }
 
-    fn reap_souls_inner(&mut self) -> Option<()> {
-        let dead_peers: IdTree = self
+    fn reap_souls_inner(&mut self) -> Result<bool, RecycleError> {
+        let Some(own_id) = self.id().cloned() else {
+            return Ok(false);
+        };
+        let dead_peers = self
             .peers()
             .filter_map(|(peer_id, peer_info)| {
                 // TODO: How to calculate timed-out peers?
@@ -207,31 +487,146 @@ where
                     None
                 }
             })
-            .reduce(|acc, id| acc.join(id))?;
+            .reduce(|acc, id| acc.join(id));
+        let Some(dead_peers) = dead_peers else {
+            return Ok(false);
+        };
 
-        let new_id = recycling::claim_ids(self.id()?.clone(), dead_peers);
+        let new_id = recycling::claim_ids(own_id.clone(), dead_peers)?;
 
-        if &new_id != self.id()? {
+        if new_id != own_id {
             debug!("Reclaimed Id: {new_id}");
+            // Claiming can move us into a shallower slot; what we leave
+            // behind is reaped like any other dead ID
+            let abandoned = defrag::subtract(&own_id, &new_id);
+            if abandoned != IdTree::Zero {
+                self.insert(abandoned, PeerInfo::dead(self.own_info.addr.clone()));
+            }
             self.propagativity = Propagativity::Propagating(new_id);
             self.set_raw(self.own_info.clone());
-            Some(())
+            if cfg!(debug_assertions) {
+                self.check_invariants()?;
+            }
+            Ok(true)
         } else {
             debug!("No Reclaim");
-            None
+            Ok(false)
+        }
+    }
+
+    /// Take one step towards fewer, shallower ID fragments across the
+    /// cluster, beyond the dead IDs `reap_souls` can reach.
+    ///
+    /// Stranded dead IDs are adopted by one of the live peers bordering them.
+    /// Spare fragments of our own ID are retired as dead and handed off to
+    /// the peer owning their sibling, who merges the two. If the handoff is
+    /// lost the fragment is still reaped like any other dead ID.
+    pub fn defragment(&mut self) -> Defrag<A> {
+        let Some(own_id) = self.id().cloned() else {
+            return Defrag::Idle;
+        };
+        match defrag::plan(&own_id, self.uuid, self.peers()) {
+            None => Defrag::Idle,
+            Some(defrag::Step::Adopt(dead_id)) => {
+                debug!("Adopting dead Id: {dead_id}");
+                self.absorb(dead_id);
+                Defrag::Adopted
+            }
+            Some(defrag::Step::HandOff { fragment, to }) => {
+                debug!("Handing off Id fragment: {fragment}");
+                self.retire(&own_id, fragment.clone());
+                match self.msg_handoff(fragment) {
+                    Some(msg) => Defrag::HandedOff { to, msg },
+                    None => Defrag::Idle,
+                }
+            }
         }
     }
 
-    fn check_no_dupes(&self) {
-        let mut dupe_checker = std::collections::HashSet::new();
-        for (_, peer) in self.peers() {
-            let uuid = peer.uuid;
-            if dupe_checker.contains(&uuid) && uuid != Uuid::from_u128(0) {
-                panic!("DUPE with {uuid}");
+    /// Depth of the deepest ID in our view of the cluster, dead or alive.
+    pub fn max_id_depth(&self) -> usize {
+        self.peers()
+            .map(|(id, _)| defrag::depth(id))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Merge `dead_id` into our own ID, replacing its entry.
+    fn absorb(&mut self, dead_id: IdTree) {
+        let Some(own_id) = self.id().cloned() else {
+            return;
+        };
+        self.propagativity.set_id(own_id.join(dead_id));
+        self.set_raw(self.own_info.clone());
+    }
+
+    /// Mark `fragment` of our ID as dead and stop owning it.
+    fn retire(&mut self, own_id: &IdTree, fragment: IdTree) {
+        let dead = PeerInfo::dead(self.own_info.addr.clone());
+        let remaining = defrag::subtract(own_id, &fragment);
+        self.insert(fragment, dead);
+        self.propagativity.set_id(remaining);
+        self.set_raw(self.own_info.clone());
+    }
+
+    /// Our entry was replaced by others claiming part of our ID, e.g. when
+    /// two peers reaped the same dead ID. Entries with a lower UUID keep
+    /// what they claimed, which includes dead markers; we take back the
+    /// rest, and rejoin if nothing is left.
+    fn settle_overlap(&mut self) {
+        let Some(own_id) = self.id().cloned() else {
+            return;
+        };
+        let yielded = self
+            .peers()
+            .filter(|(id, info)| {
+                (info.status == PeerStatus::Dead || info.uuid < self.uuid)
+                    && recycling::overlaps(id, &own_id)
+            })
+            .fold(IdTree::Zero, |acc, (id, _)| acc.join(id.clone()));
+        let remaining = defrag::subtract(&own_id, &yielded);
+        if remaining == IdTree::Zero {
+            // Keep our view; the seed merges into it
+            warn!("Lost our whole ID to peers; rejoining");
+            self.propagativity = Propagativity::Unknown;
+        } else {
+            debug!("Yielded {yielded}; keeping {remaining}");
+            // A stale entry of ours may reach beyond our ID, e.g. over a
+            // fragment we retired; that part must stay reapable
+            let beyond: Vec<IdTree> = self
+                .peers()
+                .filter(|(id, info)| {
+                    info.uuid == self.uuid
+                        && info.status == PeerStatus::Healthy
+                        && recycling::overlaps(id, &own_id)
+                })
+                .map(|(id, _)| defrag::subtract(id, &own_id))
+                .filter(|rest| *rest != IdTree::Zero)
+                .collect();
+            self.propagativity.set_id(remaining);
+            self.set_raw(self.own_info.clone());
+            for rest in beyond {
+                self.insert(rest, PeerInfo::dead(self.own_info.addr.clone()));
             }
+        }
+    }
 
-            dupe_checker.insert(uuid);
+    /// Check that no two live peers own overlapping IDs. Runs after every
+    /// reclaim in debug builds.
+    pub fn check_invariants(&self) -> Result<(), RecycleError> {
+        let live = self
+            .peers()
+            .filter(|(_, info)| info.status != PeerStatus::Dead)
+            .map(|(id, _)| id);
+        recycling::check_disjoint(live)
+    }
+
+    /// IDs arriving in patches are as untrusted as those in messages.
+    fn check_peer_ids(&self) -> Result<(), InvalidMessage> {
+        for (id, _) in self.peers() {
+            self.limits.check_id(id)?;
         }
+        Ok(())
     }
 
     /* Message Handling */
@@ -240,38 +635,86 @@ where
         &mut self,
         message: PollinationMessage,
     ) -> Result<PollinationResponse<A>, PollinationError> {
+        message.validate(&self.limits)?;
+        if let Some(timestamp) = message.timestamp() {
+            self.acks.record(message.uuid(), timestamp);
+            self.follow_compaction(timestamp);
+        }
+
         use PollinationMessage::*;
         match message {
-            Heartbeat { .. } => Ok(self.handle_heartbeat(message).into()),
-
-            Update { .. } => Ok(self.handle_update(message)?.into()),
-
-            RealitySkew { .. } => self.handle_reality_skew(message),
-
-            NewMember { .. } => Ok(self.handle_new_member(message).into()),
-
-            Seed { .. } => self.handle_seed(message),
+            Heartbeat {
+                timestamp,
+                reality_token,
+                ..
+            } => Ok(self.handle_heartbeat(timestamp, reality_token)?.into()),
+
+            Update {
+                timestamp,
+                reality_token,
+                patch,
+                ..
+            } => Ok(self.handle_update(timestamp, reality_token, patch)?.into()),
+
+            RealitySkew {
+                timestamp,
+                reality_token,
+                peer_count,
+                patch,
+                ..
+            } => self.handle_reality_skew(timestamp, reality_token, peer_count, patch),
+
+            NewMember { .. } => Ok(self.handle_new_member()?.into()),
+
+            Seed {
+                timestamp,
+                patch,
+                new_id,
+                ..
+            } => self.handle_seed(timestamp, patch, new_id),
+
+            Handoff {
+                timestamp,
+                reality_token,
+                fragment,
+                ..
+            } => Ok(self
+                .handle_handoff(timestamp, reality_token, fragment)?
+                .into()),
+
+            Digest {
+                timestamp,
+                reality_token,
+                digest,
+                ..
+            } => Ok(self.handle_digest(timestamp, reality_token, digest)?.into()),
+
+            Repair {
+                timestamp,
+                reality_token,
+                patch,
+                ..
+            } => Ok(self.handle_repair(timestamp, reality_token, patch)?.into()),
         }
     }
 
-    fn handle_heartbeat(&self, message: PollinationMessage) -> Option<PollinationMessage> {
-        let PollinationMessage::Heartbeat {
-            timestamp: peer_ts,
-            reality_token: peer_rt,
-            ..
-        } = message
-        else {
-            unreachable!()
-        };
-
+    fn handle_heartbeat(
+        &self,
+        peer_ts: EventTree,
+        peer_rt: RealityToken,
+    ) -> Result<Option<PollinationMessage>, PollinationError> {
+        if self.id().is_none() {
+            // Lost our ID or never had one; any member can seed us
+            return Ok(self.msg_new_member());
+        }
         match self.timestamp().partial_cmp(&peer_ts) {
             Some(Ordering::Greater) | None => self.msg_update(&peer_ts),
-            Some(Ordering::Less) => self.msg_heartbeat(),
+            Some(Ordering::Less) => Ok(self.msg_heartbeat()),
             Some(Ordering::Equal) => {
-                if peer_rt != self.reality_token {
-                    self.msg_reality_skew(&peer_ts)
+                if peer_rt != self.reality_token.like(&peer_rt) {
+                    self.msg_reality_skew()
                 } else {
-                    None
+                    Ok(None)
                 }
             }
         }
@@ -279,30 +722,22 @@ where
 
     fn handle_update(
         &mut self,
-        message: PollinationMessage,
+        peer_ts: EventTree,
+        peer_rt: RealityToken,
+        peer_patch: BinaryPatch,
     ) -> Result<Option<PollinationMessage>, PollinationError> {
-        let PollinationMessage::Update {
-            timestamp: peer_ts,
-            reality_token: peer_rt,
-            patch: peer_patch,
-            ..
-        } = message
-        else {
-            unreachable!()
-        };
-
         match self.timestamp().partial_cmp(&peer_ts) {
-            Some(Ordering::Greater) => Ok(self.msg_update(&peer_ts)),
+            Some(Ordering::Greater) => self.msg_update(&peer_ts),
 
-            Some(Ordering::Less) | None => match self.apply_patch(peer_rt, peer_patch) {
-                Ok(()) => Ok(self.msg_heartbeat()),
-                Err(PatchApplyError::RealitySkew(_)) => Ok(self.msg_reality_skew(&peer_ts)),
+            Some(Ordering::Less) | None => match self.apply_patch(&peer_ts, peer_rt, peer_patch) {
+                Ok(()) => Ok(self.msg_heartbeat_or_join()),
+                Err(PatchApplyError::RealitySkew(_)) => self.msg_reality_skew(),
                 Err(err) => Err(err.into()),
             },
 
             Some(Ordering::Equal) => {
-                if peer_rt != self.reality_token {
-                    Ok(self.msg_reality_skew(&peer_ts))
+                if peer_rt != self.reality_token.like(&peer_rt) {
+                    self.msg_reality_skew()
                 } else {
                     Ok(None)
                 }
@@ -310,64 +745,52 @@ where
         }
     }
 
+    /// The peer disagrees with us on who owns what. Whoever has seen less
+    /// takes over the other's view; on a tie the larger view wins.
     fn handle_reality_skew(
         &mut self,
-        message: PollinationMessage,
+        peer_ts: EventTree,
+        peer_rt: RealityToken,
+        peer_count: usize,
+        peer_patch: BinaryPatch,
     ) -> Result<PollinationResponse<A>, PollinationError> {
-        let PollinationMessage::RealitySkew {
-            timestamp: peer_ts,
-            reality_token: peer_rt,
-            peer_count,
-            patch: peer_patch,
-            ..
-        } = message
-        else {
-            unreachable!()
-        };
-
-        match self.apply_patch(peer_rt, peer_patch) {
-            Ok(()) => Ok(PollinationResponse::response(self.msg_heartbeat())),
-            Err(PatchApplyError::RealitySkew(core)) => {
-                if peer_count > self.peer_count()
-                    || peer_count == self.peer_count() && peer_rt > self.reality_token
+        let behind = self.timestamp() < &peer_ts;
+        match self.apply_patch(&peer_ts, peer_rt, peer_patch.clone()) {
+            // The peer could not merge what only we have seen
+            Ok(()) if *self.timestamp() != peer_ts => {
+                Ok(PollinationResponse::response(self.msg_reality_skew()?))
+            }
+            Ok(()) => Ok(PollinationResponse::response(self.msg_heartbeat_or_join())),
+            Err(PatchApplyError::RealitySkew(_)) => {
+                if behind
+                    || peer_count > self.peer_count()
+                    || peer_count == self.peer_count()
+                        && peer_rt > self.reality_token.like(&peer_rt)
                 {
-                    let old_core = self.swap_cores(*core);
+                    let old_core = self.swap_cores(peer_patch)?;
                     Ok(PollinationResponse::core_dump(
-                        self.msg_new_member(),
+                        self.msg_heartbeat_or_join(),
                         old_core,
                     ))
                 } else {
-                    Ok(PollinationResponse::response(
-                        self.msg_reality_skew(&peer_ts),
-                    ))
+                    Ok(PollinationResponse::response(self.msg_reality_skew()?))
                 }
             }
-            Err(PatchApplyError::DeserializationError(err)) => {
-                Err(PollinationError::DeserializationError(err))
-            }
+            Err(err) => Err(err.into()),
         }
     }
 
-    fn handle_new_member(&mut self, _message: PollinationMessage) -> Option<PollinationMessage> {
+    fn handle_new_member(&mut self) -> Result<Option<PollinationMessage>, PollinationError> {
         let new_id = self.propagate();
         self.msg_seed(new_id)
     }
 
     fn handle_seed(
         &mut self,
-        message: PollinationMessage,
+        peer_ts: EventTree,
+        peer_patch: BinaryPatch,
+        new_id: Option<IdTree>,
     ) -> Result<PollinationResponse<A>, PollinationError> {
-        let PollinationMessage::Seed {
-            timestamp: peer_ts,
-            reality_token: _peer_rt,
-            patch: peer_patch,
-            new_id,
-            ..
-        } = message
-        else {
-            unreachable!()
-        };
-
         // Only reset ourselves if we have no ID
         if self.propagativity.id().is_some() {
             return Ok(PollinationResponse::response(self.msg_heartbeat()));
@@ -377,45 +800,192 @@ where
         if let Some(id) = new_id {
             self.propagativity = Propagativity::resting(id);
             self.set_raw(self.own_info.clone());
-            self.check_no_dupes();
-            Ok(PollinationResponse::response(self.msg_update(&peer_ts)))
+            Ok(PollinationResponse::response(self.msg_update(&peer_ts)?))
         } else {
             Ok(PollinationResponse::response(None))
         }
     }
 
+    /// Merge the fragment if we already know it is dead; otherwise sync first
+    /// and leave it to `defragment` to adopt.
+    fn handle_handoff(
+        &mut self,
+        peer_ts: EventTree,
+        peer_rt: RealityToken,
+        fragment: IdTree,
+    ) -> Result<Option<PollinationMessage>, PollinationError> {
+        // Only the owner of the whole sibling can merge the two into their
+        // parent; anyone else would end up with a deeper ID
+        let mergeable = self
+            .id()
+            .zip(defrag::sibling_of(&fragment))
+            .is_some_and(|(own_id, sibling)| defrag::covers(own_id, &sibling));
+        let retired = self
+            .peers()
+            .any(|(id, info)| info.status == PeerStatus::Dead && *id == fragment);
+        if mergeable && retired {
+            self.absorb(fragment);
+            self.msg_update(&peer_ts)
+        } else {
+            self.handle_heartbeat(peer_ts, peer_rt)
+        }
+    }
+
+    /// Compare contents with a peer that has the same timestamp and reality
+    /// token. On a mismatch the side with the larger digest sends its full
+    /// state, so both agree on who repairs whom.
+    fn handle_digest(
+        &mut self,
+        peer_ts: EventTree,
+        peer_rt: RealityToken,
+        peer_digest: MapDigest,
+    ) -> Result<Option<PollinationMessage>, PollinationError> {
+        if *self.timestamp() != peer_ts || self.reality_token.like(&peer_rt) != peer_rt {
+            // Not converged; regular gossip comes first
+            return self.handle_heartbeat(peer_ts, peer_rt);
+        }
+
+        self.anti_entropy_stats.rounds += 1;
+        let digest = anti_entropy::digest(&self.core_map);
+        match digest.cmp(&peer_digest) {
+            Ordering::Equal => Ok(None),
+            Ordering::Greater => {
+                self.anti_entropy_stats.divergences += 1;
+                warn!("Core map diverged from a converged peer; sending repair");
+                self.msg_repair()
+            }
+            Ordering::Less => {
+                self.anti_entropy_stats.divergences += 1;
+                warn!("Core map diverged from a converged peer; asking for repair");
+                Ok(self.msg_digest())
+            }
+        }
+    }
+
+    /// Replace our map with the peer's full state, then bump our own entry
+    /// so whatever we hold about ourselves wins again.
+    ///
+    /// Only a repair answering our last digest is taken, and only if neither
+    /// side has moved on since; anything else could replace newer entries.
+    fn handle_repair(
+        &mut self,
+        peer_ts: EventTree,
+        peer_rt: RealityToken,
+        peer_patch: BinaryPatch,
+    ) -> Result<Option<PollinationMessage>, PollinationError> {
+        if self.id().is_none() {
+            return Ok(None);
+        }
+        if peer_ts != *self.timestamp() || self.awaiting_repair.as_ref() != Some(&peer_ts) {
+            debug!("Ignoring a repair we did not ask for");
+            return Ok(None);
+        }
+        self.awaiting_repair = None;
+
+        let patch: Patch<PeerInfo<A>> = peer_patch.decode_within(&self.limits)?;
+        let mut repaired = self.clone();
+        repaired.core_map = ItcMap::new();
+        repaired.core_map.apply(patch);
+        repaired.check_peer_ids()?;
+        repaired.reality_token = Tokens::of(repaired.core_map.iter());
+        if repaired.reality_token.like(&peer_rt) != peer_rt {
+            warn!("Repair does not match the sender's reality token; ignoring");
+            return Ok(None);
+        }
+
+        *self = repaired;
+        self.anti_entropy_stats.repairs += 1;
+        // The sender's copy of our entry may be stale
+        self.set_raw(self.own_info.clone());
+        Ok(self.msg_heartbeat())
+    }
+
     pub fn msg_heartbeat(&self) -> Option<PollinationMessage> {
         let id = self.id()?.clone();
         Some(PollinationMessage::Heartbeat {
             uuid: self.uuid,
             id,
             timestamp: self.timestamp().to_owned(),
-            reality_token: self.reality_token,
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
         })
     }
 
-    pub fn msg_update(&self, peer_ts: &EventTree) -> Option<PollinationMessage> {
-        let id = self.id()?.clone();
-        let patch = self.create_patch(peer_ts);
-        Some(PollinationMessage::Update {
+    /// A heartbeat, or a request for a new ID if we lost ours.
+    fn msg_heartbeat_or_join(&self) -> Option<PollinationMessage> {
+        self.msg_heartbeat().or_else(|| self.msg_new_member())
+    }
+
+    pub fn msg_update(
+        &self,
+        peer_ts: &EventTree,
+    ) -> Result<Option<PollinationMessage>, PollinationError> {
+        let Some(id) = self.id() else {
+            return Ok(None);
+        };
+        Ok(Some(PollinationMessage::Update {
             uuid: self.uuid,
-            id,
+            id: id.clone(),
             timestamp: self.timestamp().to_owned(),
-            reality_token: self.reality_token,
-            patch,
-        })
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
+            patch: self.create_patch(peer_ts)?,
+        }))
     }
 
-    fn msg_reality_skew(&self, peer_ts: &EventTree) -> Option<PollinationMessage> {
-        let id = self.id()?.clone();
-        let patch = self.create_patch(peer_ts);
-        Some(PollinationMessage::RealitySkew {
+    fn msg_reality_skew(&self) -> Result<Option<PollinationMessage>, PollinationError> {
+        let Some(id) = self.id() else {
+            return Ok(None);
+        };
+        Ok(Some(PollinationMessage::RealitySkew {
             uuid: self.uuid,
-            id,
+            id: id.clone(),
             timestamp: self.timestamp().to_owned(),
-            reality_token: self.reality_token,
-            patch,
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
+            // The loser of the skew takes over our whole view
+            patch: self.create_patch(&EventTree::Leaf(0))?,
             peer_count: self.core_map.len(),
+        }))
+    }
+
+    /// Anti-entropy probe, to send to a peer every so often. Only a repair
+    /// answering the latest probe is applied.
+    pub fn msg_digest(&mut self) -> Option<PollinationMessage> {
+        let msg = PollinationMessage::Digest {
+            uuid: self.uuid,
+            id: self.id()?.clone(),
+            timestamp: self.timestamp().to_owned(),
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
+            digest: anti_entropy::digest(&self.core_map),
+        };
+        self.awaiting_repair = Some(self.timestamp().clone());
+        Some(msg)
+    }
+
+    fn msg_repair(&self) -> Result<Option<PollinationMessage>, PollinationError> {
+        let Some(id) = self.id() else {
+            return Ok(None);
+        };
+        Ok(Some(PollinationMessage::Repair {
+            uuid: self.uuid,
+            id: id.clone(),
+            timestamp: self.timestamp().to_owned(),
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
+            patch: self.create_patch(&EventTree::Leaf(0))?,
+        }))
+    }
+
+    fn msg_handoff(&self, fragment: IdTree) -> Option<PollinationMessage> {
+        Some(PollinationMessage::Handoff {
+            uuid: self.uuid,
+            id: self.id()?.clone(),
+            timestamp: self.timestamp().to_owned(),
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
+            fragment,
         })
     }
 
@@ -423,18 +993,23 @@ where
         Some(PollinationMessage::NewMember { uuid: self.uuid })
     }
 
-    fn msg_seed(&self, new_id: Option<IdTree>) -> Option<PollinationMessage> {
-        let id = self.id()?.clone();
-        let patch = self.create_patch(&EventTree::Leaf(0));
-        Some(PollinationMessage::Seed {
+    fn msg_seed(
+        &self,
+        new_id: Option<IdTree>,
+    ) -> Result<Option<PollinationMessage>, PollinationError> {
+        let Some(id) = self.id() else {
+            return Ok(None);
+        };
+        Ok(Some(PollinationMessage::Seed {
             uuid: self.uuid,
-            id,
+            id: id.clone(),
             timestamp: self.timestamp().to_owned(),
-            reality_token: self.reality_token,
-            patch,
+            reality_token: self.reality_token.current(),
+            legacy_token: Some(self.reality_token.legacy()),
+            patch: self.create_patch(&EventTree::Leaf(0))?,
             peer_count: self.core_map.len(),
             new_id,
-        })
+        }))
     }
 }
 
@@ -446,11 +1021,28 @@ where
         write!(
             f,
             "UUID:{} ID:{} RT:{} OWN_INFO:({}) CORE_MAP:({})",
-            self.uuid, self.propagativity, self.reality_token, self.own_info, self.core_map
+            self.uuid,
+            self.propagativity,
+            self.reality_token.current(),
+            self.own_info,
+            self.core_map
         )
     }
 }
 
+/// The outcome of [`PollinationNode::defragment`].
+#[derive(Debug)]
+pub enum Defrag<A> {
+    Idle,
+    /// Our ID grew; gossip it.
+    Adopted,
+    /// Send `msg` to `to` so it can merge the fragment we gave up.
+    HandedOff {
+        to: A,
+        msg: PollinationMessage,
+    },
+}
+
 pub struct PollinationResponse<A> {
     pub response: Option<PollinationMessage>,
     pub old_core: Option<PollinationNode<A>>,
@@ -486,17 +1078,25 @@ pub enum PollinationError {
     #[error("Deserialization error: {0}")]
     DeserializationError(#[from] crate::serialization::DeserializeError),
 
+    #[error("Patch decode error: {0}")]
+    PatchDecodeError(#[from] PatchDecodeError),
+
+    #[error("Patch encode error: {0}")]
+    PatchEncodeError(crate::serialization::SerializeError),
+
     #[error("Patch application error")]
     PatchApplyError,
+
+    #[error("Invalid message: {0}")]
+    InvalidMessage(#[from] InvalidMessage),
 }
 
 impl<A> From<PatchApplyError<A>> for PollinationError {
     fn from(value: PatchApplyError<A>) -> PollinationError {
         match value {
-            PatchApplyError::RealitySkew(_) => unreachable!(),
-            PatchApplyError::DeserializationError(err) => {
-                PollinationError::DeserializationError(err)
-            }
+            PatchApplyError::RealitySkew(_) => PollinationError::PatchApplyError,
+            PatchApplyError::DeserializationError(err) => PollinationError::PatchDecodeError(err),
+            PatchApplyError::InvalidMessage(err) => PollinationError::InvalidMessage(err),
         }
     }
 }
@@ -507,161 +1107,585 @@ pub enum PatchApplyError<A> {
     RealitySkew(Box<PollinationNode<A>>),
 
     #[error("Deserialization error: {0}")]
-    DeserializationError(#[from] crate::serialization::DeserializeError),
+    DeserializationError(#[from] PatchDecodeError),
+
+    #[error("Invalid patch: {0}")]
+    InvalidMessage(#[from] InvalidMessage),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::engine::Engine;
-    use tracing_test::traced_test;
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::IndexedRandom};
 
     #[test]
-    #[traced_test]
     fn test_nucleus() {
-        // BAD SEEDS = [
-        //      16254458854126421037  w/ 3
-        // ]
-        for _ in 0..40 {
-            let seed = rand::thread_rng().random();
-            println!(
-                "\n\n================== NEW SIMULATION WITH SEED {seed} =======================\n"
+        for seed in 0..40 {
+            assert_eq!(
+                test_nucleus_inner(seed),
+                IdTree::One,
+                "failed to clean up with seed {seed}"
             );
-            let id_tree = test_nucleus_inner(seed);
-            if id_tree != IdTree::one() {
-                println!("FAILED TO CLEAN UP WITH SEED {seed}, GOT TO {id_tree}");
-                //panic!("Test failure");
-            }
         }
     }
 
+    /// Random joins, deaths and gossip, then gossip until the survivors
+    /// agree. Returns the ID space they own between them.
     fn test_nucleus_inner(seed: u64) -> IdTree {
         let mut rng = StdRng::seed_from_u64(seed);
-
-        let count = 5;
-        let mut nuclei = (0..count)
-            .map(|i| PollinationNode::new(Uuid::from_u128(rng.random()), i))
-            .collect::<Vec<_>>();
-
-        for _ in 0..1000 {
-            let mut idxs = (0..count).collect::<Vec<_>>();
-            idxs.shuffle(&mut rng);
-            for i in idxs {
-                // Includes Self but that is okay and we should test that fact.
-                let j = rng.gen_range(0..count);
-
-                println!("SYNC {i} -> {j}");
-                let sync_res = sync_two(&mut rng, &mut nuclei, i, j);
-                println!("END\n");
-                if let Some(old_core) = sync_res {
-                    println!("NOTIFYING KIN");
-                    notify_of_death(&mut rng, &mut nuclei, old_core);
-                    println!("END\n");
+        let mut cluster = Cluster::with_drops(seed);
+
+        for _ in 0..200 {
+            let live = cluster.live();
+            let i = *live.choose(&mut rng).unwrap();
+            match rng.random_range(0..10) {
+                0..2 if live.len() < 12 => cluster.join(i),
+                // Only members announce their death, and only to a member
+                // that can pass it on; timeouts are not modelled
+                2 if cluster.nodes[i].id().is_some() => {
+                    let others: Vec<usize> =
+                        cluster.members().into_iter().filter(|&j| j != i).collect();
+                    if let Some(&witness) = others.choose(&mut rng) {
+                        cluster.kill(i, witness);
+                    }
+                }
+                _ => {
+                    // Includes self but that is okay and we should test that fact
+                    let j = *live.choose(&mut rng).unwrap();
+                    cluster.gossip(i, j);
+                    cluster.tidy(i);
                 }
+            }
+        }
 
-                for i in 0..count {
-                    println!("STATE {i}: {}", nuclei[i]);
-                    // Reset propagativity so we don't have to wait
-                    nuclei[i].propagativity.set_propagating();
+        cluster.settle()
+    }
 
-                    // Also do any cleanup
-                    // TODO: Test delayed cleanup
-                    if nuclei[i].reap_souls() {
-                        println!("{i} REAPED SOULS");
-                    }
+    /// Nodes coming and going for a long time, a few at a time, must not
+    /// leave IDs ever deeper behind.
+    #[test]
+    fn test_churn_depth_bounded() {
+        // Depth of a balanced tree of `n` IDs
+        let balanced = |n: usize| n.next_power_of_two().trailing_zeros() as usize;
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut cluster = Cluster::new();
+
+        for _ in 0..400 {
+            let members = cluster.members();
+            let i = *members.choose(&mut rng).unwrap();
+            if cluster.live().len() < 8 {
+                cluster.join(i);
+            } else {
+                let others: Vec<usize> = members.into_iter().filter(|&j| j != i).collect();
+                if let Some(&witness) = others.choose(&mut rng) {
+                    cluster.kill(i, witness);
                 }
-
-                println!("");
             }
-
-            let mut iter = nuclei.iter().map(|n| n.reality_token);
-            let first = iter.next().unwrap();
-            if iter.all(|rt| rt == first) {
-                println!("CONVERGED");
-                break;
+            for _ in 0..8 {
+                let live = cluster.live();
+                let i = *live.choose(&mut rng).unwrap();
+                let j = *live.choose(&mut rng).unwrap();
+                cluster.gossip(i, j);
+                cluster.tidy(i);
             }
+
+            // Seeds are picked at random, so IDs only stay within a constant
+            // factor of balanced, plus handoffs still in flight
+            let live = cluster.live().len();
+            let depth = cluster.max_id_depth();
+            assert!(
+                depth <= 2 * balanced(live) + 8,
+                "IDs reached depth {depth} with {live} live"
+            );
         }
 
-        println!("END SIMULATION");
-        let mut summed_ids = IdTree::zero();
-        for i in 0..count {
-            println!("END STATE {i}: {}", nuclei[i]);
-            if let Some(id) = nuclei[i].id() {
-                summed_ids = summed_ids.join(id.to_owned());
-            }
+        assert_eq!(cluster.settle(), IdTree::One);
+        let live = cluster.live().len();
+        assert!(cluster.max_id_depth() <= balanced(live) + 3);
+    }
+
+    #[test]
+    fn test_join_mode() {
+        let mut seed = PollinationNode::new(Uuid::from_u128(1), 0);
+        let mut joiner = PollinationNode::new_with_mode(Uuid::from_u128(2), 1, StartupMode::Join);
+        assert!(joiner.id().is_none());
+        assert!(joiner.msg_heartbeat().is_none());
+
+        let new_member = joiner.msg_new_member().unwrap();
+        let seed_msg = seed.handle_message(new_member).unwrap().response.unwrap();
+        joiner.handle_message(seed_msg).unwrap();
+
+        let joined_id = joiner.id().unwrap().clone();
+        assert_eq!(seed.id().unwrap().clone().join(joined_id), IdTree::One);
+        assert_eq!(joiner.peer_count(), 2);
+    }
+
+    #[test]
+    fn test_bump_keeps_reality_token() {
+        let mut node = PollinationNode::new(Uuid::from_u128(1), 0);
+        let reality_token = node.reality_token();
+        let before = node.timestamp().clone();
+        node.bump();
+        assert!(*node.timestamp() > before);
+        assert_eq!(node.reality_token(), reality_token);
+    }
+
+    #[test]
+    fn test_reject_invalid_message() {
+        let mut node = PollinationNode::new(Uuid::from_u128(1), 0);
+        let heartbeat = PollinationMessage::Heartbeat {
+            uuid: Uuid::from_u128(2),
+            id: IdTree::SubTree(Box::new(IdTree::One), Box::new(IdTree::One)),
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+        };
+        assert!(matches!(
+            node.handle_message(heartbeat),
+            Err(PollinationError::InvalidMessage(
+                InvalidMessage::UnnormalizedId
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_defragment_stranded_half() {
+        let mut a = PollinationNode::new(Uuid::from_u128(1), 0);
+        let mut b = PollinationNode::new_with_mode(Uuid::from_u128(2), 1, StartupMode::Join);
+        let mut c = PollinationNode::new_with_mode(Uuid::from_u128(3), 2, StartupMode::Join);
+        let msg = b.msg_new_member();
+        exchange(&mut b, &mut a, msg);
+        b.set_propagating();
+        let msg = c.msg_new_member();
+        exchange(&mut c, &mut b, msg);
+        let msg = b.msg_heartbeat();
+        exchange(&mut b, &mut a, msg);
+
+        // Only quarters border the dead half, so nothing is adjacent to reap
+        a.set_raw(PeerInfo::dead(0));
+        let msg = a.msg_update(b.timestamp()).unwrap();
+        exchange(&mut a, &mut b, msg);
+        let msg = b.msg_update(c.timestamp()).unwrap();
+        exchange(&mut b, &mut c, msg);
+        assert_eq!(b.max_id_depth(), 2);
+
+        assert!(matches!(b.defragment(), Defrag::Adopted));
+        let Defrag::HandedOff { to: 2, msg } = b.defragment() else {
+            panic!("Expected a handoff to c");
+        };
+        // c has not seen the fragment retired yet, so it syncs and then adopts
+        exchange(&mut b, &mut c, Some(msg));
+        assert!(matches!(c.defragment(), Defrag::Adopted));
+        let msg = c.msg_heartbeat();
+        exchange(&mut c, &mut b, msg);
+
+        let (left, right) = IdTree::One.fork();
+        assert_eq!(b.id(), Some(&left));
+        assert_eq!(c.id(), Some(&right));
+        assert_eq!(b.reality_token(), c.reality_token());
+        assert_eq!(c.check_invariants(), Ok(()));
+        assert!(matches!(b.defragment(), Defrag::Idle));
+        assert!(matches!(c.defragment(), Defrag::Idle));
+    }
+
+    #[test]
+    fn test_handoff_requires_sibling() {
+        let mut a = PollinationNode::new(Uuid::from_u128(1), 0);
+        let mut b = PollinationNode::new_with_mode(Uuid::from_u128(2), 1, StartupMode::Join);
+        let mut c = PollinationNode::new_with_mode(Uuid::from_u128(3), 2, StartupMode::Join);
+        let msg = b.msg_new_member();
+        exchange(&mut b, &mut a, msg);
+        b.set_propagating();
+        let msg = c.msg_new_member();
+        exchange(&mut c, &mut b, msg);
+        a.set_raw(PeerInfo::dead(0));
+        let msg = a.msg_update(b.timestamp()).unwrap();
+        exchange(&mut a, &mut b, msg);
+
+        // The dead half's sibling is split between b and c, so b can not
+        // merge it into anything
+        let (left, _) = IdTree::One.fork();
+        let own_id = b.id().cloned();
+        let msg = c.msg_handoff(left).unwrap();
+        b.handle_message(msg).unwrap();
+        assert_eq!(b.id().cloned(), own_id);
+    }
+
+    /// Pass `msg` from `from` to `to` and keep replying until both are done.
+    fn exchange(
+        from: &mut PollinationNode<usize>,
+        to: &mut PollinationNode<usize>,
+        msg: Option<PollinationMessage>,
+    ) {
+        let mut msg = msg;
+        for turn in 0..10 {
+            let Some(next) = msg.take() else {
+                return;
+            };
+            let node = if turn % 2 == 0 { &mut *to } else { &mut *from };
+            msg = node.handle_message(next).unwrap().response;
+        }
+        panic!("Too many iterations during exchange");
+    }
+
+    #[test]
+    fn test_compact_once_acknowledged() {
+        let mut a = PollinationNode::new(Uuid::from_u128(1), 0);
+        let mut b = PollinationNode::new_with_mode(Uuid::from_u128(2), 1, StartupMode::Join);
+        let msg = b.msg_new_member();
+        exchange(&mut b, &mut a, msg);
+        for _ in 0..3 {
+            a.bump();
         }
+        let msg = a.msg_heartbeat();
+        exchange(&mut a, &mut b, msg);
+        let before = a.timestamp_stats();
+        let before_ts = a.timestamp().clone();
+        assert!(before.nodes > 1);
+
+        // b's heartbeat acknowledges everything a knows
+        let msg = b.msg_heartbeat().unwrap();
+        assert!(a.handle_message(msg).unwrap().response.is_none());
+        assert!(a.compact());
+        let after = a.timestamp_stats();
+        assert!(after.nodes < before.nodes);
+        assert_eq!(after.compactions, 1);
+        assert!(*a.timestamp() > before_ts);
+
+        // A message from before the compaction does not undo it
+        let compacted = a.timestamp().clone();
+        let msg = b.msg_heartbeat().unwrap();
+        a.handle_message(msg).unwrap();
+        assert_eq!(*a.timestamp(), compacted);
+
+        let msg = a.msg_heartbeat().unwrap();
+        assert!(b.handle_message(msg).unwrap().response.is_none());
+        assert_eq!(b.timestamp(), a.timestamp());
+        assert_eq!(b.timestamp_stats().followed, 1);
+        assert_eq!(b.reality_token(), a.reality_token());
+    }
 
-        println!("TOTAL ID SPACE: {summed_ids}");
-        summed_ids
+    #[test]
+    fn test_repair_silent_divergence() {
+        let mut a = PollinationNode::new(Uuid::from_u128(1), 0);
+        let mut b = PollinationNode::new_with_mode(Uuid::from_u128(2), 1, StartupMode::Join);
+        let msg = b.msg_new_member();
+        exchange(&mut b, &mut a, msg);
+
+        // b's copy of a's entry changes without its timestamp moving
+        let a_id = a.id().unwrap().clone();
+        b.core_map
+            .insert_without_event(a_id.clone(), PeerInfo::new(a.uuid(), 5));
+        assert_eq!(a.timestamp(), b.timestamp());
+        assert_eq!(a.reality_token(), b.reality_token());
+
+        let msg = a.msg_digest();
+        exchange(&mut a, &mut b, msg);
+        assert_eq!(
+            anti_entropy::digest(&a.core_map),
+            anti_entropy::digest(&b.core_map)
+        );
+        assert_eq!(b.core_map.get(&a_id).map(|info| info.addr), Some(0));
+        let (a, b) = (a.anti_entropy_stats(), b.anti_entropy_stats());
+        assert_eq!(a.repairs + b.repairs, 1);
+        assert!(a.divergences + b.divergences >= 1);
+    }
+
+    #[test]
+    fn test_unsolicited_repair_ignored() {
+        let mut a = PollinationNode::new(Uuid::from_u128(1), 0);
+        let mut b = PollinationNode::new_with_mode(Uuid::from_u128(2), 1, StartupMode::Join);
+        let msg = b.msg_new_member();
+        exchange(&mut b, &mut a, msg);
+
+        let repair = b.msg_repair().unwrap().unwrap();
+        assert!(a.handle_message(repair).unwrap().response.is_none());
+        assert_eq!(a.anti_entropy_stats().repairs, 0);
+
+        // Nor once we have moved on since asking
+        a.msg_digest();
+        let repair = b.msg_repair().unwrap().unwrap();
+        a.bump();
+        a.handle_message(repair).unwrap();
+        assert_eq!(a.anti_entropy_stats().repairs, 0);
     }
 
-    fn notify_of_death<R: Rng>(
-        rng: &mut R,
-        nuclei: &mut [PollinationNode<usize>],
-        old_core: PollinationNode<usize>,
-    ) -> Option<()> {
-        for (_, peer) in old_core.peers() {
-            // 10% chance of failure
-            if rng.random_bool(0.10) {
-                //println!("DEATH NOTIFICATION LOOP ENDED EARLY");
-                //break
+    /// Share of messages a lossy `Cluster` drops.
+    const DROP_RATE: f64 = 0.1;
+
+    /// Nodes addressed by their index, some of which have died.
+    pub(super) struct Cluster {
+        nodes: Vec<PollinationNode<usize>>,
+        alive: Vec<bool>,
+        /// Picks messages to drop, if the network is lossy.
+        drops: Option<StdRng>,
+    }
+
+    impl Cluster {
+        pub(super) fn new() -> Self {
+            Self {
+                nodes: vec![PollinationNode::new(Uuid::from_u128(1), 0)],
+                alive: vec![true],
+                drops: None,
+            }
+        }
+
+        /// A cluster whose exchanges lose messages, picked by `seed`, until
+        /// it is settled.
+        pub(super) fn with_drops(seed: u64) -> Self {
+            Self {
+                drops: Some(StdRng::seed_from_u64(seed)),
+                ..Self::new()
             }
+        }
+
+        pub(super) fn live(&self) -> Vec<usize> {
+            (0..self.nodes.len()).filter(|&i| self.alive[i]).collect()
+        }
+
+        /// Live nodes that own an ID.
+        pub(super) fn members(&self) -> Vec<usize> {
+            self.live()
+                .into_iter()
+                .filter(|&i| self.nodes[i].id().is_some())
+                .collect()
+        }
+
+        /// Add a node that joins through `seed`.
+        pub(super) fn join(&mut self, seed: usize) {
+            let addr = self.nodes.len();
+            let joiner = PollinationNode::new_with_mode(
+                Uuid::from_u128(addr as u128 + 1),
+                addr,
+                StartupMode::Join,
+            );
+            let msg = joiner.msg_new_member();
+            self.nodes.push(joiner);
+            self.alive.push(true);
+            self.nodes[seed].set_propagating();
+            self.exchange(addr, seed, msg);
+        }
 
-            if peer.addr.is_none() {
-                println!("NO PEER ADDR FOR NOTIFICATION OF DEATH: {peer}");
-                continue;
+        /// Node `i` announces its death to `witness` and goes silent.
+        pub(super) fn kill(&mut self, i: usize, witness: usize) {
+            // Catch up first, so the witness can merge the announcement.
+            // Timeouts are not modelled, so the death has to get through
+            let drops = self.drops.take();
+            self.sync(i, witness);
+            self.drops = drops;
+            // Unlike a retired ID, a death names who died
+            let obituary = PeerInfo {
+                status: PeerStatus::Dead,
+                ..self.nodes[i].own_info.clone()
+            };
+            if self.nodes[witness].id().is_none() || self.nodes[i].set_raw(obituary).is_none() {
+                // One of them lost its ID while catching up; only members
+                // die, and only to a member
+                return;
             }
-            let peer_idx = peer.addr.unwrap();
-            let peer_nucl = &mut nuclei[peer_idx];
-            let msg = old_core.msg_update(peer_nucl.timestamp())?;
-            println!("{peer_idx} <- {msg}");
-            let _ = peer_nucl
-                .handle_message(msg)
+            self.alive[i] = false;
+            let peer_ts = self.nodes[witness].timestamp().clone();
+            let msg = self.nodes[i].msg_update(&peer_ts).unwrap();
+            // Whatever the witness replies, nobody is listening
+            self.nodes[witness]
+                .handle_message(msg.expect("only members die"))
                 .expect("Pollination hit an error");
         }
-        None
-    }
 
-    fn sync_two<R: Rng>(
-        rng: &mut R,
-        nuclei: &mut [PollinationNode<usize>],
-        i: usize,
-        j: usize,
-    ) -> Option<PollinationNode<usize>> {
-        nuclei[i].bump();
-        let mut msg = nuclei[i].msg_heartbeat();
-        if msg.is_none() {
-            msg = nuclei[i].msg_new_member();
+        pub(super) fn gossip(&mut self, i: usize, j: usize) {
+            self.nodes[i].bump();
+            self.sync(i, j);
         }
 
-        let mut old_core = None;
-        let mut iters = 0;
-        loop {
-            // 10% chance of failure
-            if rng.random_bool(0.10) {
-                println!("SYNC LOOP ENDED EARLY");
-                break old_core;
+        /// Like `gossip`, without advancing `i`'s clock first.
+        fn sync(&mut self, i: usize, j: usize) {
+            if self.nodes[i].id().is_none() {
+                // Rejoins through `j`, like `join`
+                self.nodes[j].set_propagating();
             }
+            let msg = self.nodes[i].msg_heartbeat_or_join();
+            self.exchange(i, j, msg);
+        }
 
-            iters += 1;
-            if iters >= 10 {
-                panic!("Too many iterations during sync");
+        /// Reclaim and defragment IDs at `i`, delivering any handoff.
+        pub(super) fn tidy(&mut self, i: usize) {
+            self.nodes[i].reap_souls();
+            if let Defrag::HandedOff { to, msg } = self.nodes[i].defragment() {
+                // The receiver may have died since `i` last heard of it
+                if self.alive[to] {
+                    self.exchange(i, to, Some(msg));
+                }
             }
-            for idx in [j, i] {
-                let res = match msg.take() {
-                    Some(msg) => {
-                        println!("{idx} <- {msg}");
-                        nuclei[idx].handle_message(msg)
+        }
+
+        /// Gossip between every pair of live nodes until they agree on a
+        /// membership without dead entries. Returns the ID space they own.
+        ///
+        /// The network heals first, so nothing is dropped from here on.
+        pub(super) fn settle(&mut self) -> IdTree {
+            self.drops = None;
+            for _ in 0..100 {
+                let live = self.live();
+                for &i in &live {
+                    self.tidy(i);
+                    for &j in &live {
+                        self.gossip(i, j);
+                    }
+                }
+                for &i in &live {
+                    for &j in &live {
+                        self.sync(i, j);
                     }
-                    None => return old_core,
                 }
-                .expect("Pollination hit an error.");
+                if self.converged() {
+                    break;
+                }
+            }
+            assert!(self.converged(), "cluster did not converge");
+
+            let ids: Vec<&IdTree> = self
+                .live()
+                .into_iter()
+                .filter_map(|i| self.nodes[i].id())
+                .collect();
+            assert_eq!(recycling::check_disjoint(ids.iter().copied()), Ok(()));
+            ids.into_iter()
+                .fold(IdTree::Zero, |acc, id| acc.join(id.clone()))
+        }
 
+        /// Deepest ID any live node knows of.
+        pub(super) fn max_id_depth(&self) -> usize {
+            self.live()
+                .into_iter()
+                .map(|i| self.nodes[i].max_id_depth())
+                .max()
+                .unwrap_or(0)
+        }
+
+        fn converged(&self) -> bool {
+            let live = self.live();
+            let first = &self.nodes[live[0]];
+            live.iter().all(|&i| {
+                let node = &self.nodes[i];
+                node.timestamp() == first.timestamp()
+                    && node.reality_token() == first.reality_token()
+                    && node
+                        .peers()
+                        .all(|(_, info)| info.status == PeerStatus::Healthy)
+            })
+        }
+
+        /// Pass `msg` from `from` to `to` and keep replying until both are
+        /// done, or until a lossy network drops the next message.
+        fn exchange(&mut self, from: usize, to: usize, msg: Option<PollinationMessage>) {
+            let mut msg = msg;
+            for turn in 0..20 {
+                let Some(next) = msg.take() else {
+                    return;
+                };
+                // Retrying a join is not modelled, so the seed has to get
+                // through
+                if !matches!(next, PollinationMessage::Seed { .. })
+                    && let Some(rng) = self.drops.as_mut()
+                    && rng.random_bool(DROP_RATE)
+                {
+                    return;
+                }
+                let idx = if turn % 2 == 0 { to } else { from };
+                let res = self.nodes[idx]
+                    .handle_message(next)
+                    .expect("Pollination hit an error");
                 msg = res.response;
-                if let Some(new_old_core) = res.old_core {
-                    assert!(old_core.is_none());
-                    old_core = Some(new_old_core);
+            }
+            panic!("Too many iterations during exchange");
+        }
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::tests::Cluster;
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A step of a simulated cluster, naming nodes by arbitrary indices
+    /// into whoever is around at the time.
+    #[derive(Clone, Debug)]
+    enum Op {
+        Join(usize),
+        Kill(usize, usize),
+        Gossip(usize, usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            1 => any::<usize>().prop_map(Op::Join),
+            1 => (any::<usize>(), any::<usize>()).prop_map(|(i, j)| Op::Kill(i, j)),
+            3 => (any::<usize>(), any::<usize>()).prop_map(|(i, j)| Op::Gossip(i, j)),
+        ]
+    }
+
+    proptest! {
+        /// Whatever joins, deaths and gossip happen, the survivors end up
+        /// owning the whole ID space between them.
+        #[test]
+        fn prop_cluster_converges(
+            ops in prop::collection::vec(op(), 1..60),
+            drop_seed in any::<u64>(),
+        ) {
+            let mut cluster = Cluster::with_drops(drop_seed);
+            for op in ops {
+                let live = cluster.live();
+                match op {
+                    Op::Join(seed) if live.len() < 12 => cluster.join(live[seed % live.len()]),
+                    Op::Join(_) => {}
+                    Op::Kill(i, witness) => {
+                        let members = cluster.members();
+                        if members.len() > 1 {
+                            let i = members[i % members.len()];
+                            let others: Vec<usize> =
+                                members.into_iter().filter(|&j| j != i).collect();
+                            cluster.kill(i, others[witness % others.len()]);
+                        }
+                    }
+                    Op::Gossip(i, j) => {
+                        let i = live[i % live.len()];
+                        cluster.gossip(i, live[j % live.len()]);
+                        cluster.tidy(i);
+                    }
+                }
+            }
+            prop_assert_eq!(cluster.settle(), IdTree::One);
+        }
+    }
+
+    proptest! {
+        /// Nodes joining through arbitrary seeds split the ID space between
+        /// them without gaps or overlaps.
+        #[test]
+        fn prop_joins_partition_id_space(seeds in prop::collection::vec(any::<usize>(), 1..20)) {
+            let mut nodes = vec![PollinationNode::new(Uuid::from_u128(0), 0)];
+            for seed in seeds {
+                let addr = nodes.len();
+                let uuid = Uuid::from_u128(addr as u128);
+                let mut joiner = PollinationNode::new_with_mode(uuid, addr, StartupMode::Join);
+                let seed = &mut nodes[seed % addr];
+                seed.set_propagating();
+
+                let new_member = joiner.msg_new_member().unwrap();
+                let seed_msg = seed.handle_message(new_member)?.response.unwrap();
+                if let Some(update) = joiner.handle_message(seed_msg)?.response {
+                    seed.handle_message(update)?;
                 }
+                prop_assert_eq!(seed.check_invariants(), Ok(()));
+                nodes.push(joiner);
+
+                let ids: Vec<&IdTree> = nodes.iter().filter_map(|node| node.id()).collect();
+                prop_assert_eq!(recycling::check_disjoint(ids.iter().copied()), Ok(()));
+                let total = ids
+                    .into_iter()
+                    .fold(IdTree::Zero, |acc, id| acc.join(id.clone()));
+                prop_assert_eq!(total, IdTree::One);
             }
         }
     }
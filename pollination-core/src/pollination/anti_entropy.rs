@@ -0,0 +1,91 @@
+use crate::{peer_info::PeerInfo, reality_token::hash_id, serialization::serialize};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use treeclocks::ItcMap;
+
+/// Merkle root over every entry of a core map, values included.
+///
+/// Timestamps and the reality token only cover who owns which ID; this also
+/// catches entries whose contents silently diverged.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct MapDigest([u8; 32]);
+
+impl fmt::Display for MapDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        for b in &self.0[..8] {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How often anti-entropy rounds found and repaired divergence.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AntiEntropyStats {
+    /// Digests compared against a peer with the same timestamp.
+    pub rounds: u64,
+    /// Rounds whose digests differed.
+    pub divergences: u64,
+    /// Times we replaced our map with a peer's.
+    pub repairs: u64,
+}
+
+pub(crate) fn digest<A: Serialize>(map: &ItcMap<PeerInfo<A>>) -> MapDigest {
+    let mut level: Vec<[u8; 32]> = map
+        .iter()
+        .map(|(id, info)| {
+            let mut hasher = Sha256::new();
+            hasher.update([0]);
+            hash_id(&mut hasher, id);
+            // Encoding a `PeerInfo` we already hold only fails on a broken `A`
+            hasher.update(serialize(info).unwrap_or_default());
+            hasher.finalize().into()
+        })
+        .collect();
+    // Entries are iterated in map order, which need not match between peers
+    level.sort_unstable();
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [l, r] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([1]);
+                    hasher.update(l);
+                    hasher.update(r);
+                    hasher.finalize().into()
+                }
+                [odd] => *odd,
+                _ => unreachable!("chunks of two"),
+            })
+            .collect();
+    }
+    MapDigest(level.pop().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use treeclocks::IdTree;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_digest_covers_values() {
+        let (left, right) = IdTree::One.fork();
+        let mut a = ItcMap::new();
+        a.insert(left.clone(), PeerInfo::new(Uuid::from_u128(1), 1));
+        a.insert(right.clone(), PeerInfo::new(Uuid::from_u128(2), 2));
+        let mut b = ItcMap::new();
+        b.insert(right.clone(), PeerInfo::new(Uuid::from_u128(2), 2));
+        b.insert(left.clone(), PeerInfo::new(Uuid::from_u128(1), 1));
+        assert_eq!(digest(&a), digest(&b));
+
+        b.insert(left, PeerInfo::new(Uuid::from_u128(1), 3));
+        assert_ne!(digest(&a), digest(&b));
+        assert_ne!(digest(&a), MapDigest::default());
+    }
+}
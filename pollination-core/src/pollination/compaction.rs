@@ -0,0 +1,156 @@
+use super::merge;
+use crate::{message::PatchDecodeError, peer_info::PeerInfo, serialization::serialize};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use treeclocks::{EventTree, ItcMap};
+use uuid::Uuid;
+
+/// Values of a timestamp count compactions above this bit and events below
+/// it, so every compacted timestamp is ahead of all those before it.
+const EPOCH_SHIFT: u32 = 32;
+
+/// The latest timestamp each peer has sent us, i.e. how much of the
+/// membership history it has acknowledged.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Acks {
+    latest: HashMap<Uuid, EventTree>,
+}
+
+impl Acks {
+    pub(crate) fn record(&mut self, uuid: Uuid, timestamp: &EventTree) {
+        match self.latest.get_mut(&uuid) {
+            // Messages can be reordered; never move an ack backwards
+            Some(latest) if *timestamp < *latest => {}
+            Some(latest) => *latest = timestamp.clone(),
+            None => {
+                self.latest.insert(uuid, timestamp.clone());
+            }
+        }
+    }
+
+    /// Whether every peer in `live` has acknowledged `frontier`. Acks from
+    /// anyone else are dropped.
+    pub(crate) fn all_acked(&mut self, live: &[Uuid], frontier: &EventTree) -> bool {
+        self.latest.retain(|uuid, _| live.contains(uuid));
+        live.iter().all(|uuid| {
+            self.latest
+                .get(uuid)
+                .is_some_and(|timestamp| timestamp >= frontier)
+        })
+    }
+}
+
+/// `map` rebuilt from scratch at `epoch`: the same entries, each stamped
+/// exactly once on top of the epoch's base value.
+///
+/// The result only depends on the entries and the epoch, so nodes holding
+/// the same membership rebuild to the same timestamp.
+pub(crate) fn rebuild<A>(
+    map: &ItcMap<PeerInfo<A>>,
+    epoch: u64,
+) -> Result<ItcMap<PeerInfo<A>>, PatchDecodeError>
+where
+    A: Clone + for<'a> Deserialize<'a> + Serialize,
+{
+    let mut rebuilt = ItcMap::new();
+    for (id, info) in map.iter() {
+        rebuilt.insert(id.clone(), info.clone());
+    }
+    let base = epoch << EPOCH_SHIFT;
+    let lifted = match rebuilt.timestamp().clone() {
+        EventTree::Leaf(n) => EventTree::Leaf(n.saturating_add(base)),
+        EventTree::SubTree(n, l, r) => EventTree::SubTree(n.saturating_add(base), l, r),
+    };
+    rebuilt.apply(merge::clock(lifted)?);
+    Ok(rebuilt)
+}
+
+/// How many compactions `timestamp` has been through.
+pub(crate) fn epoch(timestamp: &EventTree) -> u64 {
+    max(timestamp) >> EPOCH_SHIFT
+}
+
+fn max(timestamp: &EventTree) -> u64 {
+    match timestamp {
+        EventTree::Leaf(n) => *n,
+        EventTree::SubTree(n, l, r) => n.saturating_add(max(l).max(max(r))),
+    }
+}
+
+/// Number of nodes in `timestamp`.
+pub(crate) fn nodes(timestamp: &EventTree) -> usize {
+    let mut count = 0;
+    let mut stack = vec![timestamp];
+    while let Some(timestamp) = stack.pop() {
+        count += 1;
+        if let EventTree::SubTree(_, l, r) = timestamp {
+            stack.push(l);
+            stack.push(r);
+        }
+    }
+    count
+}
+
+fn depth(timestamp: &EventTree) -> usize {
+    match timestamp {
+        EventTree::Leaf(_) => 0,
+        EventTree::SubTree(_, l, r) => 1 + depth(l).max(depth(r)),
+    }
+}
+
+/// How big the membership timestamp carried in every message is, and how
+/// often it has been compacted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimestampStats {
+    /// Encoded size in bytes.
+    pub size: usize,
+    pub nodes: usize,
+    pub depth: usize,
+    /// Compactions we started once every live peer had caught up.
+    pub compactions: u64,
+    /// Compactions we took over from a peer that got there first.
+    pub followed: u64,
+}
+
+impl TimestampStats {
+    pub(crate) fn measure(&self, timestamp: &EventTree) -> Self {
+        Self {
+            size: serialize(timestamp).map(|bytes| bytes.len()).unwrap_or(0),
+            nodes: nodes(timestamp),
+            depth: depth(timestamp),
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acks_never_regress() {
+        let uuid = Uuid::from_u128(1);
+        let mut acks = Acks::default();
+        acks.record(uuid, &EventTree::Leaf(2));
+        acks.record(uuid, &EventTree::Leaf(1));
+        assert!(acks.all_acked(&[uuid], &EventTree::Leaf(2)));
+        assert!(!acks.all_acked(&[uuid, Uuid::from_u128(2)], &EventTree::Leaf(2)));
+    }
+
+    #[test]
+    fn test_rebuild_ahead() {
+        let (left, right) = treeclocks::IdTree::One.fork();
+        let mut map = ItcMap::new();
+        for _ in 0..5 {
+            map.insert(left.clone(), PeerInfo::new(Uuid::from_u128(1), 0));
+            map.insert(right.clone(), PeerInfo::new(Uuid::from_u128(2), 1));
+        }
+        assert_eq!(epoch(map.timestamp()), 0);
+
+        let rebuilt = rebuild(&map, 1).unwrap();
+        assert!(rebuilt.timestamp() > map.timestamp());
+        assert_eq!(epoch(rebuilt.timestamp()), 1);
+        assert_eq!(rebuilt.len(), map.len());
+        assert_eq!(rebuild(&map, 1).unwrap().timestamp(), rebuilt.timestamp());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::peer_info::{PeerInfo, PeerStatus};
+use std::cmp::Reverse;
+use treeclocks::IdTree;
+use uuid::Uuid;
+
+/// A leaf of the ID space, as the turns taken from the root (`true` is right).
+type Path = Vec<bool>;
+
+/// One step towards fewer, shallower ID fragments.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Step<A> {
+    /// Take over a dead ID that no live peer borders.
+    Adopt(IdTree),
+    /// Give `fragment` of our ID to the peer at `to`, who owns all of its
+    /// sibling and so can merge the two into their parent.
+    HandOff { fragment: IdTree, to: A },
+}
+
+/// Pick the next defragmentation step for the node `uuid` owning `own`.
+///
+/// Every node runs this against its own view of the cluster. Views that
+/// agree pick at most one adopter per dead ID and at most one receiver per
+/// fragment; views that disagree can collide just like concurrent reaps.
+///
+/// Each step either turns a dead ID into a live one or removes a leaf from
+/// the cluster, so repeated steps settle.
+pub(crate) fn plan<'a, A: Clone + 'a>(
+    own: &IdTree,
+    uuid: Uuid,
+    peers: impl IntoIterator<Item = (&'a IdTree, &'a PeerInfo<A>)>,
+) -> Option<Step<A>> {
+    let mut live = vec![];
+    let mut dead = vec![];
+    for (id, info) in peers {
+        match info.status {
+            PeerStatus::Dead => dead.push(id),
+            PeerStatus::Healthy if info.uuid != uuid => live.push((id, info)),
+            PeerStatus::Healthy => {}
+        }
+    }
+
+    adopter(own, uuid, &live, &dead)
+        .map(Step::Adopt)
+        .or_else(|| receiver(own, &live))
+}
+
+/// A dead ID we should adopt: for one of its leaves, we own the shallowest
+/// part of the sibling among live peers, ties going to the lowest UUID. If
+/// nobody lives in the sibling, the parent's sibling decides, and so on.
+fn adopter<A>(
+    own: &IdTree,
+    uuid: Uuid,
+    live: &[(&IdTree, &PeerInfo<A>)],
+    dead: &[&IdTree],
+) -> Option<IdTree> {
+    let candidates = live
+        .iter()
+        .map(|(id, info)| (*id, info.uuid))
+        .chain([(own, uuid)]);
+    for dead_id in dead {
+        for mut leaf in leaves(dead_id) {
+            let chosen = loop {
+                let Some(sibling) = sibling(&leaf) else {
+                    break None;
+                };
+                let region = at(&sibling);
+                let chosen = candidates
+                    .clone()
+                    .filter_map(|(id, peer)| {
+                        let depth = leaves(&intersect(id, &region)).iter().map(Vec::len).min()?;
+                        Some((depth, peer))
+                    })
+                    .min();
+                if chosen.is_some() {
+                    break chosen;
+                }
+                leaf.pop();
+            };
+            if chosen.map(|(_, chosen)| chosen) == Some(uuid) {
+                return Some((*dead_id).clone());
+            }
+        }
+    }
+    None
+}
+
+/// Our deepest leaf whose whole sibling is owned by a single live peer. A
+/// node with a single leaf keeps it.
+fn receiver<A: Clone>(own: &IdTree, live: &[(&IdTree, &PeerInfo<A>)]) -> Option<Step<A>> {
+    let mut own_leaves = leaves(own);
+    if own_leaves.len() < 2 {
+        return None;
+    }
+    own_leaves.sort_by_key(|leaf| Reverse(leaf.len()));
+
+    own_leaves.into_iter().find_map(|leaf| {
+        let region = at(&sibling(&leaf)?);
+        let (_, info) = live.iter().find(|(id, _)| covers(id, &region))?;
+        Some(Step::HandOff {
+            fragment: at(&leaf),
+            to: info.addr.clone(),
+        })
+    })
+}
+
+/// Depth of the deepest leaf of `id`; the whole ID space is at depth 0.
+pub(crate) fn depth(id: &IdTree) -> usize {
+    leaves(id).iter().map(Vec::len).max().unwrap_or(0)
+}
+
+fn leaves(id: &IdTree) -> Vec<Path> {
+    let mut out = vec![];
+    let mut stack = vec![(id, vec![])];
+    while let Some((id, path)) = stack.pop() {
+        match id {
+            IdTree::Zero => {}
+            IdTree::One => out.push(path),
+            IdTree::SubTree(l, r) => {
+                let mut left = path.clone();
+                left.push(false);
+                let mut right = path;
+                right.push(true);
+                stack.push((r, right));
+                stack.push((l, left));
+            }
+        }
+    }
+    out
+}
+
+/// The ID owning the sibling of `fragment`, if it is a single leaf.
+pub(crate) fn sibling_of(fragment: &IdTree) -> Option<IdTree> {
+    match leaves(fragment).as_slice() {
+        [leaf] => Some(at(&sibling(leaf)?)),
+        _ => None,
+    }
+}
+
+fn sibling(path: &[bool]) -> Option<Path> {
+    let (last, parent) = path.split_last()?;
+    let mut sibling = parent.to_vec();
+    sibling.push(!last);
+    Some(sibling)
+}
+
+/// The ID owning exactly the leaf at `path`.
+fn at(path: &[bool]) -> IdTree {
+    path.iter().rev().fold(IdTree::One, |id, &right| {
+        if right {
+            IdTree::SubTree(Box::new(IdTree::Zero), Box::new(id))
+        } else {
+            IdTree::SubTree(Box::new(id), Box::new(IdTree::Zero))
+        }
+    })
+}
+
+/// Whether `outer` owns all of `inner`.
+pub(crate) fn covers(outer: &IdTree, inner: &IdTree) -> bool {
+    use IdTree::*;
+    match (outer, inner) {
+        (_, Zero) | (One, _) => true,
+        // Normalized subtrees never own their whole range
+        (Zero, _) | (SubTree(..), One) => false,
+        (SubTree(l0, r0), SubTree(l1, r1)) => covers(l0, l1) && covers(r0, r1),
+    }
+}
+
+fn intersect(a: &IdTree, b: &IdTree) -> IdTree {
+    use IdTree::*;
+    match (a, b) {
+        (Zero, _) | (_, Zero) => Zero,
+        (One, id) | (id, One) => id.clone(),
+        (SubTree(l0, r0), SubTree(l1, r1)) => node(intersect(l0, l1), intersect(r0, r1)),
+    }
+}
+
+/// `id` without the part owned by `fragment`.
+pub(crate) fn subtract(id: &IdTree, fragment: &IdTree) -> IdTree {
+    use IdTree::*;
+    match (id, fragment) {
+        (id, Zero) => id.clone(),
+        (Zero, _) | (_, One) => Zero,
+        (One, SubTree(l, r)) => node(subtract(&One, l), subtract(&One, r)),
+        (SubTree(l0, r0), SubTree(l1, r1)) => node(subtract(l0, l1), subtract(r0, r1)),
+    }
+}
+
+/// A normalized subtree of `l` and `r`.
+fn node(l: IdTree, r: IdTree) -> IdTree {
+    use IdTree::*;
+    match (l, r) {
+        (Zero, Zero) => Zero,
+        (One, One) => One,
+        (l, r) => SubTree(Box::new(l), Box::new(r)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live(uuid: u128, addr: usize) -> PeerInfo<usize> {
+        PeerInfo::new(Uuid::from_u128(uuid), addr)
+    }
+
+    #[test]
+    fn test_subtract() {
+        let (left, right) = IdTree::One.fork();
+        assert_eq!(subtract(&IdTree::One, &left), right);
+        assert_eq!(subtract(&right, &right), IdTree::Zero);
+        let (right_left, right_right) = right.clone().fork();
+        assert_eq!(subtract(&right, &right_left), right_right);
+        assert_eq!(depth(&right_left), 2);
+    }
+
+    #[test]
+    fn test_hand_off_to_sibling_owner() {
+        // We own a quarter on each side; the peer owns the rest of the right
+        let (left, right) = IdTree::One.fork();
+        let (_, left_right) = left.fork();
+        let (right_left, right_right) = right.fork();
+        let own = left_right.clone().join(right_right);
+        let peer = live(2, 7);
+        let step = plan(&own, Uuid::from_u128(1), [(&right_left, &peer)]);
+        assert!(matches!(step, Some(Step::HandOff { to: 7, .. })));
+    }
+
+    #[test]
+    fn test_adopt_stranded_dead_id() {
+        // The dead left half borders several live peers; the shallowest adopts it
+        let (left, right) = IdTree::One.fork();
+        let (right_left, right_right) = right.fork();
+        let (right_right_left, right_right_right) = right_right.fork();
+        let dead = PeerInfo::dead(0);
+        let shallow = live(1, 1);
+        let deep = live(2, 2);
+        let deeper = live(3, 3);
+        let peers = [
+            (&right_left, &shallow),
+            (&left, &dead),
+            (&right_right_left, &deep),
+            (&right_right_right, &deeper),
+        ];
+        assert_eq!(
+            plan(&right_left, Uuid::from_u128(1), peers),
+            Some(Step::Adopt(left.clone()))
+        );
+        assert_eq!(plan(&right_right_left, Uuid::from_u128(2), peers), None);
+    }
+
+    #[test]
+    fn test_adopt_next_to_dead_sibling() {
+        // Both right quarters are dead, so the left half adopts them
+        let (left, right) = IdTree::One.fork();
+        let (right_left, right_right) = right.fork();
+        let dead = PeerInfo::dead(0);
+        let peers = [(&right_left, &dead), (&right_right, &dead)];
+        assert!(matches!(
+            plan(&left, Uuid::from_u128(1), peers),
+            Some(Step::Adopt(_))
+        ));
+    }
+}
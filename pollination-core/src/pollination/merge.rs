@@ -0,0 +1,123 @@
+use crate::message::PatchDecodeError;
+use crate::serialization::{deserialize, serialize};
+use serde::Serialize;
+use treeclocks::{EventTree, IdTree, ItcMap, Patch};
+
+/// A [`Patch`] taken apart, as `treeclocks` keeps its fields private.
+#[derive(Clone)]
+pub(crate) struct PatchParts<T> {
+    /// The peer's timestamp, without any entries.
+    clock: Patch<T>,
+    timestamp: EventTree,
+    inner: Vec<(IdTree, T)>,
+}
+
+impl<T: Clone> PatchParts<T> {
+    /// Take `patch` apart by applying it to an empty map.
+    pub(crate) fn new(patch: Patch<T>) -> Self {
+        let mut scratch = ItcMap::new();
+        scratch.apply(patch);
+        Self {
+            clock: scratch.diff(scratch.timestamp()),
+            timestamp: scratch.timestamp().clone(),
+            inner: scratch
+                .iter()
+                .map(|(id, value)| (id.clone(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// The patch with only the entries `keep` accepts.
+    pub(crate) fn retain(mut self, mut keep: impl FnMut(&IdTree, &T) -> bool) -> Self {
+        self.inner.retain(|(id, value)| keep(id, value));
+        self
+    }
+
+    /// Merge the patch into `map`, like `ItcMap::apply`, but without the
+    /// entries `map` has already seen everything of.
+    ///
+    /// `ItcMap::apply` takes every entry with a split ID as soon as the peer
+    /// is ahead anywhere, so a stale entry could replace the newer one we
+    /// hold.
+    pub(crate) fn apply_to(self, map: &mut ItcMap<T>) -> (Additions<'_, T>, Removals<T>) {
+        let diff = self.timestamp.diff(map.timestamp());
+        let mut added = vec![];
+        let mut removed = vec![];
+        for (id, value) in self.inner {
+            if touches(&diff, &id) {
+                removed.append(&mut map.insert_without_event(id.clone(), value));
+                added.push(id);
+            }
+        }
+        map.apply(self.clock);
+
+        let added = added
+            .into_iter()
+            .filter_map(|id| {
+                let value = map.get(&id)?;
+                Some((id, value))
+            })
+            .collect();
+        (added, removed)
+    }
+}
+
+type Additions<'a, T> = Vec<(IdTree, &'a T)>;
+type Removals<T> = Vec<(IdTree, T)>;
+
+/// A patch advancing to `timestamp` without any entries. `treeclocks` has no
+/// way to build one, so it is decoded from its parts.
+pub(crate) fn clock<T>(timestamp: EventTree) -> Result<Patch<T>, PatchDecodeError>
+where
+    T: for<'de> serde::Deserialize<'de>,
+{
+    #[derive(Serialize)]
+    struct Clock {
+        timestamp: EventTree,
+        inner: Vec<()>,
+    }
+
+    let clock = Clock {
+        timestamp,
+        inner: Vec::new(),
+    };
+    Ok(deserialize(serialize(clock)?)?)
+}
+
+/// Whether `diff` has any events within `id`.
+fn touches(diff: &EventTree, id: &IdTree) -> bool {
+    match (diff, id) {
+        (_, IdTree::Zero) => false,
+        (EventTree::Leaf(n), _) | (EventTree::SubTree(n, ..), _) if *n > 0 => true,
+        (EventTree::Leaf(_), _) => false,
+        (EventTree::SubTree(_, l, r), IdTree::One) => {
+            touches(l, &IdTree::One) || touches(r, &IdTree::One)
+        }
+        (EventTree::SubTree(_, l, r), IdTree::SubTree(id_l, id_r)) => {
+            touches(l, id_l) || touches(r, id_r)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_entries_dropped() {
+        let (left, right) = IdTree::One.fork();
+        let mut peer = ItcMap::new();
+        peer.insert(left.clone(), 1);
+        peer.insert(right.clone(), 2);
+        let mut own = ItcMap::new();
+        own.apply(peer.diff(&EventTree::new()));
+
+        // We moved on at the left; the peer only at the right
+        own.insert(left.clone(), 3);
+        peer.insert(right.clone(), 4);
+        PatchParts::new(peer.diff(&EventTree::new())).apply_to(&mut own);
+        assert_eq!(own.get(&left), Some(&3));
+        assert_eq!(own.get(&right), Some(&4));
+        assert!(own.timestamp() > peer.timestamp());
+    }
+}
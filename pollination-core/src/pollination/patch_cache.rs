@@ -0,0 +1,128 @@
+use crate::message::BinaryPatch;
+use std::{collections::VecDeque, sync::Mutex};
+use treeclocks::EventTree;
+
+const PATCH_CACHE_SIZE: usize = 16;
+
+/// Encoded patches of the current core map, keyed by the peer timestamp they
+/// were diffed against.
+///
+/// Gossip tends to converge, so many peers sit at the same few timestamps and
+/// every joiner is seeded with the full map; caching the encoding avoids
+/// diffing and serializing the same delta over and over. The whole cache is
+/// invalidated as soon as our own timestamp moves.
+#[derive(Debug, Default)]
+pub(crate) struct PatchCache {
+    inner: Mutex<PatchCacheInner>,
+}
+
+#[derive(Debug, Default)]
+struct PatchCacheInner {
+    timestamp: Option<EventTree>,
+    patches: VecDeque<(EventTree, BinaryPatch)>,
+    stats: PatchCacheStats,
+}
+
+/// How often `create_patch` could reuse an earlier encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PatchCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl PatchCache {
+    pub(crate) fn get_or_try_insert_with<E>(
+        &self,
+        timestamp: &EventTree,
+        peer_ts: &EventTree,
+        create: impl FnOnce() -> Result<BinaryPatch, E>,
+    ) -> Result<BinaryPatch, E> {
+        {
+            let mut inner = self.inner.lock().expect("poisoned lock");
+            if inner.timestamp.as_ref() != Some(timestamp) {
+                inner.timestamp = Some(timestamp.clone());
+                inner.patches.clear();
+            }
+            let cached = inner
+                .patches
+                .iter()
+                .find(|(ts, _)| ts == peer_ts)
+                .map(|(_, patch)| patch.clone());
+            if let Some(patch) = cached {
+                inner.stats.hits += 1;
+                return Ok(patch);
+            }
+            inner.stats.misses += 1;
+        }
+
+        // Diff without holding the lock
+        let patch = create()?;
+
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        if inner.timestamp.as_ref() == Some(timestamp) {
+            if inner.patches.len() >= PATCH_CACHE_SIZE {
+                inner.patches.pop_front();
+            }
+            inner.patches.push_back((peer_ts.clone(), patch.clone()));
+        }
+        Ok(patch)
+    }
+
+    /// Drop every cached patch, e.g. once they would be encoded differently.
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        inner.timestamp = None;
+        inner.patches.clear();
+    }
+
+    pub(crate) fn stats(&self) -> PatchCacheStats {
+        self.inner.lock().expect("poisoned lock").stats
+    }
+}
+
+impl Clone for PatchCache {
+    /// Clones start out cold; they are usually modified right away.
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidated_on_new_timestamp() {
+        let cache = PatchCache::default();
+        let peer_ts = EventTree::Leaf(0);
+        let ts_a = EventTree::Leaf(1);
+        let ts_b = EventTree::Leaf(2);
+
+        let create = || Ok::<_, ()>(BinaryPatch::default());
+        cache
+            .get_or_try_insert_with(&ts_a, &peer_ts, create)
+            .unwrap();
+        cache
+            .get_or_try_insert_with(&ts_a, &peer_ts, || Err(()))
+            .unwrap();
+        cache
+            .get_or_try_insert_with(&ts_b, &peer_ts, create)
+            .unwrap();
+
+        assert_eq!(cache.stats(), PatchCacheStats { hits: 1, misses: 2 });
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = PatchCache::default();
+        let peer_ts = EventTree::Leaf(0);
+        let ts = EventTree::Leaf(1);
+
+        let create = || Ok::<_, ()>(BinaryPatch::default());
+        cache.get_or_try_insert_with(&ts, &peer_ts, create).unwrap();
+        cache.clear();
+        cache.get_or_try_insert_with(&ts, &peer_ts, create).unwrap();
+
+        assert_eq!(cache.stats(), PatchCacheStats { hits: 0, misses: 2 });
+    }
+}
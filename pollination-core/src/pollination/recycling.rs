@@ -1,34 +1,33 @@
+use super::defrag;
+use thiserror::Error;
 use treeclocks::IdTree;
 
-pub fn claim_ids(own: IdTree, dead_peers: IdTree) -> IdTree {
-    let reclaim_tree = claim_ids_recurse(own, dead_peers);
-    convert(reclaim_tree)
+/// Take over the IDs of `dead_peers` that border our own `own` ID.
+///
+/// Both trees come from gossip, so every broken invariant is reported rather
+/// than trusted. Dead IDs overlapping our own can only be stale and are
+/// ignored.
+pub fn claim_ids(own: IdTree, dead_peers: IdTree) -> Result<IdTree, RecycleError> {
+    let dead_peers = defrag::subtract(&dead_peers, &own);
+    let reclaim_tree = claim_ids_recurse(own, dead_peers)?;
+    Ok(convert(reclaim_tree))
 }
 
-fn claim_ids_recurse(own: IdTree, dead_peers: IdTree) -> IdReclaimTree {
+/// `dead_peers` must not overlap `own`.
+fn claim_ids_recurse(own: IdTree, dead_peers: IdTree) -> Result<IdReclaimTree, RecycleError> {
     use IdTree::*;
-    match (own, dead_peers) {
+    Ok(match (own, dead_peers) {
         (Zero, Zero) => IdReclaimTree::Zero,
         (Zero, One) => IdReclaimTree::Dead,
-        (One, Zero) => IdReclaimTree::One,
-        (One, One) => panic!("Logic bug"),
+        // Nothing dead lies within our own ID
+        (One, _) => IdReclaimTree::One,
+        // Only a subtree owning nothing leaves all of it to the dead
+        (SubTree(..), One) => return Err(RecycleError::Unnormalized),
 
         (Zero, SubTree(..)) => IdReclaimTree::Zero,
-        (One, SubTree(..)) => {
-            // HIT
-            //panic!("Logic bug"),
-            error!("Logic bug");
-            IdReclaimTree::One
-        }
-        (SubTree(..), One) => {
-            // HIT
-            //panic!("Logic bug"),
-            error!("Logic bug");
-            IdReclaimTree::One
-        }
         (SubTree(l, r), Zero) => {
-            let l = claim_ids_recurse(*l, Zero);
-            let r = claim_ids_recurse(*r, Zero);
+            let l = claim_ids_recurse(*l, Zero)?;
+            let r = claim_ids_recurse(*r, Zero)?;
             match (l, r) {
                 (l @ IdReclaimTree::TrendingLeft(..) | l @ IdReclaimTree::One, r) => {
                     IdReclaimTree::TrendingLeft(Box::new(l), Box::new(r))
@@ -40,11 +39,11 @@ fn claim_ids_recurse(own: IdTree, dead_peers: IdTree) -> IdReclaimTree {
             }
         }
         (SubTree(l0, r0), SubTree(l1, r1)) => {
-            let l = claim_ids_recurse(*l0, *l1);
-            let r = claim_ids_recurse(*r0, *r1);
+            let l = claim_ids_recurse(*l0, *l1)?;
+            let r = claim_ids_recurse(*r0, *r1)?;
             use IdReclaimTree as Irt;
             match (l, r) {
-                (Irt::Dead, Irt::Dead) => panic!("Logic bug"),
+                (Irt::Dead, Irt::Dead) => return Err(RecycleError::Unnormalized),
                 (Irt::Dead | Irt::Zero, Irt::Dead | Irt::Zero) => Irt::Zero,
 
                 (Irt::One, Irt::Dead) | (Irt::Dead, Irt::One) => Irt::One,
@@ -68,11 +67,34 @@ fn claim_ids_recurse(own: IdTree, dead_peers: IdTree) -> IdReclaimTree {
                     IdReclaimTree::TrendingLeft(..)
                     | IdReclaimTree::TrendingRight(..)
                     | IdReclaimTree::SubTree(..),
-                ) => panic!("Logic Bug"),
+                ) => return Err(RecycleError::SplitOwnership),
 
                 (l, r) => Irt::SubTree(Box::new(l), Box::new(r)),
             }
         }
+    })
+}
+
+/// Check that no two of `ids` claim the same part of the ID space.
+pub fn check_disjoint<'a>(ids: impl IntoIterator<Item = &'a IdTree>) -> Result<(), RecycleError> {
+    let mut claimed = IdTree::Zero;
+    for id in ids {
+        if overlaps(&claimed, id) {
+            return Err(RecycleError::OverlappingOwnership);
+        }
+        claimed = claimed.join(id.clone());
+    }
+    Ok(())
+}
+
+/// Whether `a` and `b` share any part of the ID space.
+pub(crate) fn overlaps(a: &IdTree, b: &IdTree) -> bool {
+    use IdTree::*;
+    match (a, b) {
+        (Zero, _) | (_, Zero) => false,
+        // Normalized subtrees always own something
+        (One, _) | (_, One) => true,
+        (SubTree(l0, r0), SubTree(l1, r1)) => overlaps(l0, l1) || overlaps(r0, r1),
     }
 }
 
@@ -81,10 +103,13 @@ fn convert(irt: IdReclaimTree) -> IdTree {
     match irt {
         Dead | Zero => IdTree::Zero,
         One => IdTree::One,
+        // Claiming can fill both halves of a subtree
         SubTree(l, r) | TrendingLeft(l, r) | TrendingRight(l, r) => {
-            let l = convert(*l);
-            let r = convert(*r);
-            IdTree::SubTree(Box::new(l), Box::new(r))
+            match (convert(*l), convert(*r)) {
+                (IdTree::Zero, IdTree::Zero) => IdTree::Zero,
+                (IdTree::One, IdTree::One) => IdTree::One,
+                (l, r) => IdTree::SubTree(Box::new(l), Box::new(r)),
+            }
         }
     }
 }
@@ -99,6 +124,19 @@ enum IdReclaimTree {
     TrendingRight(Box<IdReclaimTree>, Box<IdReclaimTree>),
 }
 
+/// An ID invariant broken by the trees handed to recycling.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RecycleError {
+    #[error("Two peers claim overlapping IDs")]
+    OverlappingOwnership,
+
+    #[error("Own ID is spread over both halves of a subtree with dead peers")]
+    SplitOwnership,
+
+    #[error("ID tree is not normalized")]
+    Unnormalized,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,7 +148,7 @@ mod tests {
         let i0 = SubTree(Box::new(One), Box::new(Zero));
         let i1 = SubTree(Box::new(Zero), Box::new(One));
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "1".to_string());
     }
@@ -125,7 +163,7 @@ mod tests {
         );
         let i1 = SubTree(Box::new(One), Box::new(Zero));
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "(1, 0)".to_string());
     }
@@ -143,7 +181,7 @@ mod tests {
         );
         let i1 = SubTree(Box::new(One), Box::new(Zero));
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "(1, 0)".to_string());
     }
@@ -158,7 +196,7 @@ mod tests {
         );
         let i1 = SubTree(Box::new(Zero), Box::new(One));
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "(0, 1)".to_string());
     }
@@ -176,7 +214,7 @@ mod tests {
         );
         let i1 = SubTree(Box::new(Zero), Box::new(One));
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "(0, 1)".to_string());
     }
@@ -194,7 +232,7 @@ mod tests {
         );
         let i1 = SubTree(Box::new(Zero), Box::new(One));
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "((0, (1, 0)), 0)".to_string());
     }
@@ -218,8 +256,183 @@ mod tests {
             Box::new(One),
         );
 
-        let new_id = claim_ids(i0, i1);
+        let new_id = claim_ids(i0, i1).unwrap();
 
         assert_eq!(new_id.to_string(), "((0, 1), 0)".to_string());
     }
+
+    #[test]
+    fn test_dead_overlapping_own_ignored() {
+        use IdTree::*;
+
+        let own = SubTree(Box::new(One), Box::new(Zero));
+        assert_eq!(claim_ids(own.clone(), One), Ok(One));
+        assert_eq!(claim_ids(One, own), Ok(One));
+    }
+
+    #[test]
+    fn test_split_ownership() {
+        use IdTree::*;
+
+        // We own ((1, 0), (0, 1)) and the dead border both our halves from
+        // the inside, so neither side can be merged upwards
+        let own = SubTree(
+            Box::new(SubTree(Box::new(One), Box::new(Zero))),
+            Box::new(SubTree(Box::new(Zero), Box::new(One))),
+        );
+        let dead = SubTree(
+            Box::new(SubTree(
+                Box::new(Zero),
+                Box::new(SubTree(Box::new(Zero), Box::new(One))),
+            )),
+            Box::new(SubTree(
+                Box::new(SubTree(Box::new(One), Box::new(Zero))),
+                Box::new(Zero),
+            )),
+        );
+        assert_eq!(claim_ids(own, dead), Err(RecycleError::SplitOwnership));
+    }
+
+    #[test]
+    fn test_unnormalized() {
+        use IdTree::*;
+
+        let empty = SubTree(Box::new(Zero), Box::new(Zero));
+        assert_eq!(
+            claim_ids(empty.clone(), One),
+            Err(RecycleError::Unnormalized)
+        );
+        let full = SubTree(Box::new(One), Box::new(One));
+        let own = SubTree(Box::new(empty), Box::new(Zero));
+        assert_eq!(
+            claim_ids(own, SubTree(Box::new(full), Box::new(Zero))),
+            Err(RecycleError::Unnormalized)
+        );
+    }
+
+    #[test]
+    fn test_check_disjoint() {
+        use IdTree::*;
+
+        let left = SubTree(Box::new(One), Box::new(Zero));
+        let right = SubTree(Box::new(Zero), Box::new(One));
+        assert!(check_disjoint([&left, &right]).is_ok());
+        assert_eq!(
+            check_disjoint([&left, &right, &One]),
+            Err(RecycleError::OverlappingOwnership)
+        );
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Fork(usize),
+        Die(usize),
+        Reclaim(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => any::<usize>().prop_map(Op::Fork),
+            2 => any::<usize>().prop_map(Op::Die),
+            2 => any::<usize>().prop_map(Op::Reclaim),
+        ]
+    }
+
+    fn union<'a>(ids: impl IntoIterator<Item = &'a IdTree>) -> IdTree {
+        ids.into_iter()
+            .fold(IdTree::Zero, |acc, id| acc.join(id.clone()))
+    }
+
+    fn complement(id: &IdTree) -> IdTree {
+        match id {
+            IdTree::Zero => IdTree::One,
+            IdTree::One => IdTree::Zero,
+            IdTree::SubTree(l, r) => match (complement(l), complement(r)) {
+                (IdTree::Zero, IdTree::Zero) => IdTree::Zero,
+                (IdTree::One, IdTree::One) => IdTree::One,
+                (l, r) => IdTree::SubTree(Box::new(l), Box::new(r)),
+            },
+        }
+    }
+
+    /// `inner` claims nothing outside of `outer`.
+    fn within(inner: &IdTree, outer: &IdTree) -> bool {
+        outer.clone().join(inner.clone()) == *outer
+    }
+
+    fn forked_ids(forks: &[usize]) -> Vec<IdTree> {
+        let mut ids = vec![IdTree::One];
+        for &i in forks {
+            let i = i % ids.len();
+            let (a, b) = ids[i].clone().fork();
+            ids[i] = a;
+            ids.push(b);
+        }
+        ids
+    }
+
+    proptest! {
+        /// Replay a history of forks, deaths and reclaims. Reclaiming must
+        /// only ever take dead IDs, so live IDs never overlap.
+        #[test]
+        fn prop_claim_never_overlaps(ops in prop::collection::vec(op(), 1..40)) {
+            let mut live = vec![IdTree::One];
+            let mut dead: Vec<IdTree> = Vec::new();
+
+            for op in ops {
+                match op {
+                    Op::Fork(i) => {
+                        let i = i % live.len();
+                        let (a, b) = live[i].clone().fork();
+                        live[i] = a;
+                        live.push(b);
+                    }
+                    Op::Die(i) if live.len() > 1 => {
+                        dead.push(live.remove(i % live.len()));
+                    }
+                    Op::Die(_) => {}
+                    Op::Reclaim(i) if !dead.is_empty() => {
+                        let i = i % live.len();
+                        let dead_ids = union(&dead);
+                        let new_id = claim_ids(live[i].clone(), dead_ids.clone())?;
+
+                        let claimable = live[i].clone().join(dead_ids);
+                        prop_assert!(within(&new_id, &claimable), "{new_id} outside {claimable}");
+
+                        // Dead entries the new ID touches are replaced by it
+                        dead.retain(|id| !overlaps(id, &new_id));
+                        live[i] = new_id;
+                    }
+                    Op::Reclaim(_) => {}
+                }
+                prop_assert_eq!(check_disjoint(&live), Ok(()));
+            }
+        }
+
+        /// Once every other peer is known dead, the survivor ends up owning
+        /// the whole ID space.
+        #[test]
+        fn prop_sole_survivor_recovers_one(
+            forks in prop::collection::vec(any::<usize>(), 0..20),
+            survivor in any::<usize>(),
+        ) {
+            let ids = forked_ids(&forks);
+            let mut own = ids[survivor % ids.len()].clone();
+
+            for _ in 0..ids.len() {
+                let new_id = claim_ids(own.clone(), complement(&own))?;
+                if new_id == own {
+                    break;
+                }
+                own = new_id;
+            }
+            prop_assert_eq!(own, IdTree::One);
+        }
+    }
 }
@@ -1,7 +1,8 @@
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use treeclocks::IdTree;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub(crate) enum Propagativity {
     #[default]
     Unknown,
@@ -34,6 +35,15 @@ impl Propagativity {
         }
     }
 
+    /// Swap in a new ID, keeping whether we may propagate.
+    pub(crate) fn set_id(&mut self, new_id: IdTree) {
+        use Propagativity::*;
+        match self {
+            Propagating(id) | Resting(id) => *id = new_id,
+            Unknown => *self = Resting(new_id),
+        }
+    }
+
     pub(crate) fn resting(id: IdTree) -> Self {
         Propagativity::Resting(id)
     }
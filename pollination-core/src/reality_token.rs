@@ -1,31 +1,219 @@
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use crate::peer_info::{PeerInfo, PeerStatus};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use treeclocks::IdTree;
 
-#[derive(
-    Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default, Hash,
-)]
-pub struct RealityToken(u128);
+/// Fingerprint of the membership, as carried in messages.
+///
+/// Since protocol v3 it is a multiset hash over the `(uuid, id, status)` of
+/// every entry in the core map. Entries hash to 128 bits each and are
+/// summed, so the token is updated incrementally as entries come and go and
+/// does not depend on the order they did so. Unlike a plain XOR, adding the
+/// same entry twice does not cancel out, and memberships only collide by
+/// chance.
+///
+/// Peers on protocol versions before 3 fingerprint with the XOR of uuids
+/// instead. The two only ever compare like with like.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RealityToken {
+    Legacy(u128),
+    Multiset(u128),
+}
 
 impl RealityToken {
-    pub fn new(uuid: Uuid) -> Self {
-        Self(uuid.as_u128())
+    pub fn zero() -> Self {
+        Self::Multiset(0)
     }
 
-    pub fn zero() -> Self {
-        Self(0)
+    pub fn get(&self) -> u128 {
+        match self {
+            Self::Legacy(value) | Self::Multiset(value) => *value,
+        }
     }
 
-    pub fn push(&mut self, uuid: Uuid) {
-        self.0 ^= uuid.as_u128()
+    /// The same value read the way peers before v3 read it.
+    pub(crate) fn into_legacy(self) -> Self {
+        Self::Legacy(self.get())
     }
+}
 
-    pub fn get(&self) -> u128 {
-        self.0
+impl Default for RealityToken {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+/// How a token goes on the wire. The envelope version says which kind it
+/// is, so tokens decode as [`RealityToken::Multiset`] until the envelope
+/// says otherwise.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "RealityToken")]
+struct Encoded(u128);
+
+impl Serialize for RealityToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Encoded(self.get()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RealityToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Encoded(value) = Encoded::deserialize(deserializer)?;
+        Ok(Self::Multiset(value))
+    }
+}
+
+/// Both tokens of our own membership, so we can speak to peers of either
+/// kind.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Tokens {
+    multiset: u128,
+    legacy: u128,
+}
+
+impl Tokens {
+    /// The tokens of a whole membership.
+    pub(crate) fn of<'a, A: 'a>(
+        entries: impl IntoIterator<Item = (&'a IdTree, &'a PeerInfo<A>)>,
+    ) -> Self {
+        let mut tokens = Self::default();
+        for (id, info) in entries {
+            tokens.add(id, info);
+        }
+        tokens
+    }
+
+    pub(crate) fn add<A>(&mut self, id: &IdTree, info: &PeerInfo<A>) {
+        self.multiset = self.multiset.wrapping_add(hash_entry(id, info));
+        self.legacy ^= info.uuid.as_u128();
+    }
+
+    pub(crate) fn remove<A>(&mut self, id: &IdTree, info: &PeerInfo<A>) {
+        self.multiset = self.multiset.wrapping_sub(hash_entry(id, info));
+        self.legacy ^= info.uuid.as_u128();
+    }
+
+    pub(crate) fn current(&self) -> RealityToken {
+        RealityToken::Multiset(self.multiset)
+    }
+
+    pub(crate) fn legacy(&self) -> RealityToken {
+        RealityToken::Legacy(self.legacy)
+    }
+
+    /// Our token of the same kind as `peer`'s, to compare it against.
+    pub(crate) fn like(&self, peer: &RealityToken) -> RealityToken {
+        match peer {
+            RealityToken::Legacy(_) => self.legacy(),
+            RealityToken::Multiset(_) => self.current(),
+        }
+    }
+}
+
+fn hash_entry<A>(id: &IdTree, info: &PeerInfo<A>) -> u128 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"pollination/reality-token/v1");
+    hasher.update(info.uuid.as_bytes());
+    hasher.update([match info.status {
+        PeerStatus::Healthy => 0,
+        PeerStatus::Dead => 1,
+    }]);
+    hash_id(&mut hasher, id);
+    let digest = hasher.finalize();
+    u128::from_le_bytes(digest[..16].try_into().expect("digest is 32 bytes"))
+}
+
+/// Feed `id` to `hasher` as a pre-order walk, which is unambiguous since
+/// every node is tagged.
+pub(crate) fn hash_id(hasher: &mut Sha256, id: &IdTree) {
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        match id {
+            IdTree::Zero => hasher.update([0]),
+            IdTree::One => hasher.update([1]),
+            IdTree::SubTree(l, r) => {
+                hasher.update([2]);
+                stack.push(r);
+                stack.push(l);
+            }
+        }
     }
 }
 
 impl std::fmt::Display for RealityToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.get())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::{deserialize, serialize};
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_duplicates_do_not_cancel() {
+        let info = PeerInfo::new(Uuid::from_u128(1), ());
+        let mut tokens = Tokens::default();
+        tokens.add(&IdTree::One, &info);
+        tokens.add(&IdTree::One, &info);
+        assert_ne!(tokens.current(), RealityToken::zero());
+
+        tokens.remove(&IdTree::One, &info);
+        tokens.remove(&IdTree::One, &info);
+        assert_eq!(tokens.current(), RealityToken::zero());
+    }
+
+    #[test]
+    fn test_fingerprint_covers_id_and_status() {
+        let (left, right) = IdTree::One.fork();
+        let info = PeerInfo::new(Uuid::from_u128(1), ());
+        let dead = PeerInfo {
+            status: PeerStatus::Dead,
+            ..info.clone()
+        };
+
+        let a = Tokens::of([(&left, &info)]);
+        let b = Tokens::of([(&right, &info)]);
+        let c = Tokens::of([(&left, &dead)]);
+        assert_ne!(a.current(), b.current());
+        assert_ne!(a.current(), c.current());
+
+        // Order independent
+        let other = PeerInfo::new(Uuid::from_u128(2), ());
+        let a = Tokens::of([(&left, &info), (&right, &other)]);
+        let d = Tokens::of([(&right, &other), (&left, &info)]);
+        assert_eq!(a.current(), d.current());
+    }
+
+    #[test]
+    fn test_like_with_like() {
+        let info = PeerInfo::new(Uuid::from_u128(3), ());
+        let tokens = Tokens::of([(&IdTree::One, &info)]);
+        assert_eq!(tokens.legacy(), RealityToken::Legacy(3));
+        assert_eq!(tokens.like(&RealityToken::Legacy(0)), tokens.legacy());
+        assert_eq!(tokens.like(&RealityToken::Multiset(0)), tokens.current());
+
+        // Equal values of different kinds are different tokens, and hash
+        // accordingly
+        assert_ne!(RealityToken::Legacy(3), RealityToken::Multiset(3));
+        let set = HashSet::from([RealityToken::Legacy(3), RealityToken::Multiset(3)]);
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let info = PeerInfo::new(Uuid::from_u128(3), ());
+        let tokens = Tokens::of([(&IdTree::One, &info)]);
+
+        let bytes = serialize(tokens.legacy()).unwrap();
+        let legacy: RealityToken = deserialize(bytes).unwrap();
+        assert_eq!(legacy.into_legacy(), tokens.legacy());
+
+        let bytes = serialize(tokens.current()).unwrap();
+        let current: RealityToken = deserialize(bytes).unwrap();
+        assert_eq!(current, tokens.current());
     }
 }
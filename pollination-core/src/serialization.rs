@@ -1,48 +1,108 @@
-use serde::{Deserialize, Serialize};
+use crate::limits::Limits;
+use bounded::{Bounded, Bounds, Budget};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+mod bounded;
+mod codec;
+mod encryption;
+
+#[cfg(feature = "cbor")]
+pub use codec::Cbor;
 #[cfg(feature = "json")]
-mod json {
-    use super::*;
+pub use codec::Json;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePack;
+pub use codec::{Bincode, Codec, DeserializeError, Format, SerializeError};
+pub use encryption::{ClusterCipher, EncryptionError};
 
-    pub(crate) fn serialize<T: Serialize>(val: T) -> Result<Vec<u8>, SerializeError> {
-        serde_json::to_vec(&val)
-    }
+/// Levels of nesting a message or patch adds around the trees in it.
+const NESTING_SLACK: usize = 16;
 
-    pub(crate) type SerializeError = serde_json::Error;
+/// Serialize with the default [`Bincode`] codec, used for everything that is
+/// not a message payload (envelopes, signatures, patches, snapshots).
+pub(crate) fn serialize<T: Serialize>(val: T) -> Result<Vec<u8>, SerializeError> {
+    Bincode.encode(&val)
+}
 
-    pub(crate) fn deserialize<T: for<'de> Deserialize<'de>>(
-        val: Vec<u8>,
-    ) -> Result<T, DeserializeError> {
-        let jd = &mut serde_json::Deserializer::from_slice(&val[..]);
-        serde_path_to_error::deserialize(jd)
-    }
+pub(crate) fn deserialize<T: DeserializeOwned>(val: Vec<u8>) -> Result<T, DeserializeError> {
+    Bincode.decode(&val)
+}
 
-    pub(crate) type DeserializeError = serde_path_to_error::Error<serde_json::Error>;
+/// Like [`deserialize`], but `T` may borrow from `bytes`.
+pub(crate) fn deserialize_slice<'a, T: Deserialize<'a>>(
+    bytes: &'a [u8],
+) -> Result<T, DeserializeError> {
+    deserialize_slice_within(bytes, &Limits::default())
 }
 
-#[cfg(feature = "json")]
-pub use json::*;
+/// Like [`deserialize_slice`], but within `limits`.
+///
+/// Bincode has no nesting limit of its own, so depth and claimed sizes are
+/// checked while decoding.
+pub(crate) fn deserialize_slice_within<'a, T: Deserialize<'a>>(
+    bytes: &'a [u8],
+    limits: &Limits,
+) -> Result<T, DeserializeError> {
+    let budget = Budget::new(Bounds {
+        max_depth: limits.max_tree_depth.saturating_add(NESTING_SLACK),
+        max_size: limits.max_decode_size,
+    });
+    let config = bincode::config::standard();
+    let (res, _) = bincode::serde::seed_decode_from_slice(Bounded::new(&budget), bytes, config)?;
+    Ok(res)
+}
 
-#[cfg(not(feature = "json"))]
-mod bincode_ {
+#[cfg(test)]
+mod tests {
     use super::*;
+    use crate::{message::PollinationMessage, reality_token::RealityToken};
+    use treeclocks::{EventTree, IdTree};
+    use uuid::Uuid;
 
-    pub(crate) fn serialize<T: Serialize>(val: T) -> Result<Vec<u8>, SerializeError> {
-        bincode::serde::encode_to_vec(val, bincode::config::standard())
+    fn deep_id(depth: usize) -> IdTree {
+        (1..depth).fold(IdTree::One, |id, _| {
+            IdTree::SubTree(Box::new(id), Box::new(IdTree::Zero))
+        })
     }
 
-    pub(crate) type SerializeError = bincode::error::EncodeError;
+    #[test]
+    fn test_deep_input_rejected() {
+        // `SubTree(SubTree(..., Zero), Zero)` nested far past any limit;
+        // decoding it recursively would overflow the stack
+        let depth = 1_000_000;
+        let mut bytes = vec![2; depth];
+        bytes.push(1);
+        bytes.resize(2 * depth + 1, 0);
+        assert!(deserialize_slice::<IdTree>(&bytes).is_err());
+    }
 
-    pub(crate) fn deserialize<T: for<'de> Deserialize<'de>>(
-        val: Vec<u8>,
-    ) -> Result<T, DeserializeError> {
-        let (res, _) = bincode::serde::decode_from_slice(&val, bincode::config::standard())?;
-        Ok(res)
+    #[test]
+    fn test_deepest_tree_decodes() {
+        let limits = Limits::default();
+        let msg: PollinationMessage = PollinationMessage::Heartbeat {
+            uuid: Uuid::from_u128(1),
+            id: deep_id(limits.max_tree_depth),
+            timestamp: EventTree::new(),
+            reality_token: RealityToken::zero(),
+            legacy_token: None,
+        };
+        let bytes = serialize(&msg).unwrap();
+        let out: PollinationMessage = deserialize_slice(&bytes).unwrap();
+        assert!(limits.check_id(out.id().unwrap()).is_ok());
     }
 
-    pub(crate) type DeserializeError = bincode::error::DecodeError;
-}
+    #[test]
+    fn test_decode_within_limits() {
+        let limits = Limits {
+            max_tree_depth: 8,
+            max_decode_size: 64,
+            ..Default::default()
+        };
+        let bytes = serialize(deep_id(64)).unwrap();
+        assert!(deserialize_slice::<IdTree>(&bytes).is_ok());
+        assert!(deserialize_slice_within::<IdTree>(&bytes, &limits).is_err());
 
-#[cfg(not(feature = "json"))]
-#[allow(unused_imports)]
-pub use bincode_::*;
+        let bytes = serialize(vec![0u8; 65]).unwrap();
+        assert!(deserialize_slice_within::<Vec<u8>>(&bytes, &limits).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use serde::de::{self, DeserializeSeed, Deserializer, Visitor};
+use std::{cell::Cell, fmt, marker::PhantomData};
+
+/// How deep and how much a single decode may go.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bounds {
+    /// Deepest nesting of values; every value counts one level below the one
+    /// containing it.
+    pub(crate) max_depth: usize,
+    /// Most bytes the input may claim: scalars count their size, byte and
+    /// string fields their length and sequences their announced length.
+    pub(crate) max_size: usize,
+}
+
+/// Progress of one decode against its [`Bounds`].
+#[derive(Debug)]
+pub(crate) struct Budget {
+    bounds: Bounds,
+    depth: Cell<usize>,
+    size: Cell<usize>,
+}
+
+impl Budget {
+    pub(crate) fn new(bounds: Bounds) -> Self {
+        Self {
+            bounds,
+            depth: Cell::new(0),
+            size: Cell::new(0),
+        }
+    }
+
+    fn enter<E: de::Error>(&self) -> Result<(), E> {
+        let depth = self.depth.get() + 1;
+        if depth > self.bounds.max_depth {
+            return Err(E::custom(format_args!(
+                "nested deeper than the limit of {}",
+                self.bounds.max_depth
+            )));
+        }
+        self.depth.set(depth);
+        Ok(())
+    }
+
+    fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    fn claim<E: de::Error>(&self, n: usize) -> Result<(), E> {
+        let size = self.size.get().saturating_add(n);
+        if size > self.bounds.max_size {
+            return Err(E::custom(format_args!(
+                "claims more than the limit of {} bytes",
+                self.bounds.max_size
+            )));
+        }
+        self.size.set(size);
+        Ok(())
+    }
+}
+
+/// Deserializes `T` within `budget`, failing before recursion gets deep
+/// enough to exhaust the stack.
+pub(crate) struct Bounded<'b, T> {
+    budget: &'b Budget,
+    _marker: PhantomData<T>,
+}
+
+impl<'b, T> Bounded<'b, T> {
+    pub(crate) fn new(budget: &'b Budget) -> Self {
+        Self {
+            budget,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'de, T: de::Deserialize<'de>> DeserializeSeed<'de> for Bounded<'_, T> {
+    type Value = T;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<T, D::Error> {
+        T::deserialize(Limited {
+            inner: deserializer,
+            budget: self.budget,
+        })
+    }
+}
+
+struct Limited<'b, D> {
+    inner: D,
+    budget: &'b Budget,
+}
+
+struct LimitedSeed<'b, S> {
+    inner: S,
+    budget: &'b Budget,
+}
+
+struct LimitedVisitor<'b, V> {
+    inner: V,
+    budget: &'b Budget,
+}
+
+struct LimitedAccess<'b, A> {
+    inner: A,
+    budget: &'b Budget,
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident($($arg:ident: $ty:ty),*)),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, D::Error> {
+                self.budget.enter()?;
+                let visitor = LimitedVisitor {
+                    inner: visitor,
+                    budget: self.budget,
+                };
+                let res = self.inner.$method($($arg,)* visitor);
+                self.budget.leave();
+                res
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Limited<'_, D> {
+    type Error = D::Error;
+
+    forward_deserialize! {
+        deserialize_any(), deserialize_bool(), deserialize_i8(), deserialize_i16(),
+        deserialize_i32(), deserialize_i64(), deserialize_i128(), deserialize_u8(),
+        deserialize_u16(), deserialize_u32(), deserialize_u64(), deserialize_u128(),
+        deserialize_f32(), deserialize_f64(), deserialize_char(), deserialize_str(),
+        deserialize_string(), deserialize_bytes(), deserialize_byte_buf(),
+        deserialize_option(), deserialize_unit(),
+        deserialize_unit_struct(name: &'static str),
+        deserialize_newtype_struct(name: &'static str),
+        deserialize_seq(), deserialize_tuple(len: usize),
+        deserialize_tuple_struct(name: &'static str, len: usize),
+        deserialize_map(),
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]),
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]),
+        deserialize_identifier(), deserialize_ignored_any(),
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.inner.is_human_readable()
+    }
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for LimitedSeed<'_, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<S::Value, D::Error> {
+        self.inner.deserialize(Limited {
+            inner: deserializer,
+            budget: self.budget,
+        })
+    }
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method<E: de::Error>(self, v: $ty) -> Result<V::Value, E> {
+                self.budget.claim(size_of::<$ty>())?;
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+macro_rules! forward_visit_len {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method<E: de::Error>(self, v: $ty) -> Result<V::Value, E> {
+                self.budget.claim(v.len())?;
+                self.inner.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for LimitedVisitor<'_, V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.expecting(f)
+    }
+
+    forward_visit! {
+        visit_bool(bool), visit_i8(i8), visit_i16(i16), visit_i32(i32), visit_i64(i64),
+        visit_i128(i128), visit_u8(u8), visit_u16(u16), visit_u32(u32), visit_u64(u64),
+        visit_u128(u128), visit_f32(f32), visit_f64(f64), visit_char(char),
+    }
+
+    forward_visit_len! {
+        visit_str(&str), visit_borrowed_str(&'de str), visit_string(String),
+        visit_bytes(&[u8]), visit_borrowed_bytes(&'de [u8]), visit_byte_buf(Vec<u8>),
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<V::Value, E> {
+        self.inner.visit_none()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<V::Value, E> {
+        self.inner.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<V::Value, D::Error> {
+        self.inner.visit_some(Limited {
+            inner: deserializer,
+            budget: self.budget,
+        })
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<V::Value, D::Error> {
+        self.inner.visit_newtype_struct(Limited {
+            inner: deserializer,
+            budget: self.budget,
+        })
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<V::Value, A::Error> {
+        self.budget.claim(seq.size_hint().unwrap_or(0))?;
+        self.inner.visit_seq(LimitedAccess {
+            inner: seq,
+            budget: self.budget,
+        })
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<V::Value, A::Error> {
+        self.budget.claim(map.size_hint().unwrap_or(0))?;
+        self.inner.visit_map(LimitedAccess {
+            inner: map,
+            budget: self.budget,
+        })
+    }
+
+    fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<V::Value, A::Error> {
+        self.inner.visit_enum(LimitedAccess {
+            inner: data,
+            budget: self.budget,
+        })
+    }
+}
+
+impl<'b, 'de, A: de::SeqAccess<'de>> de::SeqAccess<'de> for LimitedAccess<'b, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, A::Error> {
+        self.inner.next_element_seed(LimitedSeed {
+            inner: seed,
+            budget: self.budget,
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'b, 'de, A: de::MapAccess<'de>> de::MapAccess<'de> for LimitedAccess<'b, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        self.inner.next_key_seed(LimitedSeed {
+            inner: seed,
+            budget: self.budget,
+        })
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, A::Error> {
+        self.inner.next_value_seed(LimitedSeed {
+            inner: seed,
+            budget: self.budget,
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.inner.size_hint()
+    }
+}
+
+impl<'b, 'de, A: de::EnumAccess<'de>> de::EnumAccess<'de> for LimitedAccess<'b, A> {
+    type Error = A::Error;
+    type Variant = LimitedAccess<'b, A::Variant>;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self::Variant), A::Error> {
+        let seed = LimitedSeed {
+            inner: seed,
+            budget: self.budget,
+        };
+        let (value, variant) = self.inner.variant_seed(seed)?;
+        let variant = LimitedAccess {
+            inner: variant,
+            budget: self.budget,
+        };
+        Ok((value, variant))
+    }
+}
+
+impl<'de, A: de::VariantAccess<'de>> de::VariantAccess<'de> for LimitedAccess<'_, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.inner.unit_variant()
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, A::Error> {
+        self.inner.newtype_variant_seed(LimitedSeed {
+            inner: seed,
+            budget: self.budget,
+        })
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        self.inner.tuple_variant(
+            len,
+            LimitedVisitor {
+                inner: visitor,
+                budget: self.budget,
+            },
+        )
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        self.inner.struct_variant(
+            fields,
+            LimitedVisitor {
+                inner: visitor,
+                budget: self.budget,
+            },
+        )
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{envelope::Features, limits::Limits};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::fmt;
+use thiserror::Error;
+
+/// A serialization format for messages on the wire.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, val: &T) -> Result<Vec<u8>, SerializeError>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DeserializeError>;
+
+    /// Like [`Codec::decode`], but within `limits` where the format does
+    /// not bound nesting itself.
+    fn decode_within<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        limits: &Limits,
+    ) -> Result<T, DeserializeError> {
+        let _ = limits;
+        self.decode(bytes)
+    }
+}
+
+/// Compact and fast; what every node speaks by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+/// Human readable, for debugging.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, val: &T) -> Result<Vec<u8>, SerializeError> {
+        Ok(bincode::serde::encode_to_vec(
+            val,
+            bincode::config::standard(),
+        )?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DeserializeError> {
+        self.decode_within(bytes, &Limits::default())
+    }
+
+    fn decode_within<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        limits: &Limits,
+    ) -> Result<T, DeserializeError> {
+        super::deserialize_slice_within(bytes, limits)
+    }
+}
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, val: &T) -> Result<Vec<u8>, SerializeError> {
+        Ok(serde_json::to_vec(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DeserializeError> {
+        let jd = &mut serde_json::Deserializer::from_slice(bytes);
+        Ok(serde_path_to_error::deserialize(jd)?)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, val: &T) -> Result<Vec<u8>, SerializeError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(val, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DeserializeError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, val: &T) -> Result<Vec<u8>, SerializeError> {
+        Ok(rmp_serde::to_vec(val)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DeserializeError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// Which [`Codec`] to use, picked at runtime.
+///
+/// Every build speaks bincode; the other formats sit behind the feature of
+/// the same name and are advertised as [`Features`], so a node only uses one
+/// with peers that can read it. Variants exist regardless of features to keep
+/// their encoding stable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Format {
+    #[default]
+    Bincode,
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    /// What a peer needs to read this format.
+    pub fn feature(&self) -> Features {
+        match self {
+            Format::Bincode => Features::NONE,
+            Format::Json => Features::JSON,
+            Format::Cbor => Features::CBOR,
+            Format::MessagePack => Features::MSGPACK,
+        }
+    }
+
+    /// This format if `features` include it, bincode otherwise.
+    pub fn within(self, features: Features) -> Format {
+        if features.contains(self.feature()) {
+            self
+        } else {
+            Format::Bincode
+        }
+    }
+
+    /// Tag naming the format in a patch header.
+    pub(crate) fn tag(&self) -> u8 {
+        match self {
+            Format::Bincode => 0,
+            Format::Json => 1,
+            Format::Cbor => 2,
+            Format::MessagePack => 3,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Format> {
+        match tag {
+            0 => Some(Format::Bincode),
+            1 => Some(Format::Json),
+            2 => Some(Format::Cbor),
+            3 => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for Format {
+    fn encode<T: Serialize>(&self, val: &T) -> Result<Vec<u8>, SerializeError> {
+        match self {
+            Format::Bincode => Bincode.encode(val),
+            #[cfg(feature = "json")]
+            Format::Json => Json.encode(val),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Cbor.encode(val),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => MessagePack.encode(val),
+            #[allow(unreachable_patterns)]
+            other => Err(SerializeError::Unsupported(*other)),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, DeserializeError> {
+        match self {
+            Format::Bincode => Bincode.decode(bytes),
+            #[cfg(feature = "json")]
+            Format::Json => Json.decode(bytes),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => Cbor.decode(bytes),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => MessagePack.decode(bytes),
+            #[allow(unreachable_patterns)]
+            other => Err(DeserializeError::Unsupported(*other)),
+        }
+    }
+
+    fn decode_within<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+        limits: &Limits,
+    ) -> Result<T, DeserializeError> {
+        match self {
+            Format::Bincode => Bincode.decode_within(bytes, limits),
+            _ => self.decode(bytes),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let name = match self {
+            Format::Bincode => "bincode",
+            Format::Json => "json",
+            Format::Cbor => "cbor",
+            Format::MessagePack => "msgpack",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SerializeError {
+    #[error("bincode: {0}")]
+    Bincode(#[from] bincode::error::EncodeError),
+
+    #[cfg(feature = "json")]
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[cfg(feature = "cbor")]
+    #[error("cbor: {0}")]
+    Cbor(#[from] ciborium::ser::Error<std::io::Error>),
+
+    #[cfg(feature = "msgpack")]
+    #[error("msgpack: {0}")]
+    MessagePack(#[from] rmp_serde::encode::Error),
+
+    #[error("{0} support is not enabled in this build")]
+    Unsupported(Format),
+}
+
+#[derive(Debug, Error)]
+pub enum DeserializeError {
+    #[error("bincode: {0}")]
+    Bincode(#[from] bincode::error::DecodeError),
+
+    #[cfg(feature = "json")]
+    #[error("json: {0}")]
+    Json(#[from] serde_path_to_error::Error<serde_json::Error>),
+
+    #[cfg(feature = "cbor")]
+    #[error("cbor: {0}")]
+    Cbor(#[from] ciborium::de::Error<std::io::Error>),
+
+    #[cfg(feature = "msgpack")]
+    #[error("msgpack: {0}")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+
+    #[error("{0} support is not enabled in this build")]
+    Unsupported(Format),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::PollinationMessage;
+    use uuid::Uuid;
+
+    const ALL: [Format; 4] = [
+        Format::Bincode,
+        Format::Json,
+        Format::Cbor,
+        Format::MessagePack,
+    ];
+
+    #[test]
+    fn test_roundtrip_supported_formats() {
+        let msg: PollinationMessage = PollinationMessage::NewMember {
+            uuid: Uuid::from_u128(7),
+        };
+        for format in ALL {
+            let res = format.encode(&msg);
+            if !Features::supported().contains(format.feature()) {
+                assert!(matches!(res, Err(SerializeError::Unsupported(f)) if f == format));
+                continue;
+            }
+            let bytes = res.unwrap();
+            let out: PollinationMessage = format.decode(&bytes).unwrap();
+            assert_eq!(out.uuid(), Uuid::from_u128(7), "{format}");
+        }
+    }
+
+    #[test]
+    fn test_tags() {
+        for format in ALL {
+            assert_eq!(Format::from_tag(format.tag()), Some(format));
+        }
+        assert_eq!(Format::from_tag(4), None);
+    }
+
+    #[test]
+    fn test_within() {
+        assert_eq!(Format::Json.within(Features::JSON), Format::Json);
+        assert_eq!(Format::Json.within(Features::CBOR), Format::Bincode);
+        assert_eq!(Format::Bincode.within(Features::NONE), Format::Bincode);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_is_readable() {
+        let bytes = Json.encode(&("addr", 3)).unwrap();
+        assert_eq!(std::str::from_utf8(&bytes).unwrap(), r#"["addr",3]"#);
+    }
+}
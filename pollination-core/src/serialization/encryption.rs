@@ -0,0 +1,130 @@
+use super::*;
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use std::fmt;
+use thiserror::Error;
+
+const NONCE_LEN: usize = 24;
+
+/// Authenticated encryption of serialized messages with a shared cluster key.
+///
+/// Uses XChaCha20-Poly1305 so nonces can be drawn at random without having to
+/// coordinate counters between nodes. Encrypted payloads are laid out as
+/// `nonce || ciphertext`.
+#[derive(Clone)]
+pub struct ClusterCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ClusterCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| EncryptionError::Encrypt)?;
+
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        if bytes.len() < NONCE_LEN {
+            return Err(EncryptionError::Truncated);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::Decrypt)
+    }
+
+    pub fn serialize<T: Serialize>(&self, val: T) -> Result<Vec<u8>, EncryptionError> {
+        let plaintext = serialize(val).map_err(EncryptionError::Serialize)?;
+        self.encrypt(&plaintext)
+    }
+
+    pub fn deserialize<T: for<'de> Deserialize<'de>>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<T, EncryptionError> {
+        let plaintext = self.decrypt(bytes)?;
+        deserialize(plaintext).map_err(EncryptionError::Deserialize)
+    }
+}
+
+impl fmt::Debug for ClusterCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "ClusterCipher {{ key: <redacted> }}")
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Serialization error: {0}")]
+    Serialize(SerializeError),
+
+    #[error("Deserialization error: {0}")]
+    Deserialize(DeserializeError),
+
+    #[error("Failed to encrypt payload")]
+    Encrypt,
+
+    #[error("Payload failed authentication")]
+    Decrypt,
+
+    #[error("Payload too short to contain a nonce")]
+    Truncated,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let cipher = ClusterCipher::new([7; 32]);
+        let bytes = cipher.serialize("secret address").unwrap();
+        let out: String = cipher.deserialize(&bytes).unwrap();
+        assert_eq!(out, "secret address");
+    }
+
+    #[test]
+    fn test_ciphertext_hides_payload() {
+        let cipher = ClusterCipher::new([7; 32]);
+        let bytes = cipher.serialize("secret address").unwrap();
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+    }
+
+    #[test]
+    fn test_wrong_key_or_tampering_rejected() {
+        let cipher = ClusterCipher::new([7; 32]);
+        let mut bytes = cipher.encrypt(b"payload").unwrap();
+
+        let other = ClusterCipher::new([8; 32]);
+        assert!(matches!(
+            other.decrypt(&bytes),
+            Err(EncryptionError::Decrypt)
+        ));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&bytes),
+            Err(EncryptionError::Decrypt)
+        ));
+        assert!(matches!(
+            cipher.decrypt(&bytes[..4]),
+            Err(EncryptionError::Truncated)
+        ));
+    }
+}
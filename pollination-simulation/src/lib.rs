@@ -109,7 +109,10 @@ impl Simulee for SimulatedPollinationNode {
                     Ok(PollinationResponse { response, old_core }) => {
                         let msgs = response.map(|msg| (from, msg)).into_iter().collect();
 
-                        Some((PollinationEvent::HandleMessage(old_core), msgs))
+                        Some((
+                            PollinationEvent::HandleMessage(old_core.map(Box::new)),
+                            msgs,
+                        ))
                     }
                     Err(err) => Some((PollinationEvent::FailedMessage(err), vec![])),
                 }
@@ -134,7 +137,7 @@ pub enum PollinationEvent<A> {
     Heartbeat,
     FailedHeartbeat,
     SetPropagating,
-    HandleMessage(Option<PollinationNode<A>>),
+    HandleMessage(Option<Box<PollinationNode<A>>>),
     FailedMessage(PollinationError),
     Update,
     None,